@@ -1,24 +1,20 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use rand::distributions::{Alphanumeric, Distribution};
-use rand::{thread_rng, Rng};
-use rs_datastore::datastore::*;
+use criterion::{criterion_group, criterion_main, Criterion};
 use rs_datastore::nestedmap::options::*;
 use rs_datastore::nestedmap::test_helpers::create_item;
-use rs_datastore::nestedmap::{Item, NestedMap}; // Import your NestedMap module
-use tokio::runtime::{Builder, Runtime};
+use rs_datastore::nestedmap::NestedMap; // Import your NestedMap module
 
 fn bench_get(c: &mut Criterion) {
     let mut nm = NestedMap::new(1);
 
     nm.set(
-        &"a.b.c.d.e".to_string(),
-        &create_item(&"a.b.c.d.e", b"some value a"),
+        "a.b.c.d.e",
+        &create_item("a.b.c.d.e", b"some value a"),
         None,
     );
 
     c.bench_function("get_key a.b.c.d.e", |b| {
         b.iter(|| {
-            let _ = nm.get(&"a.b.c.d.e".to_string());
+            let _ = nm.get("a.b.c.d.e");
         });
     });
 }
@@ -49,8 +45,8 @@ fn bench_set(c: &mut Criterion) {
     c.bench_function("set_key a.b.c.d.e", |b| {
         b.iter(|| {
             nm.set(
-                &"a.b.c.d.e".to_string(),
-                &create_item(&"a.b.c.d.e", b"some value a"),
+                "a.b.c.d.e",
+                &create_item("a.b.c.d.e", b"some value a"),
                 Some(SetOptions::new().preserve_history(true)),
             );
         });
@@ -220,20 +216,20 @@ fn bench_set(c: &mut Criterion) {
 //    );
 //}
 //
-fn random_key() -> String {
-    let mut rng = thread_rng();
-    let len = rng.gen_range(1..10); // Random length between 1 and 10 for each part
-    (0..5)
-        .map(|_| {
-            Alphanumeric
-                .sample_iter(&mut rng)
-                .take(len)
-                .map(char::from)
-                .collect::<String>()
-        })
-        .collect::<Vec<_>>()
-        .join(".")
-}
+//fn random_key() -> String {
+//    let mut rng = thread_rng();
+//    let len = rng.gen_range(1..10); // Random length between 1 and 10 for each part
+//    (0..5)
+//        .map(|_| {
+//            Alphanumeric
+//                .sample_iter(&mut rng)
+//                .take(len)
+//                .map(char::from)
+//                .collect::<String>()
+//        })
+//        .collect::<Vec<_>>()
+//        .join(".")
+//}
 //
 //fn bench_set_varying_ttls(c: &mut Criterion) {
 //    let mut nm = NestedMap::new(1);
@@ -294,7 +290,7 @@ fn random_key() -> String {
 //    bench_set_diverse_keys,
 //    bench_set_varying_ttls
 //);
-criterion_group!(benches, bench_set);
+criterion_group!(benches, bench_get, bench_set);
 //criterion_group!(
 //    benches,
 //    bench_get,
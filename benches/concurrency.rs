@@ -0,0 +1,191 @@
+// Compares the sharded Datastore against a single Mutex<NestedMap>, the
+// design it replaced, by how long writers take while scans are running, and
+// measures what a write costs once a snapshot shares the level it writes to.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rs_datastore::datastore::Datastore;
+use rs_datastore::nestedmap::options::SetOptions;
+use rs_datastore::nestedmap::test_helpers::create_item;
+use rs_datastore::nestedmap::NestedMap;
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::Mutex;
+
+const DEVICES: usize = 200;
+const INTERFACES: usize = 48;
+const WRITERS: usize = 4;
+const WRITES_PER_WRITER: usize = 500;
+const READERS: usize = 2;
+// Sibling keys under one level, each with full history
+const WIDTH: usize = 10_000;
+const HISTORY: usize = 5;
+
+fn runtime() -> Runtime {
+    Builder::new_multi_thread()
+        .worker_threads(4)
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+fn interface_keys() -> impl Iterator<Item = String> {
+    (0..DEVICES).flat_map(|device| {
+        (0..INTERFACES).map(move |ifname| {
+            format!(
+                "interface.lab1.esr{}.ethernet{}.oper-status",
+                device, ifname
+            )
+        })
+    })
+}
+
+fn bgp_key(writer: usize, i: usize) -> String {
+    format!("bgp.lab1.esr{}.neighbor{}.state", writer, i % 64)
+}
+
+// Times WRITERS tasks writing under bgp while READERS tasks keep scanning
+// interface.> until the writers are done
+async fn mutex_workload(map: Arc<Mutex<NestedMap>>) -> Duration {
+    let done = Arc::new(AtomicBool::new(false));
+    let mut readers = Vec::new();
+    for _ in 0..READERS {
+        let (map, done) = (map.clone(), done.clone());
+        readers.push(tokio::spawn(async move {
+            while !done.load(Ordering::Relaxed) {
+                map.lock().await.query("interface.>", None).len();
+            }
+        }));
+    }
+
+    let start = Instant::now();
+    let mut writers = Vec::new();
+    for writer in 0..WRITERS {
+        let map = map.clone();
+        writers.push(tokio::spawn(async move {
+            for i in 0..WRITES_PER_WRITER {
+                let key = bgp_key(writer, i);
+                map.lock()
+                    .await
+                    .set(&key, &create_item(&key, b"established"), None);
+            }
+        }));
+    }
+    for writer in writers {
+        writer.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    done.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.await.unwrap();
+    }
+    elapsed
+}
+
+async fn sharded_workload(ds: Arc<Datastore>) -> Duration {
+    let done = Arc::new(AtomicBool::new(false));
+    let mut readers = Vec::new();
+    for _ in 0..READERS {
+        let (ds, done) = (ds.clone(), done.clone());
+        readers.push(tokio::spawn(async move {
+            while !done.load(Ordering::Relaxed) {
+                ds.query("interface.>", None).await.len();
+            }
+        }));
+    }
+
+    let start = Instant::now();
+    let mut writers = Vec::new();
+    for writer in 0..WRITERS {
+        let ds = ds.clone();
+        writers.push(tokio::spawn(async move {
+            for i in 0..WRITES_PER_WRITER {
+                ds.set(bgp_key(writer, i), b"established", None).await;
+            }
+        }));
+    }
+    for writer in writers {
+        writer.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    done.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.await.unwrap();
+    }
+    elapsed
+}
+
+fn bench_writes_during_scans(c: &mut Criterion) {
+    let rt = runtime();
+
+    let mut map = NestedMap::new(1);
+    for key in interface_keys() {
+        map.set(&key, &create_item(&key, b"up"), None);
+    }
+    let map = Arc::new(Mutex::new(map));
+
+    let ds = rt.block_on(async {
+        let ds = Datastore::new(1);
+        for key in interface_keys() {
+            ds.set(key, b"up", None).await;
+        }
+        Arc::new(ds)
+    });
+
+    let mut group = c.benchmark_group("bgp writes during interface.> scans");
+    group.sample_size(20);
+    group.bench_function("single mutex", |b| {
+        b.iter_custom(|iters| {
+            (0..iters)
+                .map(|_| rt.block_on(mutex_workload(map.clone())))
+                .sum()
+        });
+    });
+    group.bench_function("sharded snapshots", |b| {
+        b.iter_custom(|iters| {
+            (0..iters)
+                .map(|_| rt.block_on(sharded_workload(ds.clone())))
+                .sum()
+        });
+    });
+    group.finish();
+}
+
+// Every write publishes a snapshot sharing the tree, so the next write to
+// the same level has to copy whatever of it that write touches; that should
+// stay a handful of nodes however wide the level and large its values
+fn bench_writes_to_wide_level(c: &mut Criterion) {
+    let rt = runtime();
+    let value = vec![b'x'; 256];
+    let history = || Some(SetOptions::new().preserve_history(true));
+
+    let ds = rt.block_on(async {
+        let ds = Datastore::new(HISTORY);
+        for i in 0..WIDTH {
+            for _ in 0..HISTORY {
+                ds.set(format!("config.key{}", i), &value, history()).await;
+            }
+        }
+        ds
+    });
+
+    let mut group = c.benchmark_group("writes to one key of a wide level");
+    group.bench_function(format!("{} keys, {} values each", WIDTH, HISTORY), |b| {
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 1) % WIDTH;
+            rt.block_on(ds.set(format!("config.key{}", i), &value, history()));
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_writes_during_scans,
+    bench_writes_to_wide_level
+);
+criterion_main!(benches);
@@ -1,12 +1,25 @@
+use std::collections::BTreeMap;
+
 use tonic::transport::Channel;
 use tonic::Request;
 
 use clap::{Arg, ArgAction, Command};
+use futures::StreamExt;
 use rmp_serde::decode::from_read_ref;
 use serde_json::{json, to_string, to_string_pretty, Value};
+use tokio::io::AsyncBufReadExt;
 
+use datastore::aggregate_request::GroupBy;
+use datastore::compare::Condition;
 use datastore::datastore_client::DatastoreClient;
-use datastore::{GetRequest, QueryRequest, SetRequest};
+use datastore::set_options::Precondition;
+use datastore::txn_operation::Operation;
+use datastore::{
+    AddNodeRequest, AggregateRequest, Aggregation, BatchGetRequest, BatchSetRequest, Change,
+    ChangeKind, ChangesRequest, Compare, DeleteRequest, GetRequest, IngestRequest, QueryRequest,
+    RemoveNodeRequest, SetRequest, SnapshotRequest, TxnOperation, TxnRequest, UpstreamError,
+    WatchMode, WatchRequest,
+};
 
 use base64::{engine::general_purpose, Engine as _};
 
@@ -25,6 +38,7 @@ async fn get(
     let request = GetRequest { key: key.clone() };
     let response = client.get(Request::new(request)).await?;
     let item = response.into_inner();
+    warn_partial(&item.upstream_errors);
 
     if let Some(item) = item.item {
         if raw {
@@ -54,11 +68,22 @@ async fn get(
     Ok(())
 }
 
+// An aggregator answers with what the upstreams it could reach have
+fn warn_partial(errors: &[UpstreamError]) {
+    for error in errors {
+        eprintln!(
+            "Warning: partial results, {} is unavailable: {}",
+            error.upstream, error.message
+        );
+    }
+}
+
 async fn set(
     client: &mut DatastoreClient<Channel>,
     key: String,
     value: Vec<u8>,
     ttl: i64,
+    precondition: Option<Precondition>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let encoded_value = general_purpose::STANDARD.encode(&value);
     let request = SetRequest {
@@ -67,38 +92,35 @@ async fn set(
         options: Some(datastore::SetOptions {
             preserve_history: true,
             ttl,
+            precondition,
         }),
     };
-    let response = client.set(Request::new(request)).await?;
-    println!(
-        "Set operation successful: {}",
-        response.into_inner().success
-    );
+    let response = client.set(Request::new(request)).await?.into_inner();
+    println!("Set operation successful: {}", response.success);
+    if response.precondition_failed {
+        println!("Precondition failed, nothing was written");
+    }
     Ok(())
 }
 
 async fn query(
     client: &mut DatastoreClient<Channel>,
-    key: String,
-    history_count: Option<i64>,
+    request: QueryRequest,
     raw: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let options = history_count.map(|count| datastore::GetOptions {
-        history_count: Some(count),
-    });
-
-    let request = QueryRequest { key, options };
-    let response = client.query(Request::new(request)).await?;
+    let response = client.query(Request::new(request)).await?.into_inner();
+    warn_partial(&response.upstream_errors);
 
-    let items = response.into_inner().items;
+    let items = response.items;
     let mut results = Vec::new();
 
     for item in items {
-        if raw {
-            results.push(json!({
+        let mut result = if raw {
+            json!({
                 "key": item.key,
-                "value": general_purpose::STANDARD.encode(&item.value)
-            }));
+                "value": general_purpose::STANDARD.encode(&item.value),
+                "version": item.version
+            })
         } else {
             // deserialize messagepack into serde_json::Value
             let value = match from_read_ref::<_, Value>(&item.value) {
@@ -106,11 +128,17 @@ async fn query(
                 Err(_) => json!({"error": "Failed to deserialize MessagePack data"}),
             };
 
-            results.push(json!({
+            json!({
                 "key": item.key,
-                "value": value
-            }));
+                "value": value,
+                "version": item.version
+            })
+        };
+
+        if !item.labels.is_empty() {
+            result["labels"] = json!(item.labels);
         }
+        results.push(result);
     }
 
     // serialize results as json and return!
@@ -123,6 +151,228 @@ async fn query(
     Ok(())
 }
 
+async fn changes(
+    client: &mut DatastoreClient<Channel>,
+    request: ChangesRequest,
+    raw: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let response = client.changes(Request::new(request)).await?.into_inner();
+
+    for change in response.changes {
+        println!("{}", change_json(change, raw));
+    }
+    println!("Up to revision {}", response.revision);
+
+    Ok(())
+}
+
+// Prints the initial items and then every change as they arrive, until the
+// stream ends or fails
+async fn watch(
+    client: &mut DatastoreClient<Channel>,
+    request: WatchRequest,
+    raw: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut responses = client.watch(Request::new(request)).await?.into_inner();
+
+    while let Some(response) = responses.message().await? {
+        for item in response.items {
+            let result = json!({
+                "revision": response.revision,
+                "key": item.key,
+                "kind": "current",
+                "value": value_json(&item.value, raw),
+                "version": item.version,
+            });
+            println!("{}", result);
+        }
+        for change in response.changes {
+            println!("{}", change_json(change, raw));
+        }
+    }
+
+    Ok(())
+}
+
+fn change_json(change: Change, raw: bool) -> Value {
+    let kind = change.kind();
+    let mut result = json!({
+        "revision": change.revision,
+        "key": change.key,
+        "kind": kind.as_str_name().to_lowercase(),
+    });
+    if let Some(item) = change.item {
+        result["value"] = value_json(&item.value, raw);
+        result["version"] = json!(item.version);
+    }
+    if kind == ChangeKind::Expire {
+        result["version"] = json!(change.version);
+    }
+    result
+}
+
+fn value_json(value: &[u8], raw: bool) -> Value {
+    if raw {
+        json!(general_purpose::STANDARD.encode(value))
+    } else {
+        from_read_ref::<_, Value>(value)
+            .unwrap_or_else(|_| json!({"error": "Failed to deserialize MessagePack data"}))
+    }
+}
+
+async fn aggregate(
+    client: &mut DatastoreClient<Channel>,
+    request: AggregateRequest,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let response = client.aggregate(Request::new(request)).await?;
+
+    let groups: Vec<Value> = response
+        .into_inner()
+        .groups
+        .into_iter()
+        .map(|group| {
+            let mut result = json!({
+                "group": group.group,
+                "value": group.value,
+                "count": group.count,
+            });
+            if !group.counts.is_empty() {
+                result["counts"] = json!(group.counts);
+            }
+            result
+        })
+        .collect();
+
+    if let Ok(json_str) = to_string(&groups) {
+        println!("{}", json_str);
+    } else {
+        println!("Error formatting JSON");
+    }
+
+    Ok(())
+}
+
+async fn batch_set(
+    client: &mut DatastoreClient<Channel>,
+    request: BatchSetRequest,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let response = client.batch_set(Request::new(request)).await?;
+    let written = response
+        .into_inner()
+        .responses
+        .iter()
+        .filter(|response| response.success)
+        .count();
+    println!("Set {} keys", written);
+    Ok(())
+}
+
+async fn batch_get(
+    client: &mut DatastoreClient<Channel>,
+    request: BatchGetRequest,
+    raw: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let keys = request.keys.clone();
+    let response = client.batch_get(Request::new(request)).await?;
+
+    let results: Vec<Value> = keys
+        .into_iter()
+        .zip(response.into_inner().responses)
+        .map(|(key, response)| match response.item {
+            Some(item) => {
+                let value = if raw {
+                    json!(general_purpose::STANDARD.encode(&item.value))
+                } else {
+                    from_read_ref::<_, Value>(&item.value).unwrap_or_else(
+                        |_| json!({"error": "Failed to deserialize MessagePack data"}),
+                    )
+                };
+                json!({"key": item.key, "value": value, "version": item.version})
+            }
+            None => json!({"key": key, "value": null}),
+        })
+        .collect();
+
+    if let Ok(json_str) = to_string(&results) {
+        println!("{}", json_str);
+    } else {
+        println!("Error formatting JSON");
+    }
+
+    Ok(())
+}
+
+// Streams KEY=VALUE lines from stdin, printing each ack as it arrives
+async fn ingest(
+    client: &mut DatastoreClient<Channel>,
+    ttl: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    let requests =
+        futures::stream::unfold((lines, 0u64), move |(mut lines, sequence)| async move {
+            loop {
+                let line = lines.next_line().await.ok()??;
+                let Some((key, value)) = line.split_once('=') else {
+                    eprintln!("skipping '{}', expected KEY=VALUE", line);
+                    continue;
+                };
+
+                let request = IngestRequest {
+                    sequence: sequence + 1,
+                    set: Some(SetRequest {
+                        key: key.to_string(),
+                        value: general_purpose::STANDARD.encode(value).into(),
+                        options: Some(datastore::SetOptions {
+                            preserve_history: true,
+                            ttl,
+                            precondition: None,
+                        }),
+                    }),
+                };
+                return Some((request, (lines, sequence + 1)));
+            }
+        });
+
+    let mut acks = client.ingest(Request::new(requests)).await?.into_inner();
+    while let Some(ack) = acks.next().await {
+        let ack = ack?;
+        println!(
+            "Applied through sequence {} ({} applied, {} rejected)",
+            ack.applied_sequence, ack.applied, ack.rejected
+        );
+    }
+    Ok(())
+}
+
+async fn txn(
+    client: &mut DatastoreClient<Channel>,
+    request: TxnRequest,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let response = client.txn(Request::new(request)).await?;
+    println!("Transaction applied: {}", response.into_inner().succeeded);
+    Ok(())
+}
+
+// Parses KEY=absent, KEY=present or KEY=<version>
+fn parse_compare(arg: &str) -> Result<Compare, String> {
+    let (key, condition) = arg
+        .rsplit_once('=')
+        .ok_or_else(|| "expected KEY=absent|present|VERSION".to_string())?;
+    let condition = match condition {
+        "absent" => Condition::Absent(true),
+        "present" => Condition::Present(true),
+        version => Condition::Version(
+            version
+                .parse()
+                .map_err(|_| format!("invalid version '{}'", version))?,
+        ),
+    };
+    Ok(Compare {
+        key: key.to_string(),
+        condition: Some(condition),
+    })
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cmd = Command::new("rs-datastore client")
@@ -150,6 +400,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     Arg::new("ttl")
                         .required(false)
                         .value_parser(clap::value_parser!(i64)),
+                )
+                .arg(
+                    Arg::new("if_absent")
+                        .long("if-absent")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["if_present", "if_version"])
+                        .help("only writes if the key has no value"),
+                )
+                .arg(
+                    Arg::new("if_present")
+                        .long("if-present")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("if_version")
+                        .help("only writes if the key has a value"),
+                )
+                .arg(
+                    Arg::new("if_version")
+                        .long("if-version")
+                        .value_parser(clap::value_parser!(i64))
+                        .help("only writes if the key's latest version is this one"),
                 ),
         )
         .subcommand(
@@ -161,6 +431,41 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .required(false)
                         .value_parser(clap::value_parser!(i64)),
                 )
+                .arg(
+                    Arg::new("max_depth")
+                        .long("max-depth")
+                        .value_parser(clap::value_parser!(i64))
+                        .help("limits how many levels a '>' collector descends"),
+                )
+                .arg(
+                    Arg::new("filter")
+                        .long("filter")
+                        .help("only returns values matching e.g. \"value.state == 'idle'\""),
+                )
+                .arg(
+                    Arg::new("field")
+                        .long("field")
+                        .action(ArgAction::Append)
+                        .help("only returns this field of each value"),
+                )
+                .arg(
+                    Arg::new("include")
+                        .long("include")
+                        .action(ArgAction::Append)
+                        .help("additional pattern to union with the key"),
+                )
+                .arg(
+                    Arg::new("exclude")
+                        .long("exclude")
+                        .action(ArgAction::Append)
+                        .help("drops keys matching this pattern"),
+                )
+                .arg(
+                    Arg::new("snapshot")
+                        .long("snapshot")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("reads from a snapshot opened by the snapshot command"),
+                )
                 .arg(
                     Arg::new("raw")
                         .long("raw")
@@ -168,6 +473,187 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .help("returns raw data"),
                 ),
         )
+        .subcommand(
+            Command::new("snapshot")
+                .about("opens a snapshot for later queries and prints its token")
+                .arg(
+                    Arg::new("lease_ms")
+                        .long("lease-ms")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("how long the snapshot is kept after it was last used"),
+                ),
+        )
+        .subcommand(
+            Command::new("changes")
+                .about("lists changes to keys matching a pattern after a revision")
+                .arg(Arg::new("pattern").required(true))
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .required(true)
+                        .value_parser(clap::value_parser!(u64))
+                        .help("revision to list the changes after"),
+                )
+                .arg(
+                    Arg::new("limit")
+                        .long("limit")
+                        .value_parser(clap::value_parser!(u32)),
+                )
+                .arg(
+                    Arg::new("raw")
+                        .long("raw")
+                        .action(ArgAction::SetTrue)
+                        .help("returns raw data"),
+                ),
+        )
+        .subcommand(
+            Command::new("watch")
+                .about("streams changes to keys matching a pattern")
+                .arg(Arg::new("pattern").required(true))
+                .arg(
+                    Arg::new("initial")
+                        .long("initial")
+                        .action(ArgAction::SetTrue)
+                        .help("starts with the items currently matching the pattern"),
+                )
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("revision to resume after"),
+                )
+                .arg(
+                    Arg::new("mode")
+                        .long("mode")
+                        .value_parser(["on-change", "latest", "sample"])
+                        .help(
+                            "every change, the latest change per key, or every item, per interval",
+                        ),
+                )
+                .arg(
+                    Arg::new("interval_ms")
+                        .long("interval-ms")
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    Arg::new("history")
+                        .long("history")
+                        .action(ArgAction::SetTrue)
+                        .help("lists every kept item of each key, not just the latest"),
+                )
+                .arg(
+                    Arg::new("raw")
+                        .long("raw")
+                        .action(ArgAction::SetTrue)
+                        .help("returns raw data"),
+                ),
+        )
+        .subcommand(
+            Command::new("aggregate")
+                .about("aggregates the values matching a key")
+                .arg(Arg::new("key").required(true))
+                .arg(Arg::new("aggregation").required(true).value_parser([
+                    "sum",
+                    "min",
+                    "max",
+                    "avg",
+                    "count",
+                    "count-by-value",
+                ]))
+                .arg(
+                    Arg::new("group_by_position")
+                        .long("group-by-position")
+                        .value_parser(clap::value_parser!(u32))
+                        .conflicts_with("group_by")
+                        .help("groups by the key segment at this zero-based position"),
+                )
+                .arg(
+                    Arg::new("group_by")
+                        .long("group-by")
+                        .help("groups by a named capture such as {device} in the key"),
+                ),
+        )
+        .subcommand(
+            Command::new("batch-set")
+                .about("sets many keys in one request")
+                .arg(
+                    Arg::new("pairs")
+                        .required(true)
+                        .num_args(1..)
+                        .help("KEY=VALUE pairs"),
+                )
+                .arg(
+                    Arg::new("ttl")
+                        .long("ttl")
+                        .value_parser(clap::value_parser!(i64)),
+                ),
+        )
+        .subcommand(
+            Command::new("batch-get")
+                .about("gets many keys in one request")
+                .arg(Arg::new("keys").required(true).num_args(1..))
+                .arg(
+                    Arg::new("raw")
+                        .long("raw")
+                        .action(ArgAction::SetTrue)
+                        .help("returns raw data"),
+                ),
+        )
+        .subcommand(
+            Command::new("ingest")
+                .about("streams KEY=VALUE lines from stdin as writes")
+                .arg(
+                    Arg::new("ttl")
+                        .long("ttl")
+                        .value_parser(clap::value_parser!(i64)),
+                ),
+        )
+        .subcommand(
+            Command::new("txn")
+                .about("applies deletes then sets atomically if every compare holds")
+                .arg(
+                    Arg::new("compare")
+                        .long("compare")
+                        .action(ArgAction::Append)
+                        .value_parser(parse_compare)
+                        .help("KEY=absent, KEY=present or KEY=VERSION"),
+                )
+                .arg(
+                    Arg::new("set")
+                        .long("set")
+                        .action(ArgAction::Append)
+                        .help("KEY=VALUE to set"),
+                )
+                .arg(
+                    Arg::new("delete")
+                        .long("delete")
+                        .action(ArgAction::Append)
+                        .help("pattern whose values are deleted"),
+                ),
+        )
+        .subcommand(
+            Command::new("add-node")
+                .about("adds a node to the server's cluster and prints the members")
+                .arg(
+                    Arg::new("id")
+                        .required(true)
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    Arg::new("address")
+                        .required(true)
+                        .help("URL the other nodes reach it at"),
+                ),
+        )
+        .subcommand(
+            Command::new("remove-node")
+                .about("removes a node from the server's cluster and prints the members")
+                .arg(
+                    Arg::new("id")
+                        .required(true)
+                        .value_parser(clap::value_parser!(u64)),
+                ),
+        )
         .get_matches();
 
     // Retrieve host and port from environment or use default values
@@ -192,16 +678,218 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .as_bytes()
                 .to_vec();
             let ttl = sub_matches.get_one::<i64>("ttl").copied().unwrap_or(0);
-            set(&mut client, key.to_string(), value, ttl).await?;
+            let precondition = if sub_matches.get_flag("if_absent") {
+                Some(Precondition::IfAbsent(true))
+            } else if sub_matches.get_flag("if_present") {
+                Some(Precondition::IfPresent(true))
+            } else {
+                sub_matches
+                    .get_one::<i64>("if_version")
+                    .map(|version| Precondition::IfVersion(*version))
+            };
+            set(&mut client, key.to_string(), value, ttl, precondition).await?;
         }
         Some(("query", sub_matches)) => {
             let key = sub_matches.get_one::<String>("key").unwrap();
             let history_count = sub_matches
                 .get_one::<String>("history_count")
                 .and_then(|v| v.parse::<i64>().ok());
+            let max_depth = sub_matches.get_one::<i64>("max_depth").copied();
+            let filter = sub_matches.get_one::<String>("filter").cloned();
+            let include = sub_matches
+                .get_many::<String>("include")
+                .map(|v| v.cloned().collect())
+                .unwrap_or_default();
+            let exclude = sub_matches
+                .get_many::<String>("exclude")
+                .map(|v| v.cloned().collect())
+                .unwrap_or_default();
+            let snapshot = sub_matches.get_one::<u64>("snapshot").copied();
+            let raw = sub_matches.get_flag("raw");
+
+            let fields: Vec<String> = sub_matches
+                .get_many::<String>("field")
+                .map(|v| v.cloned().collect())
+                .unwrap_or_default();
+
+            let options = (history_count.is_some()
+                || max_depth.is_some()
+                || filter.is_some()
+                || !fields.is_empty())
+            .then_some(datastore::GetOptions {
+                // the server returns no values unless a count is given
+                history_count: history_count.or(Some(1)),
+                max_depth,
+                filter,
+                fields,
+            });
+            let request = QueryRequest {
+                key: key.to_string(),
+                options,
+                include,
+                exclude,
+                snapshot,
+            };
+
+            query(&mut client, request, raw).await?;
+        }
+        Some(("snapshot", sub_matches)) => {
+            let lease_ms = sub_matches.get_one::<u64>("lease_ms").copied().unwrap_or(0);
+            let response = client
+                .snapshot(Request::new(SnapshotRequest { lease_ms }))
+                .await?;
+            println!("{}", response.into_inner().snapshot);
+        }
+        Some(("changes", sub_matches)) => {
+            let request = ChangesRequest {
+                since_revision: *sub_matches.get_one::<u64>("since").unwrap(),
+                pattern: sub_matches.get_one::<String>("pattern").unwrap().clone(),
+                limit: sub_matches.get_one::<u32>("limit").copied().unwrap_or(0),
+            };
+            let raw = sub_matches.get_flag("raw");
+
+            changes(&mut client, request, raw).await?;
+        }
+        Some(("watch", sub_matches)) => {
+            let request = WatchRequest {
+                pattern: sub_matches.get_one::<String>("pattern").unwrap().clone(),
+                initial_state: sub_matches.get_flag("initial"),
+                since_revision: sub_matches.get_one::<u64>("since").copied(),
+                mode: match sub_matches.get_one::<String>("mode").map(String::as_str) {
+                    Some("latest") => WatchMode::Latest,
+                    Some("sample") => WatchMode::Sample,
+                    _ => WatchMode::OnChange,
+                }
+                .into(),
+                interval_ms: sub_matches
+                    .get_one::<u64>("interval_ms")
+                    .copied()
+                    .unwrap_or(0),
+                history: sub_matches.get_flag("history"),
+            };
             let raw = sub_matches.get_flag("raw");
 
-            query(&mut client, key.to_string(), history_count, raw).await?;
+            watch(&mut client, request, raw).await?;
+        }
+        Some(("aggregate", sub_matches)) => {
+            let key = sub_matches.get_one::<String>("key").unwrap();
+            let aggregation = match sub_matches
+                .get_one::<String>("aggregation")
+                .unwrap()
+                .as_str()
+            {
+                "sum" => Aggregation::Sum,
+                "min" => Aggregation::Min,
+                "max" => Aggregation::Max,
+                "avg" => Aggregation::Avg,
+                "count" => Aggregation::Count,
+                _ => Aggregation::CountByValue,
+            };
+            let group_by = match (
+                sub_matches.get_one::<u32>("group_by_position"),
+                sub_matches.get_one::<String>("group_by"),
+            ) {
+                (Some(position), _) => Some(GroupBy::GroupByPosition(*position)),
+                (_, Some(name)) => Some(GroupBy::GroupByCapture(name.clone())),
+                _ => None,
+            };
+
+            let request = AggregateRequest {
+                key: key.to_string(),
+                aggregation: aggregation.into(),
+                group_by,
+            };
+
+            aggregate(&mut client, request).await?;
+        }
+        Some(("batch-set", sub_matches)) => {
+            let ttl = sub_matches.get_one::<i64>("ttl").copied().unwrap_or(0);
+
+            let mut requests = Vec::new();
+            for pair in sub_matches.get_many::<String>("pairs").unwrap() {
+                let Some((key, value)) = pair.split_once('=') else {
+                    return Err(format!("expected KEY=VALUE, found '{}'", pair).into());
+                };
+                requests.push(SetRequest {
+                    key: key.to_string(),
+                    value: general_purpose::STANDARD.encode(value).into(),
+                    options: Some(datastore::SetOptions {
+                        preserve_history: true,
+                        ttl,
+                        precondition: None,
+                    }),
+                });
+            }
+
+            batch_set(&mut client, BatchSetRequest { requests }).await?;
+        }
+        Some(("batch-get", sub_matches)) => {
+            let keys = sub_matches
+                .get_many::<String>("keys")
+                .unwrap()
+                .cloned()
+                .collect();
+            let raw = sub_matches.get_flag("raw");
+
+            batch_get(&mut client, BatchGetRequest { keys }, raw).await?;
+        }
+        Some(("ingest", sub_matches)) => {
+            let ttl = sub_matches.get_one::<i64>("ttl").copied().unwrap_or(0);
+            ingest(&mut client, ttl).await?;
+        }
+        Some(("txn", sub_matches)) => {
+            let compares = sub_matches
+                .get_many::<Compare>("compare")
+                .map(|v| v.cloned().collect())
+                .unwrap_or_default();
+
+            let mut operations = Vec::new();
+            for key in sub_matches.get_many::<String>("delete").unwrap_or_default() {
+                operations.push(Operation::Delete(DeleteRequest { key: key.clone() }));
+            }
+            for set in sub_matches.get_many::<String>("set").unwrap_or_default() {
+                let Some((key, value)) = set.split_once('=') else {
+                    return Err(format!("expected KEY=VALUE, found '{}'", set).into());
+                };
+                operations.push(Operation::Set(SetRequest {
+                    key: key.to_string(),
+                    value: general_purpose::STANDARD.encode(value).into(),
+                    options: Some(datastore::SetOptions {
+                        preserve_history: true,
+                        ttl: 0,
+                        precondition: None,
+                    }),
+                }));
+            }
+
+            let request = TxnRequest {
+                compares,
+                operations: operations
+                    .into_iter()
+                    .map(|operation| TxnOperation {
+                        operation: Some(operation),
+                    })
+                    .collect(),
+            };
+
+            txn(&mut client, request).await?;
+        }
+        Some(("add-node", sub_matches)) => {
+            let request = AddNodeRequest {
+                id: *sub_matches.get_one::<u64>("id").unwrap(),
+                address: sub_matches.get_one::<String>("address").unwrap().clone(),
+            };
+            let response = client.add_node(Request::new(request)).await?;
+            let members: BTreeMap<_, _> = response.into_inner().members.into_iter().collect();
+            println!("{}", to_string_pretty(&members)?);
+        }
+        Some(("remove-node", sub_matches)) => {
+            let id = *sub_matches.get_one::<u64>("id").unwrap();
+            let response = client
+                .remove_node(Request::new(RemoveNodeRequest { id }))
+                .await?;
+            let members: BTreeMap<_, _> = response.into_inner().members.into_iter().collect();
+            println!("{}", to_string_pretty(&members)?);
         }
         _ => unreachable!(),
     }
@@ -0,0 +1,53 @@
+// Carries Raft messages between the servers of a cluster over gRPC
+use std::time::Duration;
+
+use log::debug;
+
+use crate::datastore::RaftMessage;
+use crate::peers::Peers;
+use rs_datastore::raft::{Envelope, NodeId, Transport};
+
+// Raft retries lost messages, so there's no point waiting long on a peer
+const SEND_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct GrpcTransport {
+    peers: Peers,
+}
+
+impl GrpcTransport {
+    pub fn new() -> Self {
+        GrpcTransport {
+            peers: Peers::new(SEND_TIMEOUT),
+        }
+    }
+}
+
+impl Transport for GrpcTransport {
+    fn send(&self, address: &str, envelope: Envelope) {
+        let Some(mut client) = self.peers.client(address) else {
+            debug!("Dropping message to invalid address {}", address);
+            return;
+        };
+        let Ok(envelope) = rmp_serde::to_vec(&envelope) else {
+            return;
+        };
+        let address = address.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = client.raft(RaftMessage { envelope }).await {
+                debug!("Sending to {} failed: {}", address, e);
+            }
+        });
+    }
+}
+
+/// Parses the cluster's initial members, e.g. 1=http://10.0.0.1:7777
+pub fn parse_member(arg: &str) -> Result<(NodeId, String), String> {
+    let (id, address) = arg
+        .split_once('=')
+        .ok_or_else(|| "expected ID=URL".to_string())?;
+    let id = id
+        .parse()
+        .map_err(|_| format!("invalid node id '{}'", id))?;
+    Ok((id, address.to_string()))
+}
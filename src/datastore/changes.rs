@@ -0,0 +1,298 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::nestedmap::pattern::Pattern;
+use crate::nestedmap::Item;
+
+// Enough for clients that reconnect within a few seconds under heavy load
+pub const DEFAULT_CHANGE_LOG_CAPACITY: usize = 100_000;
+
+// Revisions are reserved in the revision file this many at a time, so it is
+// only rewritten once per block
+const REVISION_BLOCK: u64 = 10_000;
+
+/// One mutation of the datastore. Applying a log's changes in order to the
+/// state at the revision before the first one reproduces the state after
+/// the last one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub revision: u64,
+    pub key: String,
+    pub kind: ChangeKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeKind {
+    // A new latest item; older ones are kept if `preserve_history` is set
+    Set { item: Item, preserve_history: bool },
+    // Every value of the key was deleted
+    Delete,
+    // The item with this id reached its ttl
+    Expire { id: i64 },
+}
+
+/// A page of the change log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Changes {
+    pub changes: Vec<Change>,
+    /// Pass as `since` to continue after this page.
+    pub revision: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangesError {
+    // Changes after the revision asked for are no longer kept
+    Compacted { oldest: u64 },
+    // The revision was never reached, e.g. it is from before a restart
+    Unknown { latest: u64 },
+}
+
+impl fmt::Display for ChangesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChangesError::Compacted { oldest } => {
+                write!(f, "changes are only kept after revision {}", oldest)
+            }
+            ChangesError::Unknown { latest } => {
+                write!(f, "the latest revision is {}", latest)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChangesError {}
+
+/// Hands out revisions and keeps the most recent changes. Item ids come out
+/// of the same sequence, so they never repeat either: a write draws its
+/// item's id while it holds the shard lock, and its change is given the
+/// next revision only when it is published, so that the log stays in
+/// publish order across shards. A set therefore uses two numbers, and
+/// change revisions aren't consecutive.
+#[derive(Debug)]
+pub struct ChangeLog {
+    changes: VecDeque<Change>,
+    capacity: usize,
+    // Changes after this revision are all still kept
+    start: u64,
+    committed: u64,
+    // The last revision handed out, committed or not
+    last: u64,
+    file: Option<RevisionFile>,
+}
+
+#[derive(Debug)]
+struct RevisionFile {
+    path: PathBuf,
+    // Every revision up to this one may have been handed out already
+    reserved: u64,
+}
+
+impl ChangeLog {
+    pub fn new(capacity: usize) -> Self {
+        ChangeLog {
+            changes: VecDeque::new(),
+            capacity,
+            start: 0,
+            committed: 0,
+            last: 0,
+            file: None,
+        }
+    }
+
+    /// Continues after every revision reserved in the file by an earlier run,
+    /// and keeps reserving there. Returns the revision the log now starts at.
+    pub fn persist(&mut self, path: PathBuf) -> io::Result<u64> {
+        let floor = match fs::read_to_string(&path) {
+            Ok(text) => text
+                .trim()
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+
+        self.last = self.last.max(floor);
+        self.committed = self.last;
+        self.start = self.last;
+        self.changes.clear();
+
+        let mut file = RevisionFile { path, reserved: 0 };
+        file.reserve(self.last)?;
+        self.file = Some(file);
+        Ok(self.committed)
+    }
+
+    pub fn allocate(&mut self) -> u64 {
+        self.skip_to(self.last + 1);
+        self.last
+    }
+
+    /// Never hands out this revision or any before it, e.g. once items with
+    /// ids up to it were copied from another datastore.
+    pub fn skip_to(&mut self, revision: u64) {
+        if revision <= self.last {
+            return;
+        }
+        self.last = revision;
+        if let Some(file) = &mut self.file {
+            if self.last > file.reserved {
+                if let Err(e) = file.reserve(self.last) {
+                    log::error!("Failed to reserve revisions: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Gives each change the next revision and returns the last one.
+    pub fn commit(&mut self, changes: Vec<(String, ChangeKind)>) -> u64 {
+        for (key, kind) in changes {
+            let revision = self.allocate();
+            self.changes.push_back(Change {
+                revision,
+                key,
+                kind,
+            });
+            self.committed = revision;
+        }
+
+        while self.changes.len() > self.capacity {
+            if let Some(change) = self.changes.pop_front() {
+                self.start = change.revision;
+            }
+        }
+        self.committed
+    }
+
+    pub fn committed(&self) -> u64 {
+        self.committed
+    }
+
+    /// Up to `limit` changes to keys matching the pattern after revision
+    /// `since`, oldest first.
+    pub fn since(
+        &self,
+        since: u64,
+        pattern: &Pattern,
+        limit: Option<usize>,
+    ) -> Result<Changes, ChangesError> {
+        if since > self.committed {
+            return Err(ChangesError::Unknown {
+                latest: self.committed,
+            });
+        }
+        if since < self.start {
+            return Err(ChangesError::Compacted { oldest: self.start });
+        }
+
+        let first = self
+            .changes
+            .partition_point(|change| change.revision <= since);
+        let limit = limit.unwrap_or(usize::MAX);
+        let mut changes = Vec::new();
+        for change in self.changes.range(first..) {
+            if changes.len() == limit {
+                // resume right after the last change returned
+                let revision = changes.last().map_or(since, |c: &Change| c.revision);
+                return Ok(Changes { changes, revision });
+            }
+            if pattern.matches(&change.key) {
+                changes.push(change.clone());
+            }
+        }
+        Ok(Changes {
+            changes,
+            revision: self.committed,
+        })
+    }
+}
+
+impl RevisionFile {
+    fn reserve(&mut self, revision: u64) -> io::Result<()> {
+        let reserved = revision + REVISION_BLOCK;
+        // replaced in one step so a crash never leaves it half written
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, reserved.to_string())?;
+        fs::rename(&tmp, &self.path)?;
+        self.reserved = reserved;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nestedmap::test_helpers::create_item;
+
+    fn set(key: &str) -> (String, ChangeKind) {
+        let kind = ChangeKind::Set {
+            item: create_item(key, b"up"),
+            preserve_history: false,
+        };
+        (key.to_string(), kind)
+    }
+
+    #[test]
+    fn test_since() {
+        let mut log = ChangeLog::new(3);
+        let all = Pattern::parse(">").unwrap();
+        let revisions = |changes: Changes| -> Vec<u64> {
+            changes
+                .changes
+                .iter()
+                .map(|change| change.revision)
+                .collect()
+        };
+
+        log.allocate();
+        assert_eq!(log.commit(vec![set("a.b"), set("c.d")]), 3);
+        assert_eq!(revisions(log.since(0, &all, None).unwrap()), vec![2, 3]);
+        assert_eq!(
+            log.since(4, &all, None),
+            Err(ChangesError::Unknown { latest: 3 })
+        );
+
+        log.commit(vec![("a.b".to_string(), ChangeKind::Delete)]);
+        let page = log.since(0, &Pattern::parse("a.*").unwrap(), None).unwrap();
+        assert_eq!(revisions(page.clone()), vec![2, 4]);
+        assert_eq!(page.revision, 4);
+
+        let page = log.since(0, &all, Some(2)).unwrap();
+        assert_eq!(revisions(page.clone()), vec![2, 3]);
+        assert_eq!(
+            revisions(log.since(page.revision, &all, None).unwrap()),
+            vec![4]
+        );
+
+        // only the last 3 changes are kept
+        log.commit(vec![set("e.f")]);
+        assert_eq!(
+            log.since(1, &all, None),
+            Err(ChangesError::Compacted { oldest: 2 })
+        );
+        assert_eq!(revisions(log.since(2, &all, None).unwrap()), vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn test_revisions_survive_restarts() {
+        let path = std::env::temp_dir().join(format!("forst-revision-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut log = ChangeLog::new(10);
+        assert_eq!(log.persist(path.clone()).unwrap(), 0);
+        let last = log.commit(vec![set("a.b")]);
+
+        let mut restarted = ChangeLog::new(10);
+        let start = restarted.persist(path.clone()).unwrap();
+        assert!(start >= last);
+        assert!(restarted.commit(vec![set("a.b")]) > last);
+        // changes from before the restart are gone
+        assert!(restarted
+            .since(last, &Pattern::parse(">").unwrap(), None)
+            .is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
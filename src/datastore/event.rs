@@ -1,12 +1,12 @@
-use log::{info};
+use log::info;
 
 use std::time::Duration;
 use std::time::SystemTime;
 
+use super::changes::ChangeKind;
 use super::Datastore;
 use super::ExpirationEntry;
 use tokio::sync::mpsc::Receiver;
-use tokio::time;
 use tokio::time::Sleep;
 
 pub struct Timer {
@@ -15,9 +15,7 @@ pub struct Timer {
 
 impl Timer {
     pub fn new() -> Self {
-        Self {
-            timer: None,
-        }
+        Self { timer: None }
     }
 
     pub fn reset(&mut self, duration: Duration) {
@@ -52,7 +50,7 @@ pub enum Event {
 
 impl Datastore {
     pub fn event_loop(&self, mut receiver: Receiver<Event>) {
-        let map = self.map.clone();
+        let shards = self.shards.clone();
         let ttl = self.ttl.clone();
         let sender = self.event_sender.clone();
 
@@ -89,8 +87,11 @@ impl Datastore {
                                     ttl_guard.push(entry.clone());
                                 },
                                 Event::TTLExpired(entry) => {
-                                    let mut map_guard = map.lock().await;
-                                    map_guard.delete_by_id(&entry.key, entry.id);
+                                    let mut locked = shards.lock(&entry.key).await;
+                                    if locked.map_mut(&entry.key).delete_by_id(&entry.key, entry.id) {
+                                        locked.record(entry.key.clone(), ChangeKind::Expire { id: entry.id });
+                                    }
+                                    drop(locked);
 
                                     info!("Deleted entry: key:{} id:{}", entry.key, entry.id);
 
@@ -131,7 +132,13 @@ impl Datastore {
                                     timer.disable();
                                     let _ = sender.send(Event::TTLExpired(min_entry)).await;
                                 }
+                            } else {
+                                // the entry the timer was set for was deleted
+                                let duration = next_expiry.expires_at.duration_since(SystemTime::now()).unwrap_or(Duration::new(0, 0));
+                                timer.reset(duration);
                             }
+                        } else {
+                            timer.disable();
                         }
                     }
                 }
@@ -1,9 +1,5 @@
 use std::time::SystemTime;
 
-use super::event::Event;
-use crate::datastore::Datastore;
-use tokio::sync::mpsc::Sender;
-
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct ExpirationEntry {
     pub id: i64,
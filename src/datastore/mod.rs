@@ -1,38 +1,78 @@
-use std::collections::BinaryHeap;
-use std::sync::atomic::Ordering;
-use std::sync::{atomic::AtomicI64, Arc};
+use std::collections::{BTreeSet, BinaryHeap, HashSet};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::SystemTime;
 use tokio::sync::mpsc;
 use tokio::sync::Mutex;
 
+use crate::nestedmap::aggregate::{AggregateResult, Aggregation, GroupBy};
+use crate::nestedmap::index::ValueIndex;
 use crate::nestedmap::options::{GetOptions, SetOptions};
+use crate::nestedmap::pattern::AsPattern;
 use crate::nestedmap::NestedMap;
+use changes::{ChangeKind, ChangeLog, Changes, ChangesError, DEFAULT_CHANGE_LOG_CAPACITY};
 use event::Event;
 use expiration::ExpirationEntry;
+use shards::{Locked, Shards};
+use transaction::{Operation, Transaction};
 
+pub use crate::nestedmap::pattern::{Pattern, PatternError, PatternSet};
 pub use crate::nestedmap::Item;
+pub use partition::Partitions;
+pub use snapshot::{Snapshot, SnapshotLeases};
+pub use watch::{ChangeEvent, WatchEvent, WatchMode, WatchOptions};
 
+pub mod changes;
 pub mod event;
 pub mod expiration;
+pub mod partition;
+pub mod pattern_cache;
+pub mod replica;
+pub mod shards;
+pub mod snapshot;
+pub mod transaction;
+pub mod watch;
+
+// Enough to keep writers to different top-level segments apart without
+// making full scans visit many nearly empty shards
+pub const SHARD_COUNT: usize = 16;
 
 #[derive(Debug)]
 pub struct Datastore {
-    map: Arc<Mutex<NestedMap>>,
+    shards: Arc<Shards>,
     ttl: Arc<Mutex<BinaryHeap<ExpirationEntry>>>,
-    id_counter: Arc<AtomicI64>,
     event_sender: mpsc::Sender<Event>,
 }
 
 impl Datastore {
     pub fn new(max_history: usize) -> Self {
-        env_logger::init();
+        Self::with_shards(Shards::new(SHARD_COUNT, Self::change_log(), || {
+            NestedMap::new(max_history)
+        }))
+    }
+
+    /// Like `new`, but keeps a segment index so that queries starting with
+    /// wildcards such as `*.*.*.oper-status` skip the full tree walk.
+    pub fn with_segment_index(max_history: usize) -> Self {
+        Self::with_shards(Shards::new(SHARD_COUNT, Self::change_log(), || {
+            NestedMap::new(max_history).with_segment_index()
+        }))
+    }
+
+    fn change_log() -> ChangeLog {
+        ChangeLog::new(DEFAULT_CHANGE_LOG_CAPACITY)
+    }
+
+    fn with_shards(shards: Shards) -> Self {
+        // several datastores may share a process, e.g. in tests
+        let _ = env_logger::try_init();
 
         let (sender, receiver) = mpsc::channel::<Event>(10000);
 
         let datastore = Datastore {
-            map: Arc::new(Mutex::new(NestedMap::new(max_history))),
+            shards: Arc::new(shards),
             ttl: Arc::new(Mutex::new(BinaryHeap::new())),
-            id_counter: Arc::new(AtomicI64::new(0)),
             event_sender: sender,
         };
 
@@ -40,50 +80,238 @@ impl Datastore {
         datastore
     }
 
-    // Async method to expose set functionality
-    pub async fn set(&self, key: String, value: &[u8], options: Option<SetOptions>) {
-        let mut map = self.map.lock().await;
+    /// Keeps revisions and item versions increasing across restarts by
+    /// reserving them in a file. Call it before writing anything.
+    pub fn persist_revisions(&self, path: impl Into<PathBuf>) -> io::Result<()> {
+        self.shards.persist_revisions(path.into())
+    }
+
+    // Async method to expose set functionality. Returns false if the options'
+    // precondition didn't hold and nothing was written.
+    pub async fn set(&self, key: String, value: &[u8], options: Option<SetOptions>) -> bool {
+        let mut locked = self.shards.lock(&key).await;
+        self.write(&mut locked, key, value.to_vec(), options).await
+    }
 
-        let id = self.id_counter.fetch_add(1, Ordering::Relaxed);
+    /// Sets many keys while taking each shard's lock once. Each write's
+    /// precondition is checked on its own; the returned flags report which
+    /// writes were applied, in order.
+    pub async fn batch_set(&self, writes: Vec<(String, Vec<u8>, Option<SetOptions>)>) -> Vec<bool> {
+        let indexes = writes
+            .iter()
+            .map(|(key, _, _)| self.shards.index(key))
+            .collect();
+        let mut locked = self.shards.write(indexes).await;
 
-        if let Some(ref options) = options {
-            if options.ttl.as_millis() > 0 {
-                let expires_at = SystemTime::now() + options.ttl;
+        let mut written = Vec::with_capacity(writes.len());
+        for (key, value, options) in writes {
+            written.push(self.write(&mut locked, key, value, options).await);
+        }
+        written
+    }
+
+    /// Applies every operation of the transaction if all of its
+    /// preconditions hold, and none of them otherwise. Readers never see a
+    /// partially applied transaction. Returns whether it was applied.
+    pub async fn transaction(&self, transaction: Transaction) -> bool {
+        // Lock every shard the transaction reads or writes up front
+        let mut indexes: BTreeSet<usize> = transaction
+            .compares
+            .iter()
+            .map(|(key, _)| self.shards.index(key))
+            .collect();
+        for operation in &transaction.operations {
+            match operation {
+                Operation::Set { key, .. } => {
+                    indexes.insert(self.shards.index(key));
+                }
+                Operation::Delete { key } => {
+                    if let Ok(pattern) = Pattern::parse(key) {
+                        indexes.extend(self.shards.indexes_for(&pattern));
+                    }
+                }
+            }
+        }
+        let mut locked = self.shards.write(indexes).await;
 
-                let entry = ExpirationEntry {
-                    id,
-                    key: key.to_string(),
-                    expires_at,
-                };
+        if !transaction
+            .preconditions()
+            .all(|(key, precondition)| precondition.holds(locked.map(key).get(key)))
+        {
+            return false;
+        }
 
-                let sender = self.event_sender.clone();
-                let _ = sender.send(Event::TTLInsert(entry)).await;
+        for operation in transaction.operations {
+            match operation {
+                Operation::Set {
+                    key,
+                    value,
+                    options,
+                } => {
+                    // already checked above, before anything was written
+                    let options = options.map(|mut options| {
+                        options.precondition = None;
+                        options
+                    });
+                    self.write(&mut locked, key, value, options).await;
+                }
+                Operation::Delete { key } => {
+                    // a malformed pattern deletes nothing
+                    let Ok(pattern) = Pattern::parse(&key) else {
+                        continue;
+                    };
+                    self.delete(&mut locked, &pattern).await;
+                }
             }
         }
+        true
+    }
+
+    // Stores a new item under an already held shard lock and schedules its
+    // expiration. Returns false if the precondition didn't hold.
+    async fn write(
+        &self,
+        locked: &mut Locked<'_>,
+        key: String,
+        value: Vec<u8>,
+        options: Option<SetOptions>,
+    ) -> bool {
+        let id = self.shards.next_id() as i64;
 
         let new_item = Item {
             key: key.to_string(),
-            value: value.to_vec(),
+            value,
             timestamp: SystemTime::now(),
             id,
         };
 
-        map.set(&key, &new_item, options);
+        let ttl = options.as_ref().map(|options| options.ttl);
+        let preserve_history = options
+            .as_ref()
+            .is_some_and(|options| options.preserve_history);
+        if !locked.map_mut(&key).set(&key, &new_item, options) {
+            return false;
+        }
+        locked.record(
+            key.clone(),
+            ChangeKind::Set {
+                item: new_item,
+                preserve_history,
+            },
+        );
+
+        if let Some(ttl) = ttl.filter(|ttl| ttl.as_millis() > 0) {
+            let entry = ExpirationEntry {
+                id,
+                key,
+                expires_at: SystemTime::now() + ttl,
+            };
+
+            let sender = self.event_sender.clone();
+            let _ = sender.send(Event::TTLInsert(entry)).await;
+        }
+        true
+    }
+
+    /// Starts maintaining a value index, indexing the keys already stored.
+    pub async fn add_value_index(&self, index: ValueIndex) {
+        let indexes = self.shards.indexes_for(index.pattern());
+        let mut locked = self.shards.write(indexes).await;
+        for map in locked.maps_for(index.pattern()) {
+            map.add_value_index(index.clone());
+        }
+    }
+
+    /// The state of the whole datastore as of the last completed write.
+    /// Reads through it never wait for writers, and all of them see the
+    /// same point in time however long the snapshot is held.
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.shards.snapshot()
     }
 
     pub async fn get(&self, key: &str) -> Option<Item> {
-        let map = self.map.lock().await;
-        map.get(key).cloned()
+        self.snapshot().get(key)
+    }
+
+    /// Gets many keys, in order, all as of the same point in time.
+    pub async fn batch_get<S: AsRef<str>>(&self, keys: &[S]) -> Vec<Option<Item>> {
+        self.snapshot().batch_get(keys)
+    }
+
+    /// Queries a snapshot of every shard that can hold matching keys, so a
+    /// long query never holds up writers and sees a single point in time.
+    pub async fn query<P: AsPattern + ?Sized>(
+        &self,
+        pattern: &P,
+        options: Option<GetOptions>,
+    ) -> Vec<Item> {
+        self.snapshot().query(pattern, options)
+    }
+
+    pub async fn query_set(&self, patterns: &PatternSet, options: Option<GetOptions>) -> Vec<Item> {
+        self.snapshot().query_set(patterns, options)
+    }
+
+    pub async fn aggregate<P: AsPattern + ?Sized>(
+        &self,
+        pattern: &P,
+        aggregation: Aggregation,
+        group_by: Option<&GroupBy>,
+    ) -> Vec<AggregateResult> {
+        self.snapshot().aggregate(pattern, aggregation, group_by)
+    }
+
+    pub async fn delete_matching<P: AsPattern + ?Sized>(&self, pattern: &P) -> usize {
+        let Ok(pattern) = pattern.as_pattern() else {
+            return 0;
+        };
+
+        let mut locked = self.shards.write(self.shards.indexes_for(&pattern)).await;
+        self.delete(&mut locked, &pattern).await
+    }
+
+    // Deletes the values of every matching key from the locked shards, along
+    // with their pending expirations, and returns how many keys were deleted
+    async fn delete(&self, locked: &mut Locked<'_>, pattern: &Pattern) -> usize {
+        let mut deleted = Vec::new();
+        for map in locked.maps_for(pattern) {
+            deleted.extend(map.delete_matching(pattern));
+        }
+        if deleted.is_empty() {
+            return 0;
+        }
+
+        let keys: HashSet<&str> = deleted.iter().map(String::as_str).collect();
+        self.ttl
+            .lock()
+            .await
+            .retain(|entry| !keys.contains(entry.key.as_str()));
+
+        let count = deleted.len();
+        for key in deleted {
+            locked.record(key, ChangeKind::Delete);
+        }
+        count
     }
 
-    pub async fn query(&self, key: &str, options: Option<GetOptions>) -> Vec<Item> {
-        let map = self.map.lock().await;
-        map.query(key, options)
+    /// Up to `limit` changes to keys matching the pattern after revision
+    /// `since`, oldest first, so that a client can catch up from the
+    /// revision of its last snapshot or change. Fails once those changes
+    /// are no longer kept, and the client has to query again instead.
+    pub fn changes(
+        &self,
+        since: u64,
+        pattern: &Pattern,
+        limit: Option<usize>,
+    ) -> Result<Changes, ChangesError> {
+        self.shards.changes(since, pattern, limit)
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::nestedmap::options::Precondition;
     use std::time::Duration;
 
     use tokio::time::sleep;
@@ -158,4 +386,285 @@ mod tests {
             panic!("Found key that should have been removed! a.b.e")
         }
     }
+
+    #[tokio::test]
+    async fn test_conditional_set() {
+        let ds = Datastore::new(2);
+        let key = || "config.esr1a.ntp".to_string();
+
+        assert!(
+            !ds.set(key(), b"a", Some(SetOptions::new().if_present()))
+                .await
+        );
+        assert!(
+            ds.set(key(), b"a", Some(SetOptions::new().if_absent()))
+                .await
+        );
+        assert!(
+            !ds.set(key(), b"b", Some(SetOptions::new().if_absent()))
+                .await
+        );
+        assert!(
+            ds.set(key(), b"b", Some(SetOptions::new().if_present()))
+                .await
+        );
+
+        // only the writer that saw the latest version wins
+        let version = ds.get("config.esr1a.ntp").await.unwrap().id;
+        assert!(
+            ds.set(key(), b"c", Some(SetOptions::new().if_version(version)))
+                .await
+        );
+        assert!(
+            !ds.set(key(), b"d", Some(SetOptions::new().if_version(version)))
+                .await
+        );
+        assert_eq!(ds.get("config.esr1a.ntp").await.unwrap().value, b"c");
+
+        // a failed write doesn't schedule an expiration
+        let options = SetOptions::new().ttl(Duration::from_millis(50)).if_absent();
+        assert!(!ds.set(key(), b"e", Some(options)).await);
+        sleep(Duration::from_millis(100)).await;
+        assert!(ds.get("config.esr1a.ntp").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_transaction() {
+        let ds = Datastore::new(1);
+        ds.set("device.esr1a.summary".to_string(), b"2 up", None)
+            .await;
+        ds.set("device.esr1a.interfaces.ethernet1".to_string(), b"up", None)
+            .await;
+        let version = ds.get("device.esr1a.summary").await.unwrap().id;
+
+        let update = |version| {
+            Transaction::new()
+                .compare("device.esr1a.summary", Precondition::Version(version))
+                .delete("device.esr1a.interfaces.*")
+                .set("device.esr1a.interfaces.ethernet2", "up", None)
+                .set("device.esr1a.summary", "1 up", None)
+        };
+
+        // a stale version applies nothing
+        assert!(!ds.transaction(update(version + 100)).await);
+        assert!(ds.get("device.esr1a.interfaces.ethernet1").await.is_some());
+        assert!(ds.get("device.esr1a.interfaces.ethernet2").await.is_none());
+
+        assert!(ds.transaction(update(version)).await);
+        assert!(ds.get("device.esr1a.interfaces.ethernet1").await.is_none());
+        assert!(ds.get("device.esr1a.interfaces.ethernet2").await.is_some());
+        assert_eq!(ds.get("device.esr1a.summary").await.unwrap().value, b"1 up");
+
+        // preconditions on the operations count too, before anything is written
+        let transaction = Transaction::new()
+            .set("device.esr1b.summary", "0 up", None)
+            .set(
+                "device.esr1a.summary",
+                "0 up",
+                Some(SetOptions::new().if_absent()),
+            );
+        assert!(!ds.transaction(transaction).await);
+        assert!(ds.get("device.esr1b.summary").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_batches() {
+        let ds = Datastore::new(1);
+        ds.set("interface.esr1a.ethernet1".to_string(), b"down", None)
+            .await;
+
+        let writes = vec![
+            (
+                "interface.esr1a.ethernet1".to_string(),
+                b"up".to_vec(),
+                None,
+            ),
+            (
+                "interface.esr1a.ethernet1".to_string(),
+                b"stale".to_vec(),
+                Some(SetOptions::new().if_absent()),
+            ),
+            (
+                "interface.esr1a.ethernet2".to_string(),
+                b"up".to_vec(),
+                None,
+            ),
+        ];
+        assert_eq!(ds.batch_set(writes).await, vec![true, false, true]);
+
+        let items = ds
+            .batch_get(&[
+                "interface.esr1a.ethernet2",
+                "interface.esr1a.ethernet3",
+                "interface.esr1a.ethernet1",
+            ])
+            .await;
+        let values: Vec<Option<&[u8]>> = items
+            .iter()
+            .map(|item| item.as_ref().map(|item| item.value.as_slice()))
+            .collect();
+        assert_eq!(values, vec![Some(&b"up"[..]), None, Some(&b"up"[..])]);
+    }
+
+    #[tokio::test]
+    async fn test_reads_dont_wait_for_writers() {
+        let ds = Datastore::new(1);
+        ds.set("bgp.esr1a.neighbor1".to_string(), b"idle", None)
+            .await;
+
+        // a writer stuck holding the shard doesn't hold up readers, who see
+        // the last published state
+        let locked = ds.shards.lock("bgp.esr1a.neighbor1").await;
+        let items = tokio::time::timeout(Duration::from_millis(100), ds.query("bgp.>", None))
+            .await
+            .expect("query waited for the writer");
+        assert_eq!(items[0].value, b"idle");
+        drop(locked);
+
+        ds.set("bgp.esr1a.neighbor1".to_string(), b"established", None)
+            .await;
+        assert_eq!(
+            ds.get("bgp.esr1a.neighbor1").await.unwrap().value,
+            b"established"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_forgets_expirations() {
+        let ds = Datastore::new(1);
+        let ttl = SetOptions::new().ttl(Duration::from_millis(100));
+        ds.set("bgp.esr1a.neighbor1".to_string(), b"idle", Some(ttl))
+            .await;
+        sleep(Duration::from_millis(20)).await;
+        assert_eq!(ds.ttl.lock().await.len(), 1);
+
+        assert_eq!(ds.delete_matching("bgp.>").await, 1);
+        assert!(ds.ttl.lock().await.is_empty());
+
+        // the timer set for the deleted entry finds nothing to expire
+        ds.set("bgp.esr1a.neighbor1".to_string(), b"established", None)
+            .await;
+        sleep(Duration::from_millis(150)).await;
+        assert!(ds.get("bgp.esr1a.neighbor1").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let ds = Datastore::new(1);
+        for device in ["esr1a", "esr1b"] {
+            ds.set(format!("interface.{}.ethernet1", device), b"up", None)
+                .await;
+        }
+
+        let snapshot = ds.snapshot();
+        ds.set("interface.esr1a.ethernet1".to_string(), b"down", None)
+            .await;
+        ds.set("interface.esr1c.ethernet1".to_string(), b"up", None)
+            .await;
+        ds.delete_matching("interface.esr1b.>").await;
+
+        // later writes, including to other shards, aren't visible
+        let values = |items: Vec<Item>| -> Vec<Vec<u8>> {
+            items.into_iter().map(|item| item.value).collect()
+        };
+        assert_eq!(
+            values(snapshot.query("interface.*.ethernet1", None)),
+            vec![b"up".to_vec(), b"up".to_vec()]
+        );
+        assert!(snapshot.get("interface.esr1c.ethernet1").is_none());
+        assert_eq!(
+            values(ds.query("interface.*.ethernet1", None).await),
+            vec![b"down".to_vec(), b"up".to_vec()]
+        );
+        let all = Pattern::parse(">").unwrap();
+        let changes = ds.changes(snapshot.revision(), &all, None).unwrap();
+        assert_eq!(changes.changes.len(), 3);
+        assert_eq!(changes.revision, ds.snapshot().revision());
+    }
+
+    #[tokio::test]
+    async fn test_changes() {
+        let ds = Datastore::new(1);
+        let start = ds.snapshot().revision();
+        let ttl = SetOptions::new().ttl(Duration::from_millis(50));
+
+        ds.set("bgp.esr1a.neighbor1".to_string(), b"idle", Some(ttl))
+            .await;
+        ds.set("interface.esr1a.ethernet1".to_string(), b"up", None)
+            .await;
+        ds.delete_matching("interface.esr1a.*").await;
+        sleep(Duration::from_millis(100)).await;
+
+        let all = Pattern::parse(">").unwrap();
+        let changes = ds.changes(start, &all, None).unwrap();
+        let kinds: Vec<(&str, &ChangeKind)> = changes
+            .changes
+            .iter()
+            .map(|change| (change.key.as_str(), &change.kind))
+            .collect();
+        assert!(matches!(
+            kinds[..],
+            [
+                ("bgp.esr1a.neighbor1", ChangeKind::Set { .. }),
+                ("interface.esr1a.ethernet1", ChangeKind::Set { .. }),
+                ("interface.esr1a.ethernet1", ChangeKind::Delete),
+                ("bgp.esr1a.neighbor1", ChangeKind::Expire { .. }),
+            ]
+        ));
+        assert!(changes
+            .changes
+            .windows(2)
+            .all(|pair| pair[0].revision < pair[1].revision));
+        assert_eq!(changes.revision, ds.snapshot().revision());
+
+        // resuming from the revision returned misses nothing
+        ds.set("bgp.esr1a.neighbor2".to_string(), b"idle", None)
+            .await;
+        let bgp = Pattern::parse("bgp.>").unwrap();
+        let later = ds.changes(changes.revision, &bgp, None).unwrap();
+        assert_eq!(later.changes.len(), 1);
+        assert_eq!(later.changes[0].key, "bgp.esr1a.neighbor2");
+    }
+
+    #[tokio::test]
+    async fn test_sharded_results_match_single_tree() {
+        let ds = Datastore::new(1);
+        let mut tree = NestedMap::new(1);
+        for top in ["bgp", "interface", "system", "lldp", "a", "z"] {
+            for device in ["esr1a", "esr1b"] {
+                let key = format!("{}.{}.count", top, device);
+                ds.set(key.clone(), b"2", None).await;
+                tree.set(
+                    &key,
+                    &crate::nestedmap::test_helpers::create_item(&key, b"2"),
+                    None,
+                );
+            }
+        }
+
+        let keys = |items: Vec<Item>| items.into_iter().map(|item| item.key).collect::<Vec<_>>();
+        for pattern in ["*.esr1a.count", "bgp.>", "*.{device}.*", "nothing.>"] {
+            assert_eq!(
+                keys(ds.query(pattern, None).await),
+                keys(tree.query(pattern, None)),
+                "{}",
+                pattern
+            );
+        }
+
+        let patterns = PatternSet::new()
+            .include(Pattern::parse("z.>").unwrap())
+            .include(Pattern::parse("a.>").unwrap())
+            .exclude(Pattern::parse("*.esr1b.*").unwrap());
+        assert_eq!(
+            keys(ds.query_set(&patterns, None).await),
+            vec!["a.esr1a.count", "z.esr1a.count"]
+        );
+
+        let results = ds.aggregate("*.*.count", Aggregation::Sum, None).await;
+        assert_eq!(results[0].value, 24.0);
+
+        assert_eq!(ds.delete_matching("*.esr1b.count").await, 6);
+        assert_eq!(ds.query("*.*.count", None).await.len(), 6);
+    }
 }
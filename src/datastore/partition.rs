@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use super::Pattern;
+
+/// Splits the key space between nodes by assigning the keys matching a
+/// pattern, e.g. `interface.lab1.>`, to the node at an address. The first
+/// assignment matching a key owns it, so a catch-all like `>` goes last;
+/// keys no assignment matches can't be stored.
+///
+/// Every node has to be given the same assignments, in the same order.
+#[derive(Debug, Clone, Default)]
+pub struct Partitions {
+    assignments: Vec<(Arc<Pattern>, String)>,
+}
+
+impl Partitions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn assign(mut self, pattern: impl Into<Arc<Pattern>>, owner: impl Into<String>) -> Self {
+        self.assignments.push((pattern.into(), owner.into()));
+        self
+    }
+
+    /// The address of the node that owns the key.
+    pub fn owner(&self, key: &str) -> Option<&str> {
+        self.assignments
+            .iter()
+            .find(|(pattern, _)| pattern.matches(key))
+            .map(|(_, owner)| owner.as_str())
+    }
+
+    /// The addresses of every node that may own keys matching the pattern,
+    /// in assignment order and without repeats.
+    pub fn owners(&self, pattern: &Pattern) -> Vec<&str> {
+        let mut owners: Vec<&str> = Vec::new();
+        for (assigned, owner) in &self.assignments {
+            if !assigned.overlaps(pattern) {
+                continue;
+            }
+            if !owners.contains(&owner.as_str()) {
+                owners.push(owner);
+            }
+            // the keys it matches can't belong to any later assignment
+            if assigned.covers(pattern) {
+                break;
+            }
+        }
+        owners
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partitions() -> Partitions {
+        let assign = |partitions: Partitions, pattern: &str, owner: &str| {
+            partitions.assign(Pattern::parse(pattern).unwrap(), owner)
+        };
+        let partitions = assign(Partitions::new(), "interface.lab1.>", "node1");
+        let partitions = assign(partitions, "bgp.>", "node2");
+        assign(partitions, "interface.>", "node3")
+    }
+
+    #[test]
+    fn test_owner() {
+        let partitions = partitions();
+        assert_eq!(
+            partitions.owner("interface.lab1.esr1a.ethernet1"),
+            Some("node1")
+        );
+        assert_eq!(
+            partitions.owner("interface.lab2.esr1a.ethernet1"),
+            Some("node3")
+        );
+        assert_eq!(partitions.owner("bgp.neighbor.10.0.0.1"), Some("node2"));
+        assert_eq!(partitions.owner("system.hostname"), None);
+    }
+
+    #[test]
+    fn test_owners() {
+        let partitions = partitions();
+        let owners = |pattern: &str| partitions.owners(&Pattern::parse(pattern).unwrap());
+
+        assert_eq!(owners("interface.lab1.*.ethernet1"), vec!["node1"]);
+        assert_eq!(owners("interface.*.*.ethernet1"), vec!["node1", "node3"]);
+        assert_eq!(owners("interface.lab2.>"), vec!["node3"]);
+        assert_eq!(owners(">"), vec!["node1", "node2", "node3"]);
+        assert!(owners("system.>").is_empty());
+    }
+}
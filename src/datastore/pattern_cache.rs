@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::nestedmap::pattern::{Pattern, PatternError};
+
+/// Caches compiled patterns by their source string so hot queries skip
+/// re-parsing. Once it reaches capacity the least recently used pattern is
+/// evicted to make room.
+#[derive(Debug)]
+pub struct PatternCache {
+    patterns: Mutex<Patterns>,
+    capacity: usize,
+}
+
+#[derive(Debug, Default)]
+struct Patterns {
+    // each pattern with the tick it was last used at
+    compiled: HashMap<String, (Arc<Pattern>, u64)>,
+    tick: u64,
+}
+
+impl PatternCache {
+    pub fn new(capacity: usize) -> Self {
+        PatternCache {
+            patterns: Mutex::new(Patterns::default()),
+            capacity,
+        }
+    }
+
+    pub fn get(&self, pattern: &str) -> Result<Arc<Pattern>, PatternError> {
+        let mut patterns = self.patterns.lock().unwrap();
+        patterns.tick += 1;
+        let tick = patterns.tick;
+
+        if let Some((compiled, used)) = patterns.compiled.get_mut(pattern) {
+            *used = tick;
+            return Ok(compiled.clone());
+        }
+
+        let compiled = Arc::new(Pattern::parse(pattern)?);
+        if patterns.compiled.len() >= self.capacity {
+            let oldest = patterns
+                .compiled
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(source, _)| source.clone());
+            if let Some(oldest) = oldest {
+                patterns.compiled.remove(&oldest);
+            }
+        }
+        patterns
+            .compiled
+            .insert(pattern.to_string(), (compiled.clone(), tick));
+
+        Ok(compiled)
+    }
+
+    pub fn len(&self) -> usize {
+        self.patterns.lock().unwrap().compiled.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern_cache() {
+        let cache = PatternCache::new(2);
+
+        let a = cache.get("a.*").unwrap();
+        let again = cache.get("a.*").unwrap();
+        assert!(Arc::ptr_eq(&a, &again));
+        assert_eq!(cache.len(), 1);
+
+        assert!(cache.get("a.>.b").is_err());
+        assert_eq!(cache.len(), 1);
+
+        cache.get("b.*").unwrap();
+        // "a.*" was used more recently than "b.*", so "b.*" makes room
+        cache.get("a.*").unwrap();
+        cache.get("c.*").unwrap();
+        assert_eq!(cache.len(), 2);
+        assert!(Arc::ptr_eq(&a, &cache.get("a.*").unwrap()));
+        assert_eq!(cache.len(), 2);
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::changes::{Change, ChangeKind};
+use super::shards::Locked;
+use super::Datastore;
+use crate::nestedmap::options::SetOptions;
+use crate::nestedmap::pattern::Pattern;
+use crate::nestedmap::Item;
+
+// Copying another datastore: the items and changes come from its watch
+// stream, and keep the ids they were given there, so that its expirations
+// and version preconditions still line up. Nothing expires on its own here;
+// the other datastore's expirations arrive as changes.
+impl Datastore {
+    /// Replaces everything with the items of another datastore's initial
+    /// state or sample, taken with history so that each key's items are
+    /// newest first. Readers see either the old contents or the new ones.
+    pub async fn restore(&self, items: Vec<Item>) {
+        let mut locked = self.shards.write((0..self.shards.len()).collect()).await;
+        self.delete(&mut locked, &Pattern::parse(">").unwrap())
+            .await;
+
+        let mut keys: BTreeMap<String, Vec<Item>> = BTreeMap::new();
+        for item in items {
+            keys.entry(item.key.clone()).or_default().push(item);
+        }
+        for items in keys.into_values() {
+            for item in items.into_iter().rev() {
+                self.put(&mut locked, item, true);
+            }
+        }
+    }
+
+    /// Applies changes read from another datastore, in order, all at once.
+    pub async fn apply(&self, changes: Vec<Change>) {
+        let indexes: BTreeSet<usize> = changes
+            .iter()
+            .map(|change| self.shards.index(&change.key))
+            .collect();
+        let mut locked = self.shards.write(indexes).await;
+
+        for change in changes {
+            match change.kind {
+                ChangeKind::Set {
+                    item,
+                    preserve_history,
+                } => self.put(&mut locked, item, preserve_history),
+                ChangeKind::Delete => {
+                    // the key itself, not what it would match as a pattern
+                    if locked.map_mut(&change.key).delete_values(&change.key) {
+                        locked.record(change.key, ChangeKind::Delete);
+                    }
+                }
+                ChangeKind::Expire { id } => {
+                    if locked.map_mut(&change.key).delete_by_id(&change.key, id) {
+                        locked.record(change.key, ChangeKind::Expire { id });
+                    }
+                }
+            }
+        }
+    }
+
+    // Stores an item as it is, id included
+    fn put(&self, locked: &mut Locked<'_>, item: Item, preserve_history: bool) {
+        self.shards.skip_ids_to(item.id.max(0) as u64);
+        let options = SetOptions::new().preserve_history(preserve_history);
+        locked
+            .map_mut(&item.key)
+            .set(&item.key, &item, Some(options));
+        locked.record(
+            item.key.clone(),
+            ChangeKind::Set {
+                item,
+                preserve_history,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    use crate::datastore::{WatchEvent, WatchOptions};
+    use crate::nestedmap::options::GetOptions;
+
+    #[tokio::test]
+    async fn test_replicate() {
+        let primary = Datastore::new(3);
+        let replica = Datastore::new(3);
+        let history = || Some(SetOptions::new().preserve_history(true));
+        let everything = || Pattern::parse(">").unwrap();
+        let contents = |ds: &Datastore| {
+            ds.snapshot()
+                .query(">", Some(GetOptions::new().history_count(3)))
+        };
+
+        primary
+            .set("interface.esr1a.ethernet1".to_string(), b"down", history())
+            .await;
+        primary
+            .set("interface.esr1a.ethernet1".to_string(), b"up", history())
+            .await;
+        primary
+            .set("bgp.esr1a.neighbor1".to_string(), b"idle", None)
+            .await;
+        replica
+            .set("interface.esr9z.ethernet1".to_string(), b"stale", None)
+            .await;
+
+        let mut watch = Box::pin(primary.watch(
+            everything(),
+            WatchOptions::new().initial_state(true).history(true),
+        ));
+        let Some(Ok(WatchEvent::Initial { items, .. })) = watch.next().await else {
+            panic!("expected the initial state");
+        };
+        replica.restore(items).await;
+        assert_eq!(contents(&replica), contents(&primary));
+
+        primary
+            .set("interface.esr1a.ethernet1".to_string(), b"down", history())
+            .await;
+        primary.delete_matching("bgp.>").await;
+        // keys aren't validated, so one can look like a pattern
+        primary.set("a.*".to_string(), b"up", None).await;
+        primary.set("a.b".to_string(), b"up", None).await;
+        primary
+            .delete_matching(&Pattern::parse(r"a.~re:^\*$").unwrap())
+            .await;
+        let id = primary.get("interface.esr1a.ethernet1").await.unwrap().id;
+        {
+            let mut locked = primary.shards.lock("interface.esr1a.ethernet1").await;
+            locked
+                .map_mut("interface.esr1a.ethernet1")
+                .delete_by_id("interface.esr1a.ethernet1", id);
+            locked.record("interface.esr1a.ethernet1", ChangeKind::Expire { id });
+        }
+
+        let Some(Ok(WatchEvent::Changes { changes, .. })) = watch.next().await else {
+            panic!("expected changes");
+        };
+        assert_eq!(changes.len(), 6);
+        replica.apply(changes).await;
+        assert_eq!(contents(&replica), contents(&primary));
+        assert!(replica.get("a.*").await.is_none());
+        assert!(replica.get("a.b").await.is_some());
+
+        // a replica promoted to primary never reuses the copied ids
+        replica
+            .set("interface.esr1a.ethernet2".to_string(), b"up", None)
+            .await;
+        let promoted = replica.get("interface.esr1a.ethernet2").await.unwrap();
+        assert!(promoted.id > id);
+    }
+}
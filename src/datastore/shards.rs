@@ -0,0 +1,273 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex as SyncMutex};
+
+use arc_swap::ArcSwap;
+use tokio::sync::{watch, Mutex, MutexGuard};
+
+use super::changes::{ChangeKind, ChangeLog, Changes, ChangesError};
+use super::snapshot::Snapshot;
+use crate::nestedmap::config::DELIMITER;
+use crate::nestedmap::pattern::{Pattern, Segment};
+use crate::nestedmap::{Item, NestedMap};
+
+/// The datastore's tree split into shards by top-level key segment, so that
+/// writes under `bgp` don't wait behind writes under `interface`.
+///
+/// Readers never lock: writers publish every shard's new state by swapping a
+/// single root, and readers take a `Snapshot` of whatever root is current.
+/// Since the trees share unchanged subtrees, publishing only costs copying
+/// the levels a write touched, and a snapshot stays valid however long it
+/// is held.
+///
+/// Every publish commits the changes it contains to the change log, under
+/// the log's lock, so snapshots and the log agree on what each revision
+/// holds.
+///
+/// Operations that lock several shards always lock them in ascending order,
+/// so they can't deadlock each other.
+#[derive(Debug)]
+pub struct Shards {
+    // The latest state of each shard, only touched by writers
+    writers: Vec<Mutex<NestedMap>>,
+    published: ArcSwap<Snapshot>,
+    log: SyncMutex<ChangeLog>,
+    // The revision of the last publish, for anything waiting on changes
+    revision: watch::Sender<u64>,
+}
+
+impl Shards {
+    pub fn new(count: usize, log: ChangeLog, shard: impl Fn() -> NestedMap) -> Self {
+        let maps: Vec<NestedMap> = (0..count.max(1)).map(|_| shard()).collect();
+        Shards {
+            published: ArcSwap::from_pointee(Snapshot::new(
+                0,
+                maps.iter().cloned().map(Arc::new).collect(),
+            )),
+            writers: maps.into_iter().map(Mutex::new).collect(),
+            log: SyncMutex::new(log),
+            revision: watch::Sender::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.writers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writers.is_empty()
+    }
+
+    pub fn index(&self, key: &str) -> usize {
+        index(key, self.writers.len())
+    }
+
+    /// The shards that can hold keys matching the pattern: just one if it
+    /// starts with a literal, all of them otherwise.
+    pub fn indexes_for(&self, pattern: &Pattern) -> BTreeSet<usize> {
+        indexes_for(pattern, self.writers.len())
+    }
+
+    /// The state of every shard as of the last completed write.
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.published.load_full()
+    }
+
+    /// The next revision, for an item id.
+    pub fn next_id(&self) -> u64 {
+        self.log.lock().unwrap().allocate()
+    }
+
+    /// Keeps item ids handed out from now on above this one.
+    pub fn skip_ids_to(&self, id: u64) {
+        self.log.lock().unwrap().skip_to(id);
+    }
+
+    pub fn changes(
+        &self,
+        since: u64,
+        pattern: &Pattern,
+        limit: Option<usize>,
+    ) -> Result<Changes, ChangesError> {
+        self.log.lock().unwrap().since(since, pattern, limit)
+    }
+
+    /// Notified with the revision of every publish.
+    pub fn revisions(&self) -> watch::Receiver<u64> {
+        self.revision.subscribe()
+    }
+
+    /// Keeps revisions increasing across restarts by reserving them in a
+    /// file. Only meant to be called before anything is written.
+    pub fn persist_revisions(&self, path: PathBuf) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        let revision = log.persist(path)?;
+        self.published
+            .rcu(|published| published.with_changes(&[], revision));
+        self.revision.send_replace(revision);
+        Ok(())
+    }
+
+    /// Write-locks the given shards in ascending order.
+    pub async fn write(&self, indexes: BTreeSet<usize>) -> Locked<'_> {
+        let mut guards = BTreeMap::new();
+        for index in indexes {
+            guards.insert(index, self.writers[index].lock().await);
+        }
+        Locked {
+            shards: self,
+            guards,
+            dirty: BTreeSet::new(),
+            changes: Vec::new(),
+        }
+    }
+
+    /// Write-locks the shard holding the key.
+    pub async fn lock(&self, key: &str) -> Locked<'_> {
+        self.write(BTreeSet::from([self.index(key)])).await
+    }
+}
+
+pub(super) fn index(key: &str, count: usize) -> usize {
+    let top = key.split(DELIMITER).next().unwrap_or_default();
+    let mut hasher = DefaultHasher::new();
+    top.hash(&mut hasher);
+    hasher.finish() as usize % count
+}
+
+pub(super) fn indexes_for(pattern: &Pattern, count: usize) -> BTreeSet<usize> {
+    match pattern.segments().first() {
+        Some(Segment::Literal(top)) => BTreeSet::from([index(top, count)]),
+        _ => (0..count).collect(),
+    }
+}
+
+/// Write guards on several shards, looked up by key. Whatever was changed
+/// through them is published when they are dropped, all at once, together
+/// with the changes recorded.
+pub struct Locked<'a> {
+    shards: &'a Shards,
+    guards: BTreeMap<usize, MutexGuard<'a, NestedMap>>,
+    dirty: BTreeSet<usize>,
+    changes: Vec<(String, ChangeKind)>,
+}
+
+impl Locked<'_> {
+    // Panics if the key's shard wasn't locked
+    pub fn map(&self, key: &str) -> &NestedMap {
+        &self.guards[&self.shards.index(key)]
+    }
+
+    pub fn map_mut(&mut self, key: &str) -> &mut NestedMap {
+        let index = self.shards.index(key);
+        self.dirty.insert(index);
+        self.guards.get_mut(&index).expect("shard is not locked")
+    }
+
+    /// Records a change made through these guards for the change log.
+    pub fn record(&mut self, key: impl Into<String>, kind: ChangeKind) {
+        self.changes.push((key.into(), kind));
+    }
+
+    /// The locked shards that can hold keys matching the pattern.
+    pub fn maps_for(&mut self, pattern: &Pattern) -> Vec<&mut NestedMap> {
+        let indexes = self.shards.indexes_for(pattern);
+        self.dirty.extend(
+            indexes
+                .iter()
+                .filter(|index| self.guards.contains_key(index)),
+        );
+        self.guards
+            .iter_mut()
+            .filter(|(index, _)| indexes.contains(index))
+            .map(|(_, guard)| &mut **guard)
+            .collect()
+    }
+}
+
+impl Drop for Locked<'_> {
+    // Publishes while the guards are still held, so that writes to a shard
+    // are published in the order they were made
+    fn drop(&mut self) {
+        if self.dirty.is_empty() {
+            return;
+        }
+        let changed: Vec<(usize, Arc<NestedMap>)> = self
+            .dirty
+            .iter()
+            .map(|index| (*index, Arc::new(self.guards[index].clone())))
+            .collect();
+        let mut log = self.shards.log.lock().unwrap();
+        let revision = log.commit(std::mem::take(&mut self.changes));
+        self.shards
+            .published
+            .rcu(|published| published.with_changes(&changed, revision));
+        self.shards.revision.send_replace(revision);
+    }
+}
+
+/// Orders results gathered shard by shard the way a single tree would
+/// return them: by key, and each key's history newest first as the shard
+/// returned it.
+pub fn merge(mut items: Vec<Item>) -> Vec<Item> {
+    items.sort_by(|a, b| a.key.cmp(&b.key));
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nestedmap::test_helpers::create_item;
+
+    #[test]
+    fn test_indexes_for() {
+        let shards = Shards::new(8, ChangeLog::new(10), || NestedMap::new(1));
+
+        let top = shards.index("interface");
+        assert_eq!(shards.index("interface.lab1.esr1a"), top);
+        assert_eq!(
+            shards.indexes_for(&Pattern::parse("interface.*.esr1a").unwrap()),
+            BTreeSet::from([top])
+        );
+        assert_eq!(
+            shards
+                .indexes_for(&Pattern::parse("*.lab1.>").unwrap())
+                .len(),
+            8
+        );
+    }
+
+    #[tokio::test]
+    async fn test_snapshots() {
+        let shards = Shards::new(8, ChangeLog::new(10), || NestedMap::new(1));
+        let key = "interface.lab1.esr1a";
+        let set = |locked: &mut Locked<'_>, value: &[u8]| {
+            let item = create_item(key, value);
+            locked.map_mut(key).set(key, &item, None);
+            locked.record(
+                key,
+                ChangeKind::Set {
+                    item,
+                    preserve_history: false,
+                },
+            );
+        };
+
+        set(&mut shards.lock(key).await, b"up");
+        let before = shards.snapshot();
+
+        // unpublished until the guard is dropped
+        let mut locked = shards.lock(key).await;
+        set(&mut locked, b"down");
+        assert_eq!(shards.snapshot().get(key).unwrap().value, b"up");
+        assert_eq!(shards.snapshot().revision(), before.revision());
+        drop(locked);
+
+        assert_eq!(shards.snapshot().get(key).unwrap().value, b"down");
+        assert!(shards.snapshot().revision() > before.revision());
+        assert_eq!(before.get(key).unwrap().value, b"up");
+    }
+}
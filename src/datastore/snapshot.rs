@@ -0,0 +1,196 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::shards::{self, index, indexes_for};
+use crate::nestedmap::aggregate::{AggregateResult, Aggregation, GroupBy};
+use crate::nestedmap::options::GetOptions;
+use crate::nestedmap::pattern::{AsPattern, Pattern, PatternSet};
+use crate::nestedmap::{Item, NestedMap};
+
+/// A read-only view of the whole datastore as of one point in time, pinned
+/// to the revision of the last change it includes. Every read through
+/// it sees the same state, e.g. all pages of a report, and holding it never
+/// blocks writers.
+#[derive(Debug)]
+pub struct Snapshot {
+    revision: u64,
+    maps: Vec<Arc<NestedMap>>,
+}
+
+impl Snapshot {
+    pub(super) fn new(revision: u64, maps: Vec<Arc<NestedMap>>) -> Self {
+        Snapshot { revision, maps }
+    }
+
+    /// Changes after this revision aren't visible in the snapshot.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    // The snapshot at a later revision, with some shards replaced
+    pub(super) fn with_changes(
+        &self,
+        changed: &[(usize, Arc<NestedMap>)],
+        revision: u64,
+    ) -> Snapshot {
+        let mut maps = self.maps.clone();
+        for (index, map) in changed {
+            maps[*index] = map.clone();
+        }
+        Snapshot::new(revision, maps)
+    }
+
+    pub(super) fn map(&self, key: &str) -> &NestedMap {
+        &self.maps[index(key, self.maps.len())]
+    }
+
+    // The shards that can hold keys matching the pattern, in order
+    pub(super) fn maps_for(&self, pattern: &Pattern) -> Vec<&NestedMap> {
+        indexes_for(pattern, self.maps.len())
+            .into_iter()
+            .map(|index| &*self.maps[index])
+            .collect()
+    }
+
+    pub fn get(&self, key: &str) -> Option<Item> {
+        self.map(key).get(key).cloned()
+    }
+
+    /// Gets many keys, in order.
+    pub fn batch_get<S: AsRef<str>>(&self, keys: &[S]) -> Vec<Option<Item>> {
+        keys.iter().map(|key| self.get(key.as_ref())).collect()
+    }
+
+    pub fn query<P: AsPattern + ?Sized>(
+        &self,
+        pattern: &P,
+        options: Option<GetOptions>,
+    ) -> Vec<Item> {
+        let Ok(pattern) = pattern.as_pattern() else {
+            return Vec::new();
+        };
+        let options = options.unwrap_or_default();
+
+        let mut items = Vec::new();
+        for map in self.maps_for(&pattern) {
+            items.extend(map.query(pattern.as_ref(), Some(options.clone())));
+        }
+        shards::merge(items)
+    }
+
+    pub fn query_set(&self, patterns: &PatternSet, options: Option<GetOptions>) -> Vec<Item> {
+        let options = options.unwrap_or_default();
+        let indexes: BTreeSet<usize> = patterns
+            .includes()
+            .iter()
+            .flat_map(|pattern| indexes_for(pattern, self.maps.len()))
+            .collect();
+
+        let mut items = Vec::new();
+        for index in indexes {
+            items.extend(self.maps[index].query_set(patterns, Some(options.clone())));
+        }
+        shards::merge(items)
+    }
+
+    pub fn aggregate<P: AsPattern + ?Sized>(
+        &self,
+        pattern: &P,
+        aggregation: Aggregation,
+        group_by: Option<&GroupBy>,
+    ) -> Vec<AggregateResult> {
+        let Ok(pattern) = pattern.as_pattern() else {
+            return Vec::new();
+        };
+        NestedMap::aggregate_maps(
+            self.maps_for(&pattern),
+            pattern.as_ref(),
+            aggregation,
+            group_by,
+        )
+    }
+}
+
+/// Keeps snapshots alive between requests, looked up by their revision, so
+/// that a remote client can read from the same snapshot several times. A
+/// snapshot is dropped once it goes unused for its lease.
+#[derive(Debug, Default)]
+pub struct SnapshotLeases {
+    leases: Mutex<HashMap<u64, Lease>>,
+}
+
+#[derive(Debug)]
+struct Lease {
+    snapshot: Arc<Snapshot>,
+    duration: Duration,
+    expires_at: Instant,
+}
+
+impl SnapshotLeases {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps the snapshot until it has gone unused for `duration`, and
+    /// returns the token to look it up by. Opening a snapshot that is
+    /// already leased extends its lease.
+    pub fn open(&self, snapshot: Arc<Snapshot>, duration: Duration) -> u64 {
+        let mut leases = self.leases.lock().unwrap();
+        let now = Instant::now();
+        leases.retain(|_, lease| lease.expires_at > now);
+
+        let token = snapshot.revision();
+        let lease = leases.entry(token).or_insert(Lease {
+            snapshot,
+            duration,
+            expires_at: now,
+        });
+        lease.duration = lease.duration.max(duration);
+        lease.expires_at = now + lease.duration;
+        token
+    }
+
+    /// The leased snapshot, renewing its lease, or `None` if it expired.
+    pub fn get(&self, token: u64) -> Option<Arc<Snapshot>> {
+        let mut leases = self.leases.lock().unwrap();
+        let now = Instant::now();
+        leases.retain(|_, lease| lease.expires_at > now);
+
+        let lease = leases.get_mut(&token)?;
+        lease.expires_at = now + lease.duration;
+        Some(lease.snapshot.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.leases.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leases() {
+        let leases = SnapshotLeases::new();
+        let snapshot = |revision| Arc::new(Snapshot::new(revision, Vec::new()));
+
+        let token = leases.open(snapshot(7), Duration::from_secs(60));
+        assert_eq!(token, 7);
+        assert_eq!(leases.get(token).unwrap().revision(), 7);
+        assert!(leases.get(8).is_none());
+
+        // reopening keeps the longer lease
+        leases.open(snapshot(7), Duration::ZERO);
+        assert!(leases.get(token).is_some());
+
+        leases.open(snapshot(8), Duration::ZERO);
+        assert!(leases.get(8).is_none());
+        assert_eq!(leases.len(), 1);
+    }
+}
@@ -0,0 +1,84 @@
+use crate::nestedmap::options::{Precondition, SetOptions};
+
+/// A batch of writes applied all-or-nothing, guarded by preconditions on the
+/// current state of any keys, e.g.
+///
+/// ```ignore
+/// Transaction::new()
+///     .compare("device.esr1a.summary", Precondition::Version(7))
+///     .set("device.esr1a.interfaces", interfaces, None)
+///     .set("device.esr1a.summary", summary, None)
+/// ```
+///
+/// Preconditions on an operation's own `SetOptions` are checked together
+/// with the compares, against the state before the transaction.
+#[derive(Debug, Clone, Default)]
+pub struct Transaction {
+    pub compares: Vec<(String, Precondition)>,
+    pub operations: Vec<Operation>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Operation {
+    Set {
+        key: String,
+        value: Vec<u8>,
+        options: Option<SetOptions>,
+    },
+    // Removes the values of every key matching a pattern
+    Delete {
+        key: String,
+    },
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn compare(mut self, key: impl Into<String>, precondition: Precondition) -> Self {
+        self.compares.push((key.into(), precondition));
+        self
+    }
+
+    pub fn set(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<Vec<u8>>,
+        options: Option<SetOptions>,
+    ) -> Self {
+        self.operations.push(Operation::Set {
+            key: key.into(),
+            value: value.into(),
+            options,
+        });
+        self
+    }
+
+    pub fn delete(mut self, key: impl Into<String>) -> Self {
+        self.operations.push(Operation::Delete { key: key.into() });
+        self
+    }
+
+    // Every precondition that has to hold, including the operations' own
+    pub(crate) fn preconditions(&self) -> impl Iterator<Item = (&str, Precondition)> {
+        let compares = self
+            .compares
+            .iter()
+            .map(|(key, precondition)| (key.as_str(), *precondition));
+        let operations = self
+            .operations
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::Set {
+                    key,
+                    options: Some(options),
+                    ..
+                } => options
+                    .precondition
+                    .map(|precondition| (key.as_str(), precondition)),
+                _ => None,
+            });
+        compares.chain(operations)
+    }
+}
@@ -0,0 +1,574 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use super::changes::{Change, ChangeKind, Changes, ChangesError};
+use super::shards::Shards;
+use super::Datastore;
+use crate::nestedmap::options::GetOptions;
+use crate::nestedmap::pattern::Pattern;
+use crate::nestedmap::Item;
+
+// Upper bound on the changes delivered in one event
+const WATCH_BATCH_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Default)]
+pub struct WatchOptions {
+    // Start with the items currently matching the pattern
+    pub initial_state: bool,
+    // Start after this revision instead of now, e.g. to resume a watch;
+    // ignored when the initial state is asked for
+    pub since: Option<u64>,
+    pub mode: WatchMode,
+    // Include every item kept for each key in the initial state and samples,
+    // newest first, instead of just the latest
+    pub history: bool,
+}
+
+/// How often a watcher hears about changes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WatchMode {
+    /// Every change, in order.
+    #[default]
+    OnChange,
+    /// The changes at most once per interval, with each run of plain sets to
+    /// a key cut down to its last. Keys that flap produce one change per
+    /// interval instead of thousands, while deletes, expirations and sets
+    /// that keep history all still arrive, so the changes can be applied.
+    Latest(Duration),
+    /// Every matching item once per interval, whether it changed or not.
+    Sample(Duration),
+}
+
+impl WatchOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn initial_state(mut self, value: bool) -> Self {
+        self.initial_state = value;
+        self
+    }
+
+    pub fn since(mut self, revision: u64) -> Self {
+        self.since = Some(revision);
+        self
+    }
+
+    pub fn mode(mut self, mode: WatchMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn history(mut self, value: bool) -> Self {
+        self.history = value;
+        self
+    }
+
+    fn get_options(&self) -> Option<GetOptions> {
+        self.history
+            .then(|| GetOptions::new().history_count(usize::MAX))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchEvent {
+    /// The items matching the pattern as of `revision`, when the watch was
+    /// started with its initial state.
+    Initial { items: Vec<Item>, revision: u64 },
+    /// Changes to matching keys in the order they were made, up to and
+    /// including `revision`.
+    Changes { changes: Vec<Change>, revision: u64 },
+    /// Every item matching the pattern as of `revision`, replacing whatever
+    /// was seen before: the periodic sample in `WatchMode::Sample`, or a
+    /// resync when a `WatchMode::Latest` watcher fell behind the change log.
+    Sample { items: Vec<Item>, revision: u64 },
+}
+
+/// What a subscriber sees: every change to a matching key, one at a time.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeEvent {
+    Change(Change),
+    /// The subscriber fell so far behind that the changes after revision
+    /// `since`, up to and including `resumed_after`, are no longer kept.
+    /// Changes after `resumed_after` follow; anything that depends on the
+    /// missed ones should be rebuilt from a snapshot.
+    Lagged {
+        since: u64,
+        resumed_after: u64,
+    },
+}
+
+impl Datastore {
+    /// Streams the changes to keys matching the pattern, optionally starting
+    /// with their current items. The initial items and the changes come
+    /// from the same revision, so nothing is missed or seen twice between
+    /// them.
+    ///
+    /// Changes are read from the change log as the stream is polled, so a
+    /// slow consumer never makes anything buffer up on its behalf. An
+    /// `OnChange` watcher that falls so far behind that the changes it
+    /// hasn't seen are no longer kept gets `ChangesError::Compacted`, and the
+    /// stream ends; the other modes coalesce what they missed instead.
+    pub fn watch(
+        &self,
+        pattern: Pattern,
+        options: WatchOptions,
+    ) -> impl Stream<Item = Result<WatchEvent, ChangesError>> + Send + 'static {
+        // Subscribed before reading anything, so no change goes unnoticed
+        let revisions = self.shards.revisions();
+
+        let (initial, since) = if let WatchMode::Sample(_) = options.mode {
+            // the first sample is the initial state
+            (None, 0)
+        } else if options.initial_state {
+            let snapshot = self.shards.snapshot();
+            let initial = WatchEvent::Initial {
+                items: snapshot.query(&pattern, options.get_options()),
+                revision: snapshot.revision(),
+            };
+            (Some(initial), snapshot.revision())
+        } else {
+            let since = options
+                .since
+                .unwrap_or_else(|| self.shards.snapshot().revision());
+            (None, since)
+        };
+
+        let watcher = Watcher {
+            shards: self.shards.clone(),
+            pattern,
+            since,
+            revisions,
+            mode: options.mode,
+            get_options: options.get_options(),
+            next_at: None,
+            done: false,
+        };
+        let initial = futures::stream::iter(initial.map(Ok));
+        initial.chain(futures::stream::unfold(watcher, Watcher::next))
+    }
+
+    /// Streams every change to keys matching the pattern from now on,
+    /// including expirations, for code embedding the datastore. A subscriber
+    /// that falls behind the change log gets `ChangeEvent::Lagged` and then
+    /// carries on with the oldest changes still kept.
+    pub fn subscribe(&self, pattern: Pattern) -> impl Stream<Item = ChangeEvent> + Send + 'static {
+        let revisions = self.shards.revisions();
+        let watcher = Watcher {
+            shards: self.shards.clone(),
+            pattern,
+            since: self.shards.snapshot().revision(),
+            revisions,
+            mode: WatchMode::OnChange,
+            get_options: None,
+            next_at: None,
+            done: false,
+        };
+
+        futures::stream::unfold(watcher, |mut watcher| async move {
+            let events = match watcher.changes(Some(WATCH_BATCH_SIZE)).await {
+                Ok(changes) => changes
+                    .changes
+                    .into_iter()
+                    .map(ChangeEvent::Change)
+                    .collect(),
+                Err(ChangesError::Compacted { oldest }) => {
+                    let lagged = ChangeEvent::Lagged {
+                        since: watcher.since,
+                        resumed_after: oldest,
+                    };
+                    watcher.since = oldest;
+                    vec![lagged]
+                }
+                // only reachable by starting past the latest revision
+                Err(ChangesError::Unknown { .. }) => return None,
+            };
+            Some((futures::stream::iter(events), watcher))
+        })
+        .flatten()
+    }
+}
+
+struct Watcher {
+    shards: Arc<Shards>,
+    pattern: Pattern,
+    since: u64,
+    revisions: watch::Receiver<u64>,
+    mode: WatchMode,
+    get_options: Option<GetOptions>,
+    // When the next event may be sent, in the paced modes
+    next_at: Option<Instant>,
+    done: bool,
+}
+
+impl Watcher {
+    async fn next(mut self) -> Option<(Result<WatchEvent, ChangesError>, Self)> {
+        if self.done {
+            return None;
+        }
+
+        let event = match self.mode {
+            WatchMode::OnChange => {
+                self.changes(Some(WATCH_BATCH_SIZE))
+                    .await
+                    .map(|changes| WatchEvent::Changes {
+                        changes: changes.changes,
+                        revision: changes.revision,
+                    })
+            }
+            WatchMode::Latest(interval) => Ok(self.latest(interval).await),
+            WatchMode::Sample(interval) => {
+                self.pace(interval).await;
+                Ok(self.sample())
+            }
+        };
+        self.done = event.is_err();
+        Some((event, self))
+    }
+
+    // Waits for changes to matching keys and returns them
+    async fn changes(&mut self, limit: Option<usize>) -> Result<Changes, ChangesError> {
+        loop {
+            self.revisions.borrow_and_update();
+            let changes = self.shards.changes(self.since, &self.pattern, limit)?;
+            self.since = changes.revision;
+            if !changes.changes.is_empty() {
+                return Ok(changes);
+            }
+
+            // the sender lives in the shards held here, so it can't close
+            let _ = self.revisions.changed().await;
+        }
+    }
+
+    // The latest change to each key since the last event, once the interval
+    // has passed. Falls back to a sample if the changes can't be read.
+    async fn latest(&mut self, interval: Duration) -> WatchEvent {
+        let mut changes = match self.changes(None).await {
+            Ok(changes) => changes.changes,
+            Err(_) => return self.sample(),
+        };
+
+        self.pace(interval).await;
+        match self.shards.changes(self.since, &self.pattern, None) {
+            Ok(more) => {
+                changes.extend(more.changes);
+                self.since = more.revision;
+            }
+            Err(_) => return self.sample(),
+        }
+
+        WatchEvent::Changes {
+            changes: coalesce(changes),
+            revision: self.since,
+        }
+    }
+
+    fn sample(&mut self) -> WatchEvent {
+        let snapshot = self.shards.snapshot();
+        self.since = snapshot.revision();
+        WatchEvent::Sample {
+            items: snapshot.query(&self.pattern, self.get_options.clone()),
+            revision: snapshot.revision(),
+        }
+    }
+
+    // Sleeps until an interval after the last event
+    async fn pace(&mut self, interval: Duration) {
+        if let Some(next_at) = self.next_at {
+            tokio::time::sleep_until(next_at).await;
+        }
+        self.next_at = Some(Instant::now() + interval);
+    }
+}
+
+// Drops each plain set, which replaces a key's latest item and keeps no
+// history, whose key's next change is another plain set. Applying what's
+// left, in revision order, gives the same result as applying everything.
+fn coalesce(changes: Vec<Change>) -> Vec<Change> {
+    // keys whose next change is a plain set
+    let mut replaced = HashSet::new();
+    let mut kept: Vec<Change> = changes
+        .into_iter()
+        .rev()
+        .filter(|change| {
+            let plain = matches!(
+                change.kind,
+                ChangeKind::Set {
+                    preserve_history: false,
+                    ..
+                }
+            );
+            if !plain {
+                replaced.remove(&change.key);
+                return true;
+            }
+            replaced.insert(change.key.clone())
+        })
+        .collect();
+    kept.reverse();
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::datastore::changes::ChangeLog;
+    use crate::nestedmap::test_helpers::create_item;
+    use crate::nestedmap::NestedMap;
+
+    #[tokio::test]
+    async fn test_watch() {
+        let ds = Arc::new(Datastore::new(1));
+        let pattern = || Pattern::parse("interface.*.ethernet1").unwrap();
+        ds.set("interface.esr1a.ethernet1".to_string(), b"up", None)
+            .await;
+
+        // writers racing the start of the watch
+        let writer = {
+            let ds = ds.clone();
+            tokio::spawn(async move {
+                for i in 0..100 {
+                    let key = format!("interface.esr{}.ethernet1", i % 10);
+                    ds.set(key, i.to_string().as_bytes(), None).await;
+                }
+            })
+        };
+
+        let mut watch = Box::pin(ds.watch(pattern(), WatchOptions::new().initial_state(true)));
+        let Some(Ok(WatchEvent::Initial { items, revision })) = watch.next().await else {
+            panic!("expected the initial state");
+        };
+        writer.await.unwrap();
+
+        // replaying the changes on top of the initial state ends up with the
+        // current state, so none were missed or applied twice
+        let mut state: Vec<(String, Vec<u8>)> = items
+            .into_iter()
+            .map(|item| (item.key, item.value))
+            .collect();
+        let mut seen = revision;
+        while seen < ds.snapshot().revision() {
+            let event = tokio::time::timeout(Duration::from_secs(1), watch.next())
+                .await
+                .unwrap();
+            let Some(Ok(WatchEvent::Changes { changes, revision })) = event else {
+                panic!("expected changes");
+            };
+            for change in changes {
+                assert!(change.revision > seen);
+                seen = change.revision;
+                let ChangeKind::Set { item, .. } = change.kind else {
+                    panic!("expected a set");
+                };
+                state.retain(|(key, _)| *key != item.key);
+                state.push((item.key, item.value));
+            }
+            seen = seen.max(revision);
+        }
+        state.sort();
+
+        let current: Vec<(String, Vec<u8>)> = ds
+            .query(&pattern(), None)
+            .await
+            .into_iter()
+            .map(|item| (item.key, item.value))
+            .collect();
+        assert_eq!(state, current);
+    }
+
+    #[tokio::test]
+    async fn test_watch_resumes() {
+        let ds = Datastore::new(1);
+        ds.set("bgp.esr1a.neighbor1".to_string(), b"idle", None)
+            .await;
+        let since = ds.snapshot().revision();
+        ds.set("bgp.esr1a.neighbor1".to_string(), b"established", None)
+            .await;
+
+        let pattern = Pattern::parse("bgp.>").unwrap();
+        let mut watch = Box::pin(ds.watch(pattern, WatchOptions::new().since(since)));
+        let Some(Ok(WatchEvent::Changes { changes, .. })) = watch.next().await else {
+            panic!("expected changes");
+        };
+        assert_eq!(changes.len(), 1);
+
+        let mut watch = Box::pin(ds.watch(
+            Pattern::parse(">").unwrap(),
+            WatchOptions::new().since(since + 100),
+        ));
+        assert!(matches!(
+            watch.next().await,
+            Some(Err(ChangesError::Unknown { .. }))
+        ));
+        assert!(watch.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_watch_latest() {
+        let ds = Datastore::new(1);
+        let key = "interface.esr1a.ethernet1".to_string();
+        let interval = Duration::from_millis(50);
+        let mut watch = Box::pin(ds.watch(
+            Pattern::parse("interface.>").unwrap(),
+            WatchOptions::new().mode(WatchMode::Latest(interval)),
+        ));
+
+        // a flapping interface only shows up once per interval, with its
+        // latest value
+        for i in 0..100 {
+            let value: &[u8] = if i % 2 == 0 { b"down" } else { b"up" };
+            ds.set(key.clone(), value, None).await;
+        }
+        let Some(Ok(WatchEvent::Changes { changes, .. })) = watch.next().await else {
+            panic!("expected changes");
+        };
+        assert_eq!(changes.len(), 1);
+        let ChangeKind::Set { item, .. } = &changes[0].kind else {
+            panic!("expected a set");
+        };
+        assert_eq!(item.value, b"up");
+
+        let start = std::time::Instant::now();
+        ds.set(key.clone(), b"down", None).await;
+        ds.set("interface.esr1a.ethernet2".to_string(), b"up", None)
+            .await;
+        let Some(Ok(WatchEvent::Changes { changes, .. })) = watch.next().await else {
+            panic!("expected changes");
+        };
+        assert!(start.elapsed() >= interval / 2);
+        let keys: Vec<&str> = changes.iter().map(|change| change.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec!["interface.esr1a.ethernet1", "interface.esr1a.ethernet2"]
+        );
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let set = |revision: u64, key: &str, preserve_history: bool| Change {
+            revision,
+            key: key.to_string(),
+            kind: ChangeKind::Set {
+                item: create_item(key, b"up"),
+                preserve_history,
+            },
+        };
+        let change = |revision: u64, key: &str, kind: ChangeKind| Change {
+            revision,
+            key: key.to_string(),
+            kind,
+        };
+
+        let changes = vec![
+            set(1, "a", false),
+            set(2, "b", false),
+            set(3, "a", false),
+            // a delete in between keeps the sets on either side
+            change(4, "b", ChangeKind::Delete),
+            set(5, "b", false),
+            // as does a set that keeps history
+            set(6, "a", true),
+            set(7, "a", false),
+            set(8, "a", false),
+            change(9, "c", ChangeKind::Expire { id: 1 }),
+            set(10, "b", false),
+        ];
+        let revisions: Vec<u64> = coalesce(changes)
+            .iter()
+            .map(|change| change.revision)
+            .collect();
+        assert_eq!(revisions, vec![2, 3, 4, 6, 8, 9, 10]);
+    }
+
+    #[tokio::test]
+    async fn test_watch_latest_keeps_deletes() {
+        let ds = Datastore::new(1);
+        let key = "interface.esr1a.ethernet1".to_string();
+        let mut watch = Box::pin(ds.watch(
+            Pattern::parse("interface.>").unwrap(),
+            WatchOptions::new().mode(WatchMode::Latest(Duration::from_millis(50))),
+        ));
+
+        ds.set(key.clone(), b"up", None).await;
+        ds.delete_matching(key.as_str()).await;
+        ds.set("interface.esr1a.ethernet2".to_string(), b"up", None)
+            .await;
+        let Some(Ok(WatchEvent::Changes { changes, .. })) = watch.next().await else {
+            panic!("expected changes");
+        };
+        // dropping the set would be fine, but the delete has to arrive
+        assert!(changes
+            .iter()
+            .any(|change| change.key == key && change.kind == ChangeKind::Delete));
+    }
+
+    #[tokio::test]
+    async fn test_watch_sample() {
+        let ds = Datastore::new(1);
+        ds.set("interface.esr1a.ethernet1".to_string(), b"up", None)
+            .await;
+        let interval = Duration::from_millis(20);
+        let mut watch = Box::pin(ds.watch(
+            Pattern::parse("interface.>").unwrap(),
+            WatchOptions::new().mode(WatchMode::Sample(interval)),
+        ));
+
+        // every interval, whether anything changed or not
+        for _ in 0..3 {
+            let Some(Ok(WatchEvent::Sample { items, .. })) = watch.next().await else {
+                panic!("expected a sample");
+            };
+            assert_eq!(items.len(), 1);
+        }
+
+        ds.set("interface.esr1a.ethernet1".to_string(), b"down", None)
+            .await;
+        let Some(Ok(WatchEvent::Sample { items, revision })) = watch.next().await else {
+            panic!("expected a sample");
+        };
+        assert_eq!(items[0].value, b"down");
+        assert_eq!(revision, ds.snapshot().revision());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let ds = Datastore::with_shards(Shards::new(1, ChangeLog::new(4), || NestedMap::new(1)));
+        let mut changes = Box::pin(ds.subscribe(Pattern::parse("bgp.>").unwrap()));
+        let key = |i: usize| format!("bgp.esr1a.neighbor{}", i);
+        let next = |event: Option<ChangeEvent>| match event {
+            Some(ChangeEvent::Change(change)) => change.key,
+            event => panic!("expected a change, got {:?}", event),
+        };
+
+        ds.set(key(0), b"idle", None).await;
+        ds.set("interface.esr1a.ethernet1".to_string(), b"up", None)
+            .await;
+        ds.set(key(1), b"idle", None).await;
+        assert_eq!(next(changes.next().await), key(0));
+        assert_eq!(next(changes.next().await), key(1));
+
+        // only the last 4 changes are kept
+        let since = ds.snapshot().revision();
+        for i in 2..10 {
+            ds.set(key(i), b"idle", None).await;
+        }
+        let Some(ChangeEvent::Lagged {
+            since: lagged_since,
+            ..
+        }) = changes.next().await
+        else {
+            panic!("expected to lag");
+        };
+        assert_eq!(lagged_since, since);
+        for i in 6..10 {
+            assert_eq!(next(changes.next().await), key(i));
+        }
+    }
+}
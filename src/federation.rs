@@ -0,0 +1,153 @@
+// Answers reads for several independent servers, e.g. one per site, by
+// asking every one of them and merging what they return
+use std::collections::HashSet;
+use std::future::Future;
+use std::time::Duration;
+
+use tonic::transport::Channel;
+use tonic::Code;
+
+use crate::datastore::datastore_client::DatastoreClient;
+use crate::datastore::{GetRequest, GetResponse, QueryRequest, QueryResponse, UpstreamError};
+use crate::peers::Peers;
+
+// An upstream slower than this is reported as unavailable rather than
+// holding up the whole answer
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub struct Federation {
+    // In order of precedence, for keys more than one of them holds
+    upstreams: Vec<String>,
+    peers: Peers,
+}
+
+impl Federation {
+    pub fn new(upstreams: Vec<String>) -> Self {
+        Federation {
+            upstreams,
+            peers: Peers::new(UPSTREAM_TIMEOUT),
+        }
+    }
+
+    /// The key from the first upstream that has it.
+    pub async fn get(&self, request: GetRequest) -> Result<GetResponse, tonic::Status> {
+        let replies = self
+            .ask(|mut client| {
+                let request = request.clone();
+                async move { client.get(request).await }
+            })
+            .await;
+
+        let mut response = GetResponse::default();
+        for (upstream, reply) in replies {
+            match reply {
+                Ok(reply) => {
+                    if response.item.is_none() {
+                        response.item = reply.item;
+                    }
+                    response.upstream_errors.extend(reply.upstream_errors);
+                }
+                Err(status) if status.code() == Code::InvalidArgument => return Err(status),
+                Err(status) => response
+                    .upstream_errors
+                    .push(upstream_error(upstream, status)),
+            }
+        }
+
+        if response.item.is_none() && !response.upstream_errors.is_empty() {
+            return Err(unavailable(&response.upstream_errors));
+        }
+        Ok(response)
+    }
+
+    /// The items from every upstream, ordered by key. A key more than one
+    /// upstream holds is taken from the first of them.
+    pub async fn query(&self, request: QueryRequest) -> Result<QueryResponse, tonic::Status> {
+        if request.snapshot.is_some() {
+            return Err(tonic::Status::invalid_argument(
+                "snapshots aren't supported by an aggregator",
+            ));
+        }
+        let replies = self
+            .ask(|mut client| {
+                let request = request.clone();
+                async move { client.query(request).await }
+            })
+            .await;
+
+        let mut response = QueryResponse::default();
+        // keys already taken from an earlier upstream
+        let mut seen = HashSet::new();
+        for (upstream, reply) in replies {
+            match reply {
+                Ok(reply) => {
+                    let keys: HashSet<String> =
+                        reply.items.iter().map(|item| item.key.clone()).collect();
+                    response.items.extend(
+                        reply
+                            .items
+                            .into_iter()
+                            .filter(|item| !seen.contains(&item.key)),
+                    );
+                    seen.extend(keys);
+                    response.upstream_errors.extend(reply.upstream_errors);
+                }
+                Err(status) if status.code() == Code::NotFound => {}
+                // the request is at fault, not the upstream
+                Err(status) if status.code() == Code::InvalidArgument => return Err(status),
+                Err(status) => response
+                    .upstream_errors
+                    .push(upstream_error(upstream, status)),
+            }
+        }
+
+        if response.items.is_empty() {
+            if response.upstream_errors.is_empty() {
+                return Err(tonic::Status::not_found(
+                    "No items found for the given keys",
+                ));
+            }
+            return Err(unavailable(&response.upstream_errors));
+        }
+        // each upstream's items are in order, and stay so for a key
+        response.items.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(response)
+    }
+
+    // Makes the same call to every upstream at once, and returns their
+    // replies in upstream order
+    async fn ask<T, F, Fut>(&self, call: F) -> Vec<(&str, Result<T, tonic::Status>)>
+    where
+        F: Fn(DatastoreClient<Channel>) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, tonic::Status>>,
+    {
+        let calls = self.upstreams.iter().map(|upstream| {
+            let reply = self.peers.client(upstream).map(&call);
+            async move {
+                let reply = match reply {
+                    Some(reply) => reply.await.map(tonic::Response::into_inner),
+                    None => Err(tonic::Status::unavailable("invalid upstream URL")),
+                };
+                (upstream.as_str(), reply)
+            }
+        });
+        futures::future::join_all(calls).await
+    }
+}
+
+fn upstream_error(upstream: &str, status: tonic::Status) -> UpstreamError {
+    UpstreamError {
+        upstream: upstream.to_string(),
+        message: status.message().to_string(),
+    }
+}
+
+// Nothing was found, but it may be on an upstream that couldn't be asked
+fn unavailable(errors: &[UpstreamError]) -> tonic::Status {
+    let upstreams: Vec<&str> = errors.iter().map(|error| error.upstream.as_str()).collect();
+    tonic::Status::unavailable(format!(
+        "nothing found, and some upstreams are unavailable: {}",
+        upstreams.join(", ")
+    ))
+}
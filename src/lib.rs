@@ -1,2 +1,3 @@
 pub mod datastore;
 pub mod nestedmap;
+pub mod raft;
@@ -0,0 +1,333 @@
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use super::config::*;
+use super::pattern::{AsPattern, Pattern};
+use super::{value, NestedMap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    Sum,
+    Min,
+    Max,
+    Avg,
+    Count,
+    CountByValue,
+}
+
+impl Aggregation {
+    /// What to aggregate each part of a key space with, when it is split
+    /// between datastores, for `merge` to combine. Averages only combine
+    /// exactly from sums and counts.
+    pub fn partial(self) -> Aggregation {
+        match self {
+            Aggregation::Avg => Aggregation::Sum,
+            other => other,
+        }
+    }
+}
+
+/// How matched keys are split into groups before aggregating.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupBy {
+    // Zero-based segment position in the key
+    Position(usize),
+    // Name of a `{capture}` segment in the pattern
+    Capture(String),
+}
+
+/// The aggregate for one group. `group` is empty when no grouping was asked
+/// for. `count` is the number of values that contributed, and `counts` is
+/// only filled in for `CountByValue`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregateResult {
+    pub group: String,
+    pub value: f64,
+    pub count: u64,
+    pub counts: BTreeMap<String, u64>,
+}
+
+impl AggregateResult {
+    fn new(group: String) -> Self {
+        AggregateResult {
+            group,
+            value: 0.0,
+            count: 0,
+            counts: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, aggregation: Aggregation, value: &Value) {
+        match aggregation {
+            Aggregation::Count => {}
+            Aggregation::CountByValue => {
+                let label = match value {
+                    Value::String(text) => text.clone(),
+                    other => other.to_string(),
+                };
+                *self.counts.entry(label).or_default() += 1;
+            }
+            _ => {
+                // the caller only passes numbers for numeric aggregations
+                let number = value.as_f64().unwrap_or_default();
+                self.value = match aggregation {
+                    _ if self.count == 0 => number,
+                    Aggregation::Min => self.value.min(number),
+                    Aggregation::Max => self.value.max(number),
+                    _ => self.value + number,
+                };
+            }
+        }
+        self.count += 1;
+    }
+
+    fn finish(mut self, aggregation: Aggregation) -> Self {
+        match aggregation {
+            Aggregation::Avg => self.value /= self.count as f64,
+            Aggregation::Count | Aggregation::CountByValue => self.value = self.count as f64,
+            _ => {}
+        }
+        self
+    }
+}
+
+impl NestedMap {
+    /// Aggregates the latest value of every key matching the pattern. Sum,
+    /// min, max and avg only consider values that decode to numbers; the
+    /// counts consider any decodable value. Groups are ordered by name and
+    /// only appear if at least one value contributed to them.
+    pub fn aggregate<P: AsPattern + ?Sized>(
+        &self,
+        pattern: &P,
+        aggregation: Aggregation,
+        group_by: Option<&GroupBy>,
+    ) -> Vec<AggregateResult> {
+        Self::aggregate_maps([self], pattern, aggregation, group_by)
+    }
+
+    /// Aggregates over several maps as if they were one, e.g. the shards of
+    /// a datastore.
+    pub fn aggregate_maps<'a, P: AsPattern + ?Sized>(
+        maps: impl IntoIterator<Item = &'a NestedMap>,
+        pattern: &P,
+        aggregation: Aggregation,
+        group_by: Option<&GroupBy>,
+    ) -> Vec<AggregateResult> {
+        let Ok(pattern) = pattern.as_pattern() else {
+            return Vec::new();
+        };
+        let mut groups: BTreeMap<String, AggregateResult> = BTreeMap::new();
+
+        for map in maps {
+            map.visit_matching(pattern.as_ref(), None, |items| {
+                let Some(item) = items.front() else {
+                    return;
+                };
+                let Some((value, _)) = value::decode(&item.value) else {
+                    return;
+                };
+                let numeric =
+                    !matches!(aggregation, Aggregation::Count | Aggregation::CountByValue);
+                if numeric && !value.is_number() {
+                    return;
+                }
+
+                let group = group_name(&pattern, &item.key, group_by);
+                groups
+                    .entry(group.clone())
+                    .or_insert_with(|| AggregateResult::new(group))
+                    .add(aggregation, &value);
+            });
+        }
+
+        groups
+            .into_values()
+            .map(|result| result.finish(aggregation))
+            .collect()
+    }
+}
+
+/// Combines the results of an aggregation over disjoint sets of keys, each
+/// made with `Aggregation::partial`, into the aggregation's over all of
+/// them.
+pub fn merge(
+    aggregation: Aggregation,
+    parts: impl IntoIterator<Item = AggregateResult>,
+) -> Vec<AggregateResult> {
+    let mut groups: BTreeMap<String, AggregateResult> = BTreeMap::new();
+    for part in parts {
+        let group = match groups.entry(part.group.clone()) {
+            Entry::Vacant(entry) => {
+                entry.insert(part);
+                continue;
+            }
+            Entry::Occupied(entry) => entry.into_mut(),
+        };
+        group.value = match aggregation {
+            Aggregation::Min => group.value.min(part.value),
+            Aggregation::Max => group.value.max(part.value),
+            _ => group.value + part.value,
+        };
+        group.count += part.count;
+        for (label, count) in part.counts {
+            *group.counts.entry(label).or_default() += count;
+        }
+    }
+
+    groups
+        .into_values()
+        .map(|mut result| {
+            if aggregation == Aggregation::Avg {
+                result.value /= result.count as f64;
+            }
+            result
+        })
+        .collect()
+}
+
+fn group_name(pattern: &Pattern, key: &str, group_by: Option<&GroupBy>) -> String {
+    match group_by {
+        Some(GroupBy::Position(position)) => key
+            .split(DELIMITER)
+            .nth(*position)
+            .unwrap_or_default()
+            .to_string(),
+        Some(GroupBy::Capture(name)) => pattern.captures(key).remove(name).unwrap_or_default(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nestedmap::test_helpers::*;
+
+    fn seed_counters(nm: &mut NestedMap) {
+        let counters = [
+            ("esr1a", "ethernet1", b"100".as_slice()),
+            ("esr1a", "ethernet2", b"50"),
+            ("esr1b", "ethernet1", b"7"),
+            ("esr1b", "ethernet2", b"n/a"),
+        ];
+        for (device, ifname, value) in counters {
+            let key = format!("interface.lab1.p01.rk01.{}.{}.in-octets", device, ifname);
+            nm.set(&key, &create_item(&key, value), None);
+        }
+    }
+
+    fn values(results: &[AggregateResult]) -> Vec<(&str, f64, u64)> {
+        results
+            .iter()
+            .map(|r| (r.group.as_str(), r.value, r.count))
+            .collect()
+    }
+
+    #[test]
+    fn test_numeric_aggregations() {
+        let mut nm = NestedMap::new(1);
+        seed_counters(&mut nm);
+        let pattern = "interface.*.*.*.*.*.in-octets";
+
+        let cases = [
+            (Aggregation::Sum, 157.0),
+            (Aggregation::Min, 7.0),
+            (Aggregation::Max, 100.0),
+            (Aggregation::Avg, 157.0 / 3.0),
+        ];
+        for (aggregation, expected) in cases {
+            let results = nm.aggregate(pattern, aggregation, None);
+            assert_eq!(
+                values(&results),
+                vec![("", expected, 3)],
+                "{:?}",
+                aggregation
+            );
+        }
+
+        // non-numeric values still count
+        let results = nm.aggregate(pattern, Aggregation::Count, None);
+        assert_eq!(values(&results), vec![("", 4.0, 4)]);
+    }
+
+    #[test]
+    fn test_grouped_aggregations() {
+        let mut nm = NestedMap::new(1);
+        seed_counters(&mut nm);
+
+        let results = nm.aggregate(
+            "interface.*.*.*.*.*.in-octets",
+            Aggregation::Sum,
+            Some(&GroupBy::Position(4)),
+        );
+        assert_eq!(
+            values(&results),
+            vec![("esr1a", 150.0, 2), ("esr1b", 7.0, 1)]
+        );
+
+        let results = nm.aggregate(
+            "interface.*.*.*.*.{ifname}.in-octets",
+            Aggregation::Max,
+            Some(&GroupBy::Capture("ifname".to_string())),
+        );
+        assert_eq!(
+            values(&results),
+            vec![("ethernet1", 100.0, 2), ("ethernet2", 50.0, 1)]
+        );
+    }
+
+    #[test]
+    fn test_count_by_value() {
+        let mut nm = NestedMap::new(1);
+        for (device, status) in [("esr1a", "up"), ("esr1b", "down"), ("esr1c", "up")] {
+            let key = format!("interface.lab1.{}.ethernet1.oper-status", device);
+            nm.set(&key, &create_item(&key, status.as_bytes()), None);
+        }
+
+        let results = nm.aggregate("interface.>", Aggregation::CountByValue, None);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].count, 3);
+        assert_eq!(results[0].counts["up"], 2);
+        assert_eq!(results[0].counts["down"], 1);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut first = NestedMap::new(1);
+        seed_counters(&mut first);
+        let mut second = NestedMap::new(1);
+        for (device, value) in [("esr1b", b"3".as_slice()), ("esr1c", b"40")] {
+            let key = format!("interface.lab2.p01.rk01.{}.ethernet1.in-octets", device);
+            second.set(&key, &create_item(&key, value), None);
+        }
+        let pattern = "interface.*.*.*.*.*.in-octets";
+        let group_by = Some(GroupBy::Position(4));
+
+        // the same as aggregating both maps at once
+        let aggregations = [
+            Aggregation::Sum,
+            Aggregation::Min,
+            Aggregation::Max,
+            Aggregation::Avg,
+            Aggregation::Count,
+            Aggregation::CountByValue,
+        ];
+        for aggregation in aggregations {
+            let parts = [&first, &second]
+                .into_iter()
+                .flat_map(|nm| nm.aggregate(pattern, aggregation.partial(), group_by.as_ref()));
+            assert_eq!(
+                merge(aggregation, parts),
+                NestedMap::aggregate_maps(
+                    [&first, &second],
+                    pattern,
+                    aggregation,
+                    group_by.as_ref()
+                ),
+                "{:?}",
+                aggregation
+            );
+        }
+    }
+}
@@ -2,3 +2,4 @@ pub const VALUE_KEY: &str = "__value";
 pub const DELIMITER: &str = ".";
 pub const WILDCARD: &str = "*";
 pub const COLLECTOR: &str = ">";
+pub const REGEX_PREFIX: &str = "~re:";
@@ -1,5 +1,8 @@
 use super::config::*;
+use super::pattern::AsPattern;
 use super::*;
+use im::OrdMap;
+use std::sync::Arc;
 
 impl NestedMap {
     pub fn delete(&mut self, keys: &str) -> bool {
@@ -9,13 +12,23 @@ impl NestedMap {
         for (i, key) in keys.iter().enumerate() {
             if i == keys.len() - 1 {
                 // Last key, attempt to delete
-                return current_map.remove(*key).is_some();
+                let deleted = current_map.remove(*key).is_some();
+                if deleted {
+                    let prefix = keys.join(DELIMITER);
+                    if let Some(index) = &mut self.index {
+                        index.remove_prefix(&prefix);
+                    }
+                    for index in &mut self.value_indexes {
+                        index.remove_prefix(&prefix);
+                    }
+                }
+                return deleted;
             }
 
             // Not the last key, dive deeper
             match current_map.get_mut(*key) {
                 Some(NestedValue::Map(map)) => {
-                    current_map = &mut map.data;
+                    current_map = &mut Arc::make_mut(map).data;
                 }
                 _ => return false, // Next key is not a map, or key not found
             }
@@ -24,6 +37,35 @@ impl NestedMap {
         false
     }
 
+    /// Removes the values of every key matching the pattern, returning the
+    /// keys that were deleted.
+    pub fn delete_matching<P: AsPattern + ?Sized>(&mut self, pattern: &P) -> Vec<String> {
+        let mut keys = Vec::new();
+        self.visit_matching(pattern, None, |items| {
+            if let Some(item) = items.front() {
+                keys.push(item.key.clone());
+            }
+        });
+
+        keys.retain(|key| self.delete_values(key));
+        keys
+    }
+
+    /// Removes the values of exactly this key, leaving the keys below it.
+    pub fn delete_values(&mut self, keys: &str) -> bool {
+        let segments: Vec<&str> = keys.split(DELIMITER).collect();
+        let deleted = remove_values(&mut self.data, &segments);
+        if deleted {
+            if let Some(index) = &mut self.index {
+                index.remove(keys);
+            }
+            for index in &mut self.value_indexes {
+                index.update(keys, None);
+            }
+        }
+        deleted
+    }
+
     pub fn delete_at_index(&mut self, keys: &str, index: usize) -> bool {
         let keys: Vec<&str> = keys.split(DELIMITER).collect();
         let mut current_map = &mut self.data;
@@ -32,13 +74,21 @@ impl NestedMap {
             if i == keys.len() - 1 {
                 // At the last key, access the nested items via VALUE_KEY
                 if let Some(NestedValue::Map(final_map)) = current_map.get_mut(*key) {
+                    let final_map = Arc::make_mut(final_map);
                     if let Some(NestedValue::Items(items)) = final_map.data.get_mut(VALUE_KEY) {
                         if index < items.len() {
+                            let items = Arc::make_mut(items);
                             items.remove(index);
+                            for value_index in &mut self.value_indexes {
+                                value_index.update(&keys.join(DELIMITER), items.front());
+                            }
 
                             // Optionally remove the VALUE_KEY if no items left
                             if items.is_empty() {
                                 final_map.data.remove(VALUE_KEY);
+                                if let Some(index) = &mut self.index {
+                                    index.remove(&keys.join(DELIMITER));
+                                }
                             }
 
                             return true;
@@ -52,7 +102,7 @@ impl NestedMap {
 
             // Navigate deeper into the map
             if let Some(NestedValue::Map(map)) = current_map.get_mut(*key) {
-                current_map = &mut map.data;
+                current_map = &mut Arc::make_mut(map).data;
             } else {
                 return false;
             }
@@ -69,18 +119,25 @@ impl NestedMap {
             if i == keys.len() - 1 {
                 // At the last key, access the nested items via VALUE_KEY
                 if let Some(NestedValue::Map(final_map)) = current_map.get_mut(*key) {
+                    let final_map = Arc::make_mut(final_map);
                     if let Some(NestedValue::Items(items)) = final_map.data.get_mut(VALUE_KEY) {
-                        for (idx, item) in items.iter().enumerate() {
-                            if item.id == id {
-                                items.remove(idx);
+                        // only copied if the id is there to remove
+                        if let Some(idx) = items.iter().position(|item| item.id == id) {
+                            let items = Arc::make_mut(items);
+                            items.remove(idx);
+                            for index in &mut self.value_indexes {
+                                index.update(&keys.join(DELIMITER), items.front());
+                            }
 
-                                // Optionally remove the VALUE_KEY if no items left
-                                if items.is_empty() {
-                                    final_map.data.remove(VALUE_KEY);
+                            // Optionally remove the VALUE_KEY if no items left
+                            if items.is_empty() {
+                                final_map.data.remove(VALUE_KEY);
+                                if let Some(index) = &mut self.index {
+                                    index.remove(&keys.join(DELIMITER));
                                 }
-
-                                return true;
                             }
+
+                            return true;
                         }
                     }
                 }
@@ -88,7 +145,7 @@ impl NestedMap {
 
             // Navigate deeper into the map
             if let Some(NestedValue::Map(map)) = current_map.get_mut(*key) {
-                current_map = &mut map.data;
+                current_map = &mut Arc::make_mut(map).data;
             } else {
                 return false;
             }
@@ -98,9 +155,29 @@ impl NestedMap {
     }
 }
 
+// Removes the values stored under the key, and any maps left empty on the
+// way back up so that deleted keys don't leave their parents behind
+fn remove_values(data: &mut OrdMap<Arc<str>, NestedValue>, keys: &[&str]) -> bool {
+    let Some((key, rest)) = keys.split_first() else {
+        return data.remove(VALUE_KEY).is_some();
+    };
+    let Some(NestedValue::Map(map)) = data.get_mut(*key) else {
+        return false;
+    };
+
+    let map = Arc::make_mut(map);
+    let deleted = remove_values(&mut map.data, rest);
+    if deleted && map.data.is_empty() {
+        data.remove(*key);
+    }
+    deleted
+}
+
+#[cfg(test)]
 mod tests {
     #[allow(unused_imports)]
     use self::options::{GetOptions, SetOptions};
+    use self::pattern::Pattern;
 
     use super::*;
     use crate::nestedmap::test_helpers::*;
@@ -248,6 +325,38 @@ mod tests {
         assert_eq!(items.len(), 0);
     }
 
+    #[test]
+    fn test_delete_matching() {
+        let mut nm = NestedMap::new(1);
+        for key in ["a.b.c", "a.b.d", "a.b.d.e", "a.x.c", "b.b.c"] {
+            nm.set(key, &create_item(key, b"value"), None);
+        }
+
+        let pattern = Pattern::parse("a.*.c").unwrap();
+        assert_eq!(nm.delete_matching(&pattern).len(), 2);
+        assert!(nm.get("a.b.c").is_none());
+        assert!(nm.get("a.x.c").is_none());
+        assert!(nm.get("b.b.c").is_some());
+
+        // children of a deleted value are left alone
+        assert_eq!(nm.delete_matching("a.b.d"), vec!["a.b.d"]);
+        assert!(nm.get("a.b.d.e").is_some());
+
+        assert!(nm.delete_matching("a.b.d").is_empty());
+        assert!(nm.delete_matching("a.>.c").is_empty());
+
+        // maps left empty are pruned, those still holding keys are kept
+        let NestedValue::Map(a) = &nm.data["a"] else {
+            panic!("expected a map under a");
+        };
+        assert!(!a.data.contains_key("x"));
+        assert!(a.data.contains_key("b"));
+
+        nm.delete_matching("a.b.d.e");
+        assert!(!nm.data.contains_key("a"));
+        assert!(nm.data.contains_key("b"));
+    }
+
     fn delete_tests(test_cases: Vec<TestCase>) {
         for test in test_cases {
             let mut nm = NestedMap::new(test.max_history);
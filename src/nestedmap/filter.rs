@@ -0,0 +1,538 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use serde_json::Value;
+
+use super::value;
+
+const VALUE_ROOT: &str = "value";
+
+/// A predicate over decoded item values, such as
+/// `value.state == "established" && value.mtu > 1500`.
+///
+/// Paths start at `value` and step into objects by field name or into arrays
+/// by `[index]`. Comparisons against a missing field or a value of a
+/// different type are false, whatever the operator. Values that cannot be
+/// decoded never match.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterError(String);
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid filter: {}", self.0)
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(FieldPath, Op, Value),
+}
+
+/// A path into a decoded value, such as `value.counters.in-octets` or
+/// `value.peers[0]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldPath(Vec<PathSegment>);
+
+#[derive(Debug, Clone, PartialEq)]
+enum PathSegment {
+    Field(String),
+    Index(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl Filter {
+    pub fn parse(filter: &str) -> Result<Filter, FilterError> {
+        let tokens = tokenize(filter)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.parse_or()?;
+
+        if let Some(token) = parser.peek() {
+            return Err(FilterError(format!("unexpected {:?}", token)));
+        }
+
+        Ok(Filter {
+            source: filter.to_string(),
+            expr,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Decodes a stored value and evaluates the filter against it.
+    pub fn matches(&self, bytes: &[u8]) -> bool {
+        match value::decode(bytes) {
+            Some((value, _)) => self.matches_value(&value),
+            None => false,
+        }
+    }
+
+    pub fn matches_value(&self, value: &Value) -> bool {
+        self.expr.eval(value)
+    }
+
+    /// The `path == literal` comparisons that every matching value has to
+    /// satisfy, i.e. those not under an `||` or `!`.
+    pub fn equalities(&self) -> Vec<(&FieldPath, &Value)> {
+        let mut equalities = Vec::new();
+        self.expr.equalities(&mut equalities);
+        equalities
+    }
+}
+
+impl FieldPath {
+    pub fn parse(path: &str) -> Result<FieldPath, FilterError> {
+        let tokens = tokenize(path)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let path = parser.parse_path()?;
+
+        if let Some(token) = parser.peek() {
+            return Err(FilterError(format!("unexpected {:?}", token)));
+        }
+        Ok(path)
+    }
+
+    pub fn resolve<'a>(&self, value: &'a Value) -> Option<&'a Value> {
+        self.0
+            .iter()
+            .try_fold(value, |value, segment| match segment {
+                PathSegment::Field(name) => value.get(name),
+                PathSegment::Index(index) => value.get(index),
+            })
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl std::str::FromStr for Filter {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Filter::parse(s)
+    }
+}
+
+impl Expr {
+    fn eval(&self, value: &Value) -> bool {
+        match self {
+            Expr::And(a, b) => a.eval(value) && b.eval(value),
+            Expr::Or(a, b) => a.eval(value) || b.eval(value),
+            Expr::Not(a) => !a.eval(value),
+            Expr::Compare(path, op, expected) => match path.resolve(value) {
+                Some(actual) => compare(actual, *op, expected),
+                None => false,
+            },
+        }
+    }
+
+    fn equalities<'a>(&'a self, equalities: &mut Vec<(&'a FieldPath, &'a Value)>) {
+        match self {
+            Expr::And(a, b) => {
+                a.equalities(equalities);
+                b.equalities(equalities);
+            }
+            Expr::Compare(path, Op::Eq, expected) => equalities.push((path, expected)),
+            _ => {}
+        }
+    }
+}
+
+fn compare(actual: &Value, op: Op, expected: &Value) -> bool {
+    let ordering = match (actual, expected) {
+        (Value::Number(a), Value::Number(b)) => compare_numbers(a, b),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) if matches!(op, Op::Eq | Op::Ne) => Some(a.cmp(b)),
+        (Value::Null, Value::Null) if matches!(op, Op::Eq | Op::Ne) => Some(Ordering::Equal),
+        _ => None,
+    };
+
+    match ordering {
+        Some(ordering) => match op {
+            Op::Eq => ordering == Ordering::Equal,
+            Op::Ne => ordering != Ordering::Equal,
+            Op::Gt => ordering == Ordering::Greater,
+            Op::Ge => ordering != Ordering::Less,
+            Op::Lt => ordering == Ordering::Less,
+            Op::Le => ordering != Ordering::Greater,
+        },
+        None => false,
+    }
+}
+
+// Integers are compared exactly, since counters and ids past 2^53 don't
+// survive the trip through f64; anything else is compared as floats
+fn compare_numbers(a: &serde_json::Number, b: &serde_json::Number) -> Option<Ordering> {
+    if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+        return Some(a.cmp(&b));
+    }
+    if let (Some(a), Some(b)) = (a.as_u64(), b.as_u64()) {
+        return Some(a.cmp(&b));
+    }
+    // a negative integer against one too large for an i64
+    if a.is_i64() && b.is_u64() {
+        return Some(Ordering::Less);
+    }
+    if a.is_u64() && b.is_i64() {
+        return Some(Ordering::Greater);
+    }
+    a.as_f64()?.partial_cmp(&b.as_f64()?)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Literal(Value),
+    Op(Op),
+    Dot,
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, FilterError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '.' => {
+                chars.next();
+                tokens.push(Token::Dot);
+            }
+            '[' => {
+                chars.next();
+                tokens.push(Token::LBracket);
+            }
+            ']' => {
+                chars.next();
+                tokens.push(Token::RBracket);
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::LParen);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::RParen);
+            }
+            '=' | '!' | '<' | '>' | '&' | '|' => {
+                chars.next();
+                let next = chars.peek().copied();
+                let token = match (c, next) {
+                    ('=', Some('=')) => Token::Op(Op::Eq),
+                    ('!', Some('=')) => Token::Op(Op::Ne),
+                    ('>', Some('=')) => Token::Op(Op::Ge),
+                    ('<', Some('=')) => Token::Op(Op::Le),
+                    ('&', Some('&')) => Token::And,
+                    ('|', Some('|')) => Token::Or,
+                    ('>', _) => {
+                        tokens.push(Token::Op(Op::Gt));
+                        continue;
+                    }
+                    ('<', _) => {
+                        tokens.push(Token::Op(Op::Lt));
+                        continue;
+                    }
+                    ('!', _) => {
+                        tokens.push(Token::Not);
+                        continue;
+                    }
+                    _ => return Err(FilterError(format!("unexpected '{}'", c))),
+                };
+                chars.next();
+                tokens.push(token);
+            }
+            '"' | '\'' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => match chars.next() {
+                            Some(escaped) => text.push(escaped),
+                            None => return Err(FilterError("unterminated string".into())),
+                        },
+                        Some(end) if end == c => break,
+                        Some(other) => text.push(other),
+                        None => return Err(FilterError("unterminated string".into())),
+                    }
+                }
+                tokens.push(Token::Literal(Value::String(text)));
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut number = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E') {
+                        number.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                let value = serde_json::from_str::<serde_json::Number>(&number)
+                    .map_err(|_| FilterError(format!("invalid number '{}'", number)))?;
+                tokens.push(Token::Literal(Value::Number(value)));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut ident = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_alphanumeric() || c == '_' || c == '-' {
+                        ident.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(match ident.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    "true" => Token::Literal(Value::Bool(true)),
+                    "false" => Token::Literal(Value::Bool(false)),
+                    "null" => Token::Literal(Value::Null),
+                    _ => Token::Ident(ident),
+                });
+            }
+            _ => return Err(FilterError(format!("unexpected '{}'", c))),
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), FilterError> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            other => Err(FilterError(format!(
+                "expected {:?}, found {:?}",
+                expected, other
+            ))),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.parse_unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, FilterError> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.next();
+                Ok(Expr::Not(Box::new(self.parse_unary()?)))
+            }
+            Some(Token::LParen) => {
+                self.next();
+                let expr = self.parse_or()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            _ => self.parse_comparison(),
+        }
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, FilterError> {
+        let path = self.parse_path()?;
+
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            other => {
+                return Err(FilterError(format!(
+                    "expected comparison, found {:?}",
+                    other
+                )))
+            }
+        };
+
+        match self.next() {
+            Some(Token::Literal(value)) => Ok(Expr::Compare(path, op, value)),
+            other => Err(FilterError(format!("expected literal, found {:?}", other))),
+        }
+    }
+
+    fn parse_path(&mut self) -> Result<FieldPath, FilterError> {
+        match self.next() {
+            Some(Token::Ident(root)) if root == VALUE_ROOT => {}
+            other => {
+                return Err(FilterError(format!(
+                    "paths must start with '{}', found {:?}",
+                    VALUE_ROOT, other
+                )))
+            }
+        }
+
+        let mut path = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::Dot) => {
+                    self.next();
+                    match self.next() {
+                        Some(Token::Ident(field)) => path.push(PathSegment::Field(field)),
+                        other => {
+                            return Err(FilterError(format!(
+                                "expected field name, found {:?}",
+                                other
+                            )))
+                        }
+                    }
+                }
+                Some(Token::LBracket) => {
+                    self.next();
+                    match self.next() {
+                        Some(Token::Literal(Value::Number(n))) if n.is_u64() => {
+                            path.push(PathSegment::Index(n.as_u64().unwrap() as usize))
+                        }
+                        other => {
+                            return Err(FilterError(format!(
+                                "expected array index, found {:?}",
+                                other
+                            )))
+                        }
+                    }
+                    self.expect(Token::RBracket)?;
+                }
+                _ => return Ok(FieldPath(path)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn matches(filter: &str, value: Value) -> bool {
+        Filter::parse(filter).unwrap().matches_value(&value)
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let peer = json!({"state": "idle", "mtu": 9000, "up": false, "tags": ["core", "lab1"]});
+
+        assert!(matches(r#"value.state == "idle""#, peer.clone()));
+        assert!(matches("value.state != 'established'", peer.clone()));
+        assert!(matches("value.mtu > 1500", peer.clone()));
+        assert!(matches("value.mtu >= 9000", peer.clone()));
+        assert!(!matches("value.mtu < 9000", peer.clone()));
+        assert!(matches("value.mtu <= 9000.0", peer.clone()));
+        assert!(matches("value.up == false", peer.clone()));
+        assert!(matches("value.tags[1] == 'lab1'", peer.clone()));
+        assert!(matches("value == 'up'", json!("up")));
+
+        // missing fields and mismatched types never match
+        assert!(!matches("value.missing != 'idle'", peer.clone()));
+        assert!(!matches("value.state > 5", peer.clone()));
+        assert!(!matches("value.up > false", peer));
+
+        // large integers compare exactly rather than as the nearest f64
+        let counters = json!({"in": 9007199254740993u64, "out": u64::MAX, "delta": -1});
+        assert!(!matches("value.in == 9007199254740992", counters.clone()));
+        assert!(matches("value.in > 9007199254740992", counters.clone()));
+        assert!(matches("value.out == 18446744073709551615", counters.clone()));
+        assert!(matches("value.out > 18446744073709551614", counters.clone()));
+        assert!(matches("value.delta < 18446744073709551615", counters.clone()));
+        assert!(matches("value.delta < 0.5", counters));
+    }
+
+    #[test]
+    fn test_boolean_operators() {
+        let peer = json!({"state": "active", "peer-as": 65001});
+
+        assert!(matches(
+            "value.state != 'established' && value.peer-as == 65001",
+            peer.clone()
+        ));
+        assert!(matches(
+            "value.state == 'idle' or value.state == 'active'",
+            peer.clone()
+        ));
+        assert!(matches("!(value.state == 'established')", peer.clone()));
+        assert!(!matches(
+            "not (value.state == 'active' || value.state == 'idle')",
+            peer
+        ));
+    }
+
+    #[test]
+    fn test_matches_encoded_values() {
+        let filter = Filter::parse("value.state == 'established'").unwrap();
+
+        let packed = rmp_serde::to_vec_named(&json!({"state": "established"})).unwrap();
+        assert!(filter.matches(&packed));
+        assert!(filter.matches(br#"{"state":"established"}"#));
+        assert!(!filter.matches(br#"{"state":"idle"}"#));
+        assert!(!filter.matches(&[0xc1]));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Filter::parse("state == 'idle'").is_err());
+        assert!(Filter::parse("value.state =").is_err());
+        assert!(Filter::parse("value.state == 'idle").is_err());
+        assert!(Filter::parse("value.state == 'idle' &&").is_err());
+        assert!(Filter::parse("(value.mtu > 1").is_err());
+        assert!(Filter::parse("value.mtu > 1 value").is_err());
+        assert!(Filter::parse("value.tags[x] == 1").is_err());
+    }
+}
@@ -3,6 +3,12 @@ use super::*;
 
 impl NestedMap {
     pub fn get(&self, keys: &str) -> Option<&Item> {
+        // Return the first item if available
+        self.get_items(keys).and_then(|items| items.front())
+    }
+
+    // Returns the whole history of a key, newest first
+    pub(crate) fn get_items(&self, keys: &str) -> Option<&VecDeque<Item>> {
         let mut current_map = &self.data;
 
         for key in keys.split(DELIMITER) {
@@ -15,13 +21,14 @@ impl NestedMap {
 
         // Try to retrieve items at the VALUE_KEY in the final map
         if let Some(NestedValue::Items(items)) = current_map.get(VALUE_KEY) {
-            items.front() // Return the first item if available
+            Some(items.as_ref())
         } else {
             None
         }
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
     use crate::nestedmap::test_helpers::*;
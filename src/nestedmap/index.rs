@@ -0,0 +1,437 @@
+use im::{HashMap, OrdSet};
+
+use serde_json::Value;
+
+use super::config::*;
+use super::filter::{FieldPath, Filter, FilterError};
+use super::pattern::{Pattern, Segment};
+use super::{value, Item};
+
+/// Maps each (depth, segment) pair to the keys holding a value with that
+/// segment at that depth. Queries that start with wildcards can then jump
+/// straight to the keys sharing their most selective literal segment
+/// instead of walking the whole tree.
+#[derive(Debug, Clone, Default)]
+pub struct SegmentIndex {
+    // Persistent, so that snapshots of the tree share it
+    postings: HashMap<(usize, String), OrdSet<String>>,
+}
+
+impl SegmentIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        for (depth, segment) in key.split(DELIMITER).enumerate() {
+            self.postings
+                .entry((depth, segment.to_string()))
+                .or_default()
+                .insert(key.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        for (depth, segment) in key.split(DELIMITER).enumerate() {
+            let posting = (depth, segment.to_string());
+            if let Some(keys) = self.postings.get_mut(&posting) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.postings.remove(&posting);
+                }
+            }
+        }
+    }
+
+    /// Removes `prefix` and every key below it.
+    pub fn remove_prefix(&mut self, prefix: &str) {
+        let Some(first) = prefix.split(DELIMITER).next() else {
+            return;
+        };
+        let Some(keys) = self.postings.get(&(0, first.to_string())) else {
+            return;
+        };
+
+        let children = format!("{}{}", prefix, DELIMITER);
+        let removed: Vec<String> = keys
+            .iter()
+            .filter(|key| *key == prefix || key.starts_with(&children))
+            .cloned()
+            .collect();
+        for key in removed {
+            self.remove(&key);
+        }
+    }
+
+    /// Returns the keys matching the pattern, in the same order a tree walk
+    /// would visit them, or `None` if the index can't help. Only fixed-length
+    /// patterns that don't start with a literal are planned here; the tree
+    /// walk is already selective for everything else.
+    pub fn candidates(&self, pattern: &Pattern) -> Option<Vec<&str>> {
+        let segments = pattern.segments();
+        if matches!(segments.first(), None | Some(Segment::Literal(_)))
+            || segments
+                .iter()
+                .any(|segment| matches!(segment, Segment::Collector(_)))
+        {
+            return None;
+        }
+
+        // Start from the literal with the fewest keys
+        let (_, keys) = segments
+            .iter()
+            .enumerate()
+            .filter_map(|(depth, segment)| match segment {
+                Segment::Literal(literal) => {
+                    let keys = self.postings.get(&(depth, literal.clone()));
+                    Some((keys.map_or(0, OrdSet::len), keys))
+                }
+                _ => None,
+            })
+            .min_by_key(|(len, _)| *len)?;
+
+        Some(matching(keys, pattern))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.postings.is_empty()
+    }
+}
+
+/// Maps the values of one field to the keys whose latest value has them, for
+/// the keys matching a pattern. Filtered queries such as
+/// `value.state == "idle"` can then jump straight to the matching keys
+/// instead of decoding every value.
+#[derive(Debug, Clone)]
+pub struct ValueIndex {
+    pattern: Pattern,
+    field: String,
+    path: FieldPath,
+    postings: HashMap<String, OrdSet<String>>,
+    // The posting each key is currently listed under
+    indexed: HashMap<String, String>,
+}
+
+impl ValueIndex {
+    /// Indexes `field`, a filter path such as `value.state`, for keys
+    /// matching `pattern`.
+    pub fn new(pattern: Pattern, field: &str) -> Result<Self, FilterError> {
+        Ok(ValueIndex {
+            pattern,
+            field: field.to_string(),
+            path: FieldPath::parse(field)?,
+            postings: HashMap::new(),
+            indexed: HashMap::new(),
+        })
+    }
+
+    pub fn pattern(&self) -> &Pattern {
+        &self.pattern
+    }
+
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+
+    /// The number of keys currently indexed.
+    pub fn len(&self) -> usize {
+        self.indexed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indexed.is_empty()
+    }
+
+    /// Re-indexes a key after its values changed. `latest` is its newest
+    /// item, or `None` once it has no values left.
+    pub fn update(&mut self, key: &str, latest: Option<&Item>) {
+        if !self.pattern.matches(key) {
+            return;
+        }
+
+        let path = &self.path;
+        let listed = latest
+            .and_then(|item| value::decode(&item.value))
+            .and_then(|(value, _)| path.resolve(&value).and_then(posting));
+        if self.indexed.get(key) == listed.as_ref() {
+            return;
+        }
+
+        self.unlist(key);
+        if let Some(listed) = listed {
+            self.postings
+                .entry(listed.clone())
+                .or_default()
+                .insert(key.to_string());
+            self.indexed.insert(key.to_string(), listed);
+        }
+    }
+
+    /// Removes `prefix` and every key below it.
+    pub fn remove_prefix(&mut self, prefix: &str) {
+        let children = format!("{}{}", prefix, DELIMITER);
+        let removed: Vec<String> = self
+            .indexed
+            .keys()
+            .filter(|key| *key == prefix || key.starts_with(&children))
+            .cloned()
+            .collect();
+        for key in removed {
+            self.unlist(&key);
+        }
+    }
+
+    /// Returns the keys matching the pattern whose latest value can satisfy
+    /// the filter, in the same order a tree walk would visit them. Returns
+    /// `None` if the index doesn't cover the pattern or the filter doesn't
+    /// require an exact value for the indexed field.
+    pub fn candidates(&self, pattern: &Pattern, filter: &Filter) -> Option<Vec<&str>> {
+        if !self.pattern.covers(pattern) {
+            return None;
+        }
+
+        let (_, expected) = filter
+            .equalities()
+            .into_iter()
+            .find(|(path, _)| **path == self.path)?;
+        let keys = posting(expected).and_then(|listed| self.postings.get(&listed));
+        Some(matching(keys, pattern))
+    }
+
+    fn unlist(&mut self, key: &str) {
+        if let Some(listed) = self.indexed.remove(key) {
+            if let Some(keys) = self.postings.get_mut(&listed) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.postings.remove(&listed);
+                }
+            }
+        }
+    }
+}
+
+// Values that compare equal in a filter share a posting. Objects and arrays
+// never compare equal to a literal, so they aren't indexed.
+fn posting(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(format!("s:{}", text)),
+        Value::Number(number) => number
+            .as_f64()
+            .map(|n| format!("n:{}", if n == 0.0 { 0.0 } else { n })),
+        Value::Bool(b) => Some(format!("b:{}", b)),
+        Value::Null => Some("null".to_string()),
+        _ => None,
+    }
+}
+
+// Keeps the posted keys matching the pattern, ordered segment by segment
+fn matching<'a>(keys: Option<&'a OrdSet<String>>, pattern: &Pattern) -> Vec<&'a str> {
+    let mut keys: Vec<&str> = keys
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+        .filter(|key| pattern.matches(key))
+        .collect();
+    keys.sort_by(|a, b| a.split(DELIMITER).cmp(b.split(DELIMITER)));
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::options::{GetOptions, SetOptions};
+    use super::super::NestedMap;
+    use super::*;
+    use crate::nestedmap::test_helpers::*;
+
+    fn seed(nm: &mut NestedMap) {
+        for device in ["esr1a", "esr1b", "esr1c"] {
+            for ifname in ["ethernet1", "ethernet1-1", "ethernet2"] {
+                for leaf in ["oper-status", "in-octets"] {
+                    let key = format!("interface.lab1.{}.{}.{}", device, ifname, leaf);
+                    nm.set(&key, &create_item(&key, b"up"), None);
+                }
+            }
+        }
+        nm.set("oper-status", &create_item("oper-status", b"top"), None);
+        nm.set(
+            "bgp.lab1.esr1a.oper-status",
+            &create_item("bgp.lab1.esr1a.oper-status", b"idle"),
+            None,
+        );
+    }
+
+    fn keys(nm: &NestedMap, pattern: &str) -> Vec<String> {
+        nm.query(pattern, None)
+            .into_iter()
+            .map(|item| item.key)
+            .collect()
+    }
+
+    fn filtered_keys(nm: &NestedMap, pattern: &str, filter: &str) -> Vec<String> {
+        let options = GetOptions::new().filter(Filter::parse(filter).unwrap());
+        nm.query(pattern, Some(options))
+            .into_iter()
+            .map(|item| item.key)
+            .collect()
+    }
+
+    fn plan<'a>(index: &'a SegmentIndex, pattern: &str) -> Option<Vec<&'a str>> {
+        index.candidates(&Pattern::parse(pattern).unwrap())
+    }
+
+    #[test]
+    fn test_candidates() {
+        let mut index = SegmentIndex::new();
+        for key in ["a.b.c", "a.x.c", "b.b.d", "a.b", "a.b.c.d"] {
+            index.insert(key);
+        }
+
+        assert_eq!(plan(&index, "*.b.c"), Some(vec!["a.b.c"]));
+        assert_eq!(plan(&index, "*.*.c"), Some(vec!["a.b.c", "a.x.c"]));
+        assert_eq!(plan(&index, "*.*.z"), Some(vec![]));
+        assert_eq!(plan(&index, "a.*.c"), None);
+        assert_eq!(plan(&index, "*.b.>"), None);
+        assert_eq!(plan(&index, "*.*"), None);
+
+        index.remove_prefix("a.b");
+        assert_eq!(plan(&index, "*.*.c"), Some(vec!["a.x.c"]));
+        assert_eq!(plan(&index, "*.b"), Some(vec![]));
+        assert_eq!(plan(&index, "*.b.d"), Some(vec!["b.b.d"]));
+
+        index.remove("a.x.c");
+        index.remove("b.b.d");
+        assert!(index.is_empty());
+    }
+
+    #[test]
+    fn test_indexed_queries_match_tree_walk() {
+        let mut plain = NestedMap::new(2);
+        let mut indexed = NestedMap::new(2).with_segment_index();
+        seed(&mut plain);
+        seed(&mut indexed);
+
+        let patterns = [
+            "*.*.*.*.oper-status",
+            "*.lab1.*.ethernet1.*",
+            "*.*.esr1b.{ifname}.in-octets",
+            "*.*.*.ethernet1*.oper-status",
+            "*",
+            "*.*.*.oper-status",
+            "*.nothing.*",
+        ];
+        let check = |plain: &NestedMap, indexed: &NestedMap| {
+            for pattern in patterns {
+                assert_eq!(keys(indexed, pattern), keys(plain, pattern), "{}", pattern);
+            }
+        };
+        check(&plain, &indexed);
+
+        for nm in [&mut plain, &mut indexed] {
+            nm.delete("interface.lab1.esr1a");
+            nm.delete_matching("interface.*.esr1b.ethernet2.*");
+            nm.delete_by_id("oper-status", 1);
+            nm.delete_at_index("bgp.lab1.esr1a.oper-status", 0);
+
+            // overwriting and keeping history doesn't duplicate keys
+            let key = "interface.lab1.esr1c.ethernet1.oper-status";
+            nm.set(key, &create_item(key, b"down"), None);
+            nm.set(
+                key,
+                &create_item(key, b"up"),
+                Some(SetOptions::new().preserve_history(true)),
+            );
+        }
+        check(&plain, &indexed);
+
+        assert_eq!(
+            keys(&indexed, "*.*.*.*.oper-status"),
+            vec![
+                "interface.lab1.esr1b.ethernet1-1.oper-status",
+                "interface.lab1.esr1b.ethernet1.oper-status",
+                "interface.lab1.esr1c.ethernet1-1.oper-status",
+                "interface.lab1.esr1c.ethernet1.oper-status",
+                "interface.lab1.esr1c.ethernet2.oper-status",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_value_index_stays_consistent() {
+        let mut plain = NestedMap::new(2);
+        let mut indexed = NestedMap::new(2);
+        let history = Some(SetOptions::new().preserve_history(true));
+
+        let state = |nm: &mut NestedMap, peer: &str, state: &str, id: i64| {
+            let key = format!("bgp.neighbor.{}.session", peer);
+            let mut item = create_item(&key, format!(r#"{{"state":"{}"}}"#, state).as_bytes());
+            item.id = id;
+            nm.set(&key, &item, history.clone());
+        };
+        for nm in [&mut plain, &mut indexed] {
+            state(nm, "peer1", "idle", 1);
+            state(nm, "peer2", "established", 2);
+        }
+        // keys set before the index was added are indexed too
+        let pattern = Pattern::parse("bgp.neighbor.>").unwrap();
+        indexed.add_value_index(ValueIndex::new(pattern, "value.state").unwrap());
+        assert_eq!(indexed.value_indexes()[0].len(), 2);
+
+        let checks = [
+            ("bgp.neighbor.*.session", r#"value.state == "idle""#),
+            (
+                "bgp.neighbor.{peer}.session",
+                r#"value.state == "idle" && value.state != "down""#,
+            ),
+            ("bgp.neighbor.*.session", r#"value.state == "established""#),
+        ];
+        let check = |plain: &NestedMap, indexed: &NestedMap| {
+            for (pattern, filter) in checks {
+                assert_eq!(
+                    filtered_keys(indexed, pattern, filter),
+                    filtered_keys(plain, pattern, filter),
+                    "{} {}",
+                    pattern,
+                    filter
+                );
+            }
+        };
+        check(&plain, &indexed);
+
+        let planned = |nm: &NestedMap, filter: &str| {
+            nm.value_indexes()[0]
+                .candidates(
+                    &Pattern::parse("bgp.neighbor.*.session").unwrap(),
+                    &Filter::parse(filter).unwrap(),
+                )
+                .map(|keys| keys.len())
+        };
+        assert_eq!(planned(&indexed, r#"value.state == "idle""#), Some(1));
+        assert_eq!(planned(&indexed, r#"value.state != "idle""#), None);
+        assert_eq!(
+            planned(&indexed, r#"value.state == "idle" || value.up == true"#),
+            None
+        );
+
+        for nm in [&mut plain, &mut indexed] {
+            // overwrite, then evict the idle history entry
+            state(nm, "peer1", "established", 3);
+            state(nm, "peer1", "established", 4);
+            state(nm, "peer3", "idle", 5);
+        }
+        check(&plain, &indexed);
+        assert_eq!(planned(&indexed, r#"value.state == "idle""#), Some(1));
+
+        for nm in [&mut plain, &mut indexed] {
+            // expiring the latest item falls back to the previous one
+            state(nm, "peer2", "idle", 6);
+            nm.delete_by_id("bgp.neighbor.peer2.session", 6);
+            nm.delete("bgp.neighbor.peer3");
+        }
+        check(&plain, &indexed);
+        assert_eq!(planned(&indexed, r#"value.state == "idle""#), Some(0));
+        assert_eq!(
+            planned(&indexed, r#"value.state == "established""#),
+            Some(2)
+        );
+    }
+}
@@ -1,20 +1,39 @@
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::VecDeque;
+use std::sync::Arc;
 use std::time::SystemTime;
 
+use im::OrdMap;
 use serde::{Deserialize, Serialize};
 
+use index::{SegmentIndex, ValueIndex};
+
+pub mod aggregate;
 pub mod config;
 pub mod delete;
+pub mod filter;
 pub mod get;
+pub mod index;
 pub mod options;
+pub mod pattern;
 pub mod query;
 pub mod set;
 pub mod test_helpers;
+pub mod value;
 
-#[derive(Debug)]
+/// A tree of values keyed by dotted paths. Subtrees are shared between
+/// clones and only copied when written to, so cloning a whole tree is cheap
+/// and a write to a clone copies just the path to the key it writes: each
+/// level is a persistent map, of which a write copies O(log width) nodes,
+/// and only the written key's item list is copied. That is what lets
+/// readers hold snapshots while writers carry on.
+#[derive(Debug, Clone)]
 pub struct NestedMap {
-    data: BTreeMap<String, NestedValue>,
+    data: OrdMap<Arc<str>, NestedValue>,
     max_history: usize,
+    // Only kept on the root map, and only when enabled
+    index: Option<SegmentIndex>,
+    // Only kept on the root map
+    value_indexes: Vec<ValueIndex>,
 }
 
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
@@ -25,17 +44,19 @@ pub struct Item {
     pub id: i64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum NestedValue {
-    Map(NestedMap),
-    Items(VecDeque<Item>),
+    Map(Arc<NestedMap>),
+    // Shared with snapshots like the maps, so copying a level never copies
+    // its keys' values
+    Items(Arc<VecDeque<Item>>),
 }
 
 // Helper function to get mutable reference to nested map if the variant is Map
 impl NestedValue {
-    pub fn as_map_mut(&mut self) -> &mut BTreeMap<String, NestedValue> {
+    pub fn as_map_mut(&mut self) -> &mut OrdMap<Arc<str>, NestedValue> {
         match self {
-            NestedValue::Map(map) => &mut map.data,
+            NestedValue::Map(map) => &mut Arc::make_mut(map).data,
             _ => panic!("Expected NestedValue to be Map"),
         }
     }
@@ -44,11 +65,36 @@ impl NestedValue {
 impl NestedMap {
     pub fn new(max_history: usize) -> Self {
         NestedMap {
-            data: BTreeMap::new(),
+            data: OrdMap::new(),
             max_history,
+            index: None,
+            value_indexes: Vec::new(),
         }
     }
 
+    /// Maintains a segment index so that queries starting with wildcards
+    /// don't have to walk every node, at the cost of memory proportional to
+    /// the number of keys times their depth. Only affects keys set afterwards.
+    pub fn with_segment_index(mut self) -> Self {
+        self.index = Some(SegmentIndex::new());
+        self
+    }
+
+    /// Starts maintaining a value index, indexing the keys already stored.
+    pub fn add_value_index(&mut self, mut index: ValueIndex) {
+        let pattern = index.pattern().clone();
+        self.visit_matching(&pattern, None, |items| {
+            if let Some(item) = items.front() {
+                index.update(&item.key, Some(item));
+            }
+        });
+        self.value_indexes.push(index);
+    }
+
+    pub fn value_indexes(&self) -> &[ValueIndex] {
+        &self.value_indexes
+    }
+
     pub fn eviction_callback(&mut self, keys: &str, id: i64) {
         let _ = self.delete_by_id(keys, id);
     }
@@ -1,7 +1,33 @@
+use serde::{Deserialize, Serialize};
+
+use super::filter::Filter;
+use super::Item;
+
 #[derive(Debug, Clone)]
 pub struct SetOptions {
     pub preserve_history: bool,
     pub ttl: std::time::Duration,
+    // The write is skipped unless this holds for the key's latest item
+    pub precondition: Option<Precondition>,
+}
+
+/// A condition on a key's latest item that must hold for a write to apply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Precondition {
+    Absent,
+    Present,
+    // The latest item's id must equal this version
+    Version(i64),
+}
+
+impl Precondition {
+    pub fn holds(&self, current: Option<&Item>) -> bool {
+        match self {
+            Precondition::Absent => current.is_none(),
+            Precondition::Present => current.is_some(),
+            Precondition::Version(version) => current.is_some_and(|item| item.id == *version),
+        }
+    }
 }
 
 impl Default for SetOptions {
@@ -15,6 +41,7 @@ impl SetOptions {
         Self {
             preserve_history: false,
             ttl: std::time::Duration::from_secs(3600),
+            precondition: None,
         }
     }
 
@@ -28,10 +55,32 @@ impl SetOptions {
         self.ttl = value;
         self
     }
+
+    pub fn if_absent(mut self) -> Self {
+        self.precondition = Some(Precondition::Absent);
+        self
+    }
+
+    pub fn if_present(mut self) -> Self {
+        self.precondition = Some(Precondition::Present);
+        self
+    }
+
+    pub fn if_version(mut self, version: i64) -> Self {
+        self.precondition = Some(Precondition::Version(version));
+        self
+    }
 }
 
+#[derive(Debug, Clone)]
 pub struct GetOptions {
     pub history_count: usize,
+    // Limits how many levels a `>` collector descends; `None` is unlimited
+    pub max_depth: Option<usize>,
+    // Only returns items whose decoded value matches
+    pub filter: Option<Filter>,
+    // Only returns these fields of structured values; empty returns them whole
+    pub fields: Vec<String>,
 }
 
 impl Default for GetOptions {
@@ -43,12 +92,39 @@ impl Default for GetOptions {
 impl GetOptions {
     // Default constructor
     pub fn new() -> Self {
-        Self { history_count: 1 }
+        Self {
+            history_count: 1,
+            max_depth: None,
+            filter: None,
+            fields: Vec::new(),
+        }
     }
 
-    // Setter method
+    // Setter methods
     pub fn history_count(mut self, count: usize) -> Self {
         self.history_count = count;
         self
     }
+
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn fields(mut self, fields: Vec<String>) -> Self {
+        self.fields = fields;
+        self
+    }
+
+    pub fn matches(&self, item: &Item) -> bool {
+        match &self.filter {
+            Some(filter) => filter.matches(&item.value),
+            None => true,
+        }
+    }
 }
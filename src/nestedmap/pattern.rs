@@ -0,0 +1,789 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use regex::Regex;
+
+use super::config::*;
+
+/// A query pattern that has been split, parsed and validated once so it can
+/// be reused across queries, deletes and subscriptions.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatternError {
+    InvalidSegment(String),
+    CollectorNotLast,
+    DuplicateCapture(String),
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatternError::InvalidSegment(segment) => {
+                write!(f, "invalid pattern segment: {}", segment)
+            }
+            PatternError::CollectorNotLast => {
+                write!(f, "'{}' may only be the last segment", COLLECTOR)
+            }
+            PatternError::DuplicateCapture(name) => {
+                write!(f, "capture '{}' is used more than once", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+impl Pattern {
+    /// Parses a pattern. A regex segment runs to the next `.` unless it is
+    /// quoted between slashes, e.g. `~re:/^et-.+/`, in which case it runs
+    /// to the closing `/` and may contain dots; `\/` stands for a slash.
+    pub fn parse(pattern: &str) -> Result<Pattern, PatternError> {
+        let segments = split_segments(pattern)?
+            .into_iter()
+            .map(|segment| {
+                Segment::parse(segment).ok_or_else(|| PatternError::InvalidSegment(segment.into()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if let Some(pos) = segments
+            .iter()
+            .position(|s| matches!(s, Segment::Collector(_)))
+        {
+            if pos != segments.len() - 1 {
+                return Err(PatternError::CollectorNotLast);
+            }
+        }
+
+        let mut names = std::collections::HashSet::new();
+        for segment in &segments {
+            if let Segment::Capture(name) = segment {
+                if !names.insert(name.as_str()) {
+                    return Err(PatternError::DuplicateCapture(name.clone()));
+                }
+            }
+        }
+
+        Ok(Pattern {
+            source: pattern.to_string(),
+            segments,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn has_captures(&self) -> bool {
+        self.segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Capture(_)))
+    }
+
+    pub fn has_capture(&self, name: &str) -> bool {
+        self.segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Capture(capture) if capture == name))
+    }
+
+    /// Returns the key segments matched by each named capture, e.g. `{device}`.
+    pub fn captures(&self, key: &str) -> BTreeMap<String, String> {
+        self.segments
+            .iter()
+            .zip(key.split(DELIMITER))
+            .filter_map(|(segment, key)| match segment {
+                Segment::Capture(name) => Some((name.clone(), key.to_string())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Reports whether a full key would be returned by a query for this pattern.
+    pub fn matches(&self, key: &str) -> bool {
+        let mut keys = key.split(DELIMITER);
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Collector(max_depth) => {
+                    let depth = keys.count();
+                    return depth > 0 && !matches!(max_depth, Some(max) if depth > *max);
+                }
+                _ => match keys.next() {
+                    Some(key) if segment.is_match(key) => {}
+                    _ => return false,
+                },
+            }
+        }
+
+        keys.next().is_none()
+    }
+
+    /// Reports whether every key matched by `other` is also matched by this
+    /// pattern. Errs on the side of `false` when that's hard to tell, e.g.
+    /// for two different globs.
+    pub fn covers(&self, other: &Pattern) -> bool {
+        let mut theirs = other.segments.iter();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Collector(None) => return theirs.next().is_some(),
+                Segment::Collector(Some(max)) => {
+                    let rest: Vec<&Segment> = theirs.collect();
+                    return (1..=*max).contains(&rest.len())
+                        && !rest
+                            .iter()
+                            .any(|segment| matches!(segment, Segment::Collector(_)));
+                }
+                _ => match theirs.next() {
+                    None | Some(Segment::Collector(_)) => return false,
+                    Some(Segment::Literal(literal)) if segment.is_match(literal) => {}
+                    Some(_) if matches!(segment, Segment::Wildcard | Segment::Capture(_)) => {}
+                    Some(_) => return false,
+                },
+            }
+        }
+
+        theirs.next().is_none()
+    }
+
+    /// Reports whether some key could be matched by both patterns. Errs on
+    /// the side of `true` when that's hard to tell, e.g. for two globs.
+    pub fn overlaps(&self, other: &Pattern) -> bool {
+        let mut ours = self.segments.iter();
+        let mut theirs = other.segments.iter();
+
+        loop {
+            match (ours.next(), theirs.next()) {
+                (None, None) => return true,
+                // a collector needs at least one more segment
+                (Some(Segment::Collector(_)), next) | (next, Some(Segment::Collector(_))) => {
+                    return next.is_some()
+                }
+                (None, Some(_)) | (Some(_), None) => return false,
+                (Some(Segment::Literal(literal)), Some(segment))
+                | (Some(segment), Some(Segment::Literal(literal))) => {
+                    if !segment.is_match(literal) {
+                        return false;
+                    }
+                }
+                (Some(_), Some(_)) => {}
+            }
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl std::str::FromStr for Pattern {
+    type Err = PatternError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Pattern::parse(s)
+    }
+}
+
+/// A union of include patterns minus any key matching an exclude pattern.
+#[derive(Debug, Clone, Default)]
+pub struct PatternSet {
+    include: Vec<Arc<Pattern>>,
+    exclude: Vec<Arc<Pattern>>,
+}
+
+impl PatternSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn include(mut self, pattern: impl Into<Arc<Pattern>>) -> Self {
+        self.include.push(pattern.into());
+        self
+    }
+
+    pub fn exclude(mut self, pattern: impl Into<Arc<Pattern>>) -> Self {
+        self.exclude.push(pattern.into());
+        self
+    }
+
+    pub fn includes(&self) -> &[Arc<Pattern>] {
+        &self.include
+    }
+
+    pub fn excludes(&self) -> &[Arc<Pattern>] {
+        &self.exclude
+    }
+
+    pub fn is_excluded(&self, key: &str) -> bool {
+        self.exclude.iter().any(|pattern| pattern.matches(key))
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        self.include.iter().any(|pattern| pattern.matches(key)) && !self.is_excluded(key)
+    }
+
+    /// Returns the key segments matched by the named captures of the first
+    /// include pattern that matches the key.
+    pub fn captures(&self, key: &str) -> BTreeMap<String, String> {
+        if !self.include.iter().any(|pattern| pattern.has_captures()) {
+            return BTreeMap::new();
+        }
+        self.include
+            .iter()
+            .find(|pattern| pattern.matches(key))
+            .map(|pattern| pattern.captures(key))
+            .unwrap_or_default()
+    }
+}
+
+/// Anything that can be used where a pattern is expected: a precompiled
+/// `Pattern`, or a string that is parsed on every call.
+pub trait AsPattern {
+    fn as_pattern(&self) -> Result<Cow<'_, Pattern>, PatternError>;
+}
+
+impl AsPattern for Pattern {
+    fn as_pattern(&self) -> Result<Cow<'_, Pattern>, PatternError> {
+        Ok(Cow::Borrowed(self))
+    }
+}
+
+impl AsPattern for str {
+    fn as_pattern(&self) -> Result<Cow<'_, Pattern>, PatternError> {
+        Pattern::parse(self).map(Cow::Owned)
+    }
+}
+
+impl AsPattern for String {
+    fn as_pattern(&self) -> Result<Cow<'_, Pattern>, PatternError> {
+        self.as_str().as_pattern()
+    }
+}
+
+/// A single dot-separated segment of a query pattern.
+#[derive(Debug, Clone)]
+pub enum Segment {
+    Literal(String),
+    Wildcard,
+    /// A wildcard whose matched value is reported under a name (`{device}`).
+    Capture(String),
+    /// Matches every key below this level, optionally limited to a number of
+    /// levels (`>2`).
+    Collector(Option<usize>),
+    Match(SegmentMatcher),
+}
+
+/// Matches a key segment against a glob (`ethernet*`, `esr1[ab]`,
+/// `{management0,ethernet1}`) or a regex (`~re:^et-\d+/\d+$`, or quoted as
+/// `~re:/^et-.+/` when it contains a dot).
+///
+/// `prefix` is the literal text every match must start with, which lets the
+/// query walk a `BTreeMap` range instead of every child.
+#[derive(Debug, Clone)]
+pub struct SegmentMatcher {
+    prefix: String,
+    regex: Regex,
+}
+
+impl SegmentMatcher {
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn is_match(&self, segment: &str) -> bool {
+        segment.starts_with(&self.prefix) && self.regex.is_match(segment)
+    }
+}
+
+impl Segment {
+    /// Reports whether a single key segment matches. A collector matches
+    /// anything; callers handle its multi-segment span.
+    pub fn is_match(&self, key: &str) -> bool {
+        match self {
+            Segment::Literal(literal) => literal == key,
+            Segment::Wildcard | Segment::Capture(_) | Segment::Collector(_) => true,
+            Segment::Match(matcher) => matcher.is_match(key),
+        }
+    }
+
+    /// Parses a single segment. Returns `None` if a glob or regex is malformed.
+    pub fn parse(segment: &str) -> Option<Segment> {
+        match segment {
+            WILDCARD => Some(Segment::Wildcard),
+            COLLECTOR => Some(Segment::Collector(None)),
+            _ => {
+                if let Some(depth) = segment.strip_prefix(COLLECTOR) {
+                    match depth.parse::<usize>() {
+                        Ok(depth) if depth > 0 => Some(Segment::Collector(Some(depth))),
+                        _ => None,
+                    }
+                } else if let Some(name) = capture_name(segment) {
+                    Some(Segment::Capture(name.to_string()))
+                } else if let Some(re) = segment.strip_prefix(REGEX_PREFIX) {
+                    let re = match re.strip_prefix('/') {
+                        Some(quoted) => unquote_regex(quoted.strip_suffix('/')?),
+                        None => re.to_string(),
+                    };
+                    let regex = Regex::new(&re).ok()?;
+                    Some(Segment::Match(SegmentMatcher {
+                        prefix: regex_literal_prefix(&re),
+                        regex,
+                    }))
+                } else if is_glob(segment) {
+                    let regex = Regex::new(&glob_to_regex(segment)?).ok()?;
+                    Some(Segment::Match(SegmentMatcher {
+                        prefix: glob_literal_prefix(segment),
+                        regex,
+                    }))
+                } else {
+                    Some(Segment::Literal(segment.to_string()))
+                }
+            }
+        }
+    }
+}
+
+// Splits a pattern on the delimiter, except inside a quoted regex segment
+// (`~re:/.../`), which ends at the first unescaped `/` followed by the
+// delimiter or the end of the pattern.
+fn split_segments(pattern: &str) -> Result<Vec<&str>, PatternError> {
+    let quoted = format!("{}/", REGEX_PREFIX);
+    let mut segments = Vec::new();
+    let mut rest = pattern;
+
+    loop {
+        if rest.starts_with(&quoted) {
+            let body = &rest[quoted.len()..];
+            let mut chars = body.char_indices();
+            let mut end = None;
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        chars.next();
+                    }
+                    '/' if body[i + 1..].is_empty() || body[i + 1..].starts_with(DELIMITER) => {
+                        end = Some(quoted.len() + i + 1);
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            let end = end.ok_or_else(|| PatternError::InvalidSegment(rest.into()))?;
+            segments.push(&rest[..end]);
+            match rest[end..].strip_prefix(DELIMITER) {
+                Some(next) => rest = next,
+                None => return Ok(segments),
+            }
+        } else {
+            match rest.split_once(DELIMITER) {
+                Some((segment, next)) => {
+                    segments.push(segment);
+                    rest = next;
+                }
+                None => {
+                    segments.push(rest);
+                    return Ok(segments);
+                }
+            }
+        }
+    }
+}
+
+// `\/` in a quoted regex stands for a slash; every other escape is the regex's
+fn unquote_regex(quoted: &str) -> String {
+    let mut re = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('/')) => {
+                re.push('/');
+                chars.next();
+            }
+            ('\\', Some(escaped)) => {
+                re.push(c);
+                re.push(escaped);
+                chars.next();
+            }
+            _ => re.push(c),
+        }
+    }
+    re
+}
+
+// `{name}` is a capture as long as it is a plain identifier. Braces holding
+// anything else are an alternation, which needs a comma: `{et-0/1}` is
+// rejected rather than read as one alternative, so that braces around a
+// single item always mean a capture.
+fn capture_name(segment: &str) -> Option<&str> {
+    let name = segment.strip_prefix('{')?.strip_suffix('}')?;
+    let mut chars = name.chars();
+    let first = chars.next()?;
+
+    if (first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+const GLOB_META: &[char] = &['*', '?', '[', '{'];
+
+fn is_glob(segment: &str) -> bool {
+    segment.contains(GLOB_META)
+}
+
+// Translates a glob into an anchored regex. Supports `*`, `?`, `[...]`,
+// `[!...]`, `{a,b}` (nestable, with at least two alternatives) and `\`
+// escapes.
+fn glob_to_regex(glob: &str) -> Option<String> {
+    let mut out = String::from("^");
+    let mut chars = glob.chars();
+    // whether each open brace has had a comma yet
+    let mut braces: Vec<bool> = Vec::new();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => out.push_str(".*"),
+            '?' => out.push('.'),
+            '\\' => out.push_str(&regex::escape(&chars.next()?.to_string())),
+            '[' => {
+                out.push('[');
+                let mut first = true;
+                loop {
+                    let c = chars.next()?;
+                    match c {
+                        ']' if !first => break,
+                        '!' | '^' if first => out.push('^'),
+                        '\\' => out.push_str(&regex::escape(&chars.next()?.to_string())),
+                        '[' | '&' | '~' => {
+                            out.push('\\');
+                            out.push(c);
+                        }
+                        _ => out.push(c),
+                    }
+                    first = false;
+                }
+                out.push(']');
+            }
+            '{' => {
+                braces.push(false);
+                out.push_str("(?:");
+            }
+            ',' if !braces.is_empty() => {
+                *braces.last_mut().unwrap() = true;
+                out.push('|');
+            }
+            // `{x}` with a single alternative would read as a capture, so
+            // an alternation needs at least two
+            '}' if !braces.is_empty() => {
+                if !braces.pop().unwrap() {
+                    return None;
+                }
+                out.push(')');
+            }
+            _ => out.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    if !braces.is_empty() {
+        return None;
+    }
+
+    out.push('$');
+    Some(out)
+}
+
+fn glob_literal_prefix(glob: &str) -> String {
+    let mut prefix = String::new();
+    let mut chars = glob.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) => prefix.push(escaped),
+                None => break,
+            },
+            c if GLOB_META.contains(&c) => break,
+            _ => prefix.push(c),
+        }
+    }
+
+    prefix
+}
+
+// Best-effort literal prefix of an anchored regex such as `^et-\d+`. Anything
+// unanchored or using top-level alternation gets no prefix.
+fn regex_literal_prefix(re: &str) -> String {
+    let Some(body) = re.strip_prefix('^') else {
+        return String::new();
+    };
+    if body.contains('|') {
+        return String::new();
+    }
+
+    let mut prefix = String::new();
+    for c in body.chars() {
+        match c {
+            '?' | '*' | '{' => {
+                // the quantifier makes the preceding char optional
+                prefix.pop();
+                break;
+            }
+            '\\' | '.' | '+' | '(' | ')' | '[' | ']' | '}' | '^' | '$' => break,
+            _ => prefix.push(c),
+        }
+    }
+
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(segment: &str) -> SegmentMatcher {
+        match Segment::parse(segment) {
+            Some(Segment::Match(m)) => m,
+            other => panic!(
+                "expected {} to parse as a matcher, got {:?}",
+                segment, other
+            ),
+        }
+    }
+
+    #[test]
+    fn test_parse_segments() {
+        assert!(matches!(Segment::parse("*"), Some(Segment::Wildcard)));
+        assert!(matches!(
+            Segment::parse(">"),
+            Some(Segment::Collector(None))
+        ));
+        assert!(matches!(
+            Segment::parse(">2"),
+            Some(Segment::Collector(Some(2)))
+        ));
+        assert!(Segment::parse(">0").is_none());
+        assert!(matches!(Segment::parse("{site}"), Some(Segment::Capture(name)) if name == "site"));
+        assert!(matches!(Segment::parse("{a,b}"), Some(Segment::Match(_))));
+        assert!(Segment::parse(">x").is_none());
+        assert!(matches!(Segment::parse("esr1a"), Some(Segment::Literal(_))));
+        assert!(Segment::parse("{a,b").is_none());
+        // braces around one item are a capture or nothing
+        assert!(Segment::parse("{et-0/1}").is_none());
+        assert!(Segment::parse("eth{0}").is_none());
+        assert!(matches!(Segment::parse("eth{0,1}"), Some(Segment::Match(_))));
+        assert!(Segment::parse("esr1[ab").is_none());
+        assert!(Segment::parse("~re:(").is_none());
+    }
+
+    #[test]
+    fn test_parse_pattern() {
+        let pattern = Pattern::parse("interface.*.esr1[ab].>").unwrap();
+        assert_eq!(pattern.as_str(), "interface.*.esr1[ab].>");
+        assert_eq!(pattern.segments().len(), 4);
+
+        assert_eq!(
+            Pattern::parse("a.>.b").unwrap_err(),
+            PatternError::CollectorNotLast
+        );
+        assert_eq!(
+            Pattern::parse("a.{b,c").unwrap_err(),
+            PatternError::InvalidSegment("{b,c".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_quoted_regex() {
+        let pattern = Pattern::parse(r"interface.~re:/^et-.+\/1$/.>").unwrap();
+        assert_eq!(pattern.segments().len(), 3);
+        assert!(pattern.matches("interface.et-0/1.state"));
+        assert!(!pattern.matches("interface.et-0/2.state"));
+
+        let pattern = Pattern::parse("a.~re:/^b.c$/").unwrap();
+        assert_eq!(pattern.segments().len(), 2);
+        assert!(pattern.matches("a.bxc"));
+
+        // unquoted, a regex ends at the next delimiter
+        assert_eq!(Pattern::parse("a.~re:^b.c$").unwrap().segments().len(), 3);
+        assert_eq!(
+            Pattern::parse("a.~re:/b.c").unwrap_err(),
+            PatternError::InvalidSegment("~re:/b.c".to_string())
+        );
+    }
+
+    #[test]
+    fn test_pattern_matches() {
+        let pattern = Pattern::parse("interface.*.esr1[ab].>").unwrap();
+        assert!(pattern.matches("interface.lab1.esr1a.ethernet1"));
+        assert!(pattern.matches("interface.lab1.esr1b.ethernet1.oper-status"));
+        assert!(!pattern.matches("interface.lab1.esr1a"));
+        assert!(!pattern.matches("interface.lab1.esr1c.ethernet1"));
+
+        let pattern = Pattern::parse("interface.lab1.>2").unwrap();
+        assert!(pattern.matches("interface.lab1.esr1a"));
+        assert!(pattern.matches("interface.lab1.esr1a.ethernet1"));
+        assert!(!pattern.matches("interface.lab1.esr1a.ethernet1.oper-status"));
+
+        let pattern = Pattern::parse("a.*.c").unwrap();
+        assert!(pattern.matches("a.b.c"));
+        assert!(!pattern.matches("a.b"));
+        assert!(!pattern.matches("a.b.c.d"));
+    }
+
+    #[test]
+    fn test_pattern_covers() {
+        let covers = |a: &str, b: &str| {
+            Pattern::parse(a)
+                .unwrap()
+                .covers(&Pattern::parse(b).unwrap())
+        };
+
+        assert!(covers("bgp.neighbor.>", "bgp.neighbor.*.state"));
+        assert!(covers("bgp.neighbor.>", "bgp.neighbor.>"));
+        assert!(covers("bgp.*.{peer}", "bgp.neighbor.10*"));
+        assert!(covers("bgp.neighbor*.*", "bgp.neighbor1.x"));
+        assert!(covers("bgp.>2", "bgp.a.*"));
+        assert!(!covers("bgp.>2", "bgp.a.*.c"));
+        assert!(!covers("bgp.>2", "bgp.>"));
+        assert!(!covers("bgp.neighbor.>", "bgp.neighbor"));
+        assert!(!covers("bgp.neighbor.>", "bgp.*.x"));
+        assert!(!covers("bgp.neighbor*.*", "bgp.n*.x"));
+        assert!(!covers("bgp.*", "bgp.*.x"));
+    }
+
+    #[test]
+    fn test_pattern_overlaps() {
+        let overlaps = |a: &str, b: &str| {
+            Pattern::parse(a)
+                .unwrap()
+                .overlaps(&Pattern::parse(b).unwrap())
+        };
+
+        assert!(overlaps("interface.lab1.>", "interface.*.p01.>"));
+        assert!(overlaps("interface.>", "*.lab1"));
+        assert!(overlaps("bgp.neighbor*.>", "bgp.neighbor1.state"));
+        assert!(overlaps(">", "bgp"));
+        assert!(!overlaps("interface.lab1.>", "interface.lab2.>"));
+        assert!(!overlaps("interface.lab1.>", "interface.lab1"));
+        assert!(!overlaps("bgp.neighbor*.>", "bgp.peer.state"));
+        assert!(!overlaps("bgp.*", "bgp.*.x"));
+    }
+
+    #[test]
+    fn test_pattern_captures() {
+        let pattern = Pattern::parse("interface.{site}.*.*.{device}.{ifname}.oper-status").unwrap();
+        assert!(pattern.has_captures());
+        assert!(pattern.matches("interface.lab1.p01.rk01.esr1a.ethernet1.oper-status"));
+
+        let labels = pattern.captures("interface.lab1.p01.rk01.esr1a.ethernet1.oper-status");
+        assert_eq!(labels.len(), 3);
+        assert_eq!(labels["site"], "lab1");
+        assert_eq!(labels["device"], "esr1a");
+        assert_eq!(labels["ifname"], "ethernet1");
+
+        assert_eq!(
+            Pattern::parse("a.{x}.{x}").unwrap_err(),
+            PatternError::DuplicateCapture("x".to_string())
+        );
+        assert!(!Pattern::parse("a.*.>").unwrap().has_captures());
+    }
+
+    #[test]
+    fn test_pattern_set_matches() {
+        let set = PatternSet::new()
+            .include(Pattern::parse("interface.lab1.>").unwrap())
+            .include(Pattern::parse("bgp.*").unwrap())
+            .exclude(Pattern::parse("interface.lab1.*.management0.>").unwrap());
+
+        assert!(set.matches("interface.lab1.esr1a.ethernet1.oper-status"));
+        assert!(set.matches("bgp.esr1a"));
+        assert!(!set.matches("interface.lab1.esr1a.management0.oper-status"));
+        assert!(!set.matches("interface.lab2.esr1a.ethernet1.oper-status"));
+    }
+
+    #[test]
+    fn test_pattern_set_captures() {
+        let set = PatternSet::new()
+            .include(Pattern::parse("interface.{site}.{device}.>").unwrap())
+            .include(Pattern::parse("interface.lab1.{host}.>").unwrap())
+            .include(Pattern::parse("bgp.*").unwrap());
+
+        // the first include that matches names the labels
+        let labels = set.captures("interface.lab1.esr1a.ethernet1");
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels["site"], "lab1");
+        assert_eq!(labels["device"], "esr1a");
+
+        // plain wildcards don't produce labels
+        assert!(set.captures("bgp.esr1a").is_empty());
+        assert!(set.captures("system.hostname").is_empty());
+    }
+
+    #[test]
+    fn test_glob_matching() {
+        let m = matcher("ethernet*");
+        assert_eq!(m.prefix(), "ethernet");
+        assert!(m.is_match("ethernet1"));
+        assert!(m.is_match("ethernet"));
+        assert!(!m.is_match("management0"));
+
+        let m = matcher("esr1[ab]");
+        assert_eq!(m.prefix(), "esr1");
+        assert!(m.is_match("esr1a"));
+        assert!(m.is_match("esr1b"));
+        assert!(!m.is_match("esr1c"));
+        assert!(!m.is_match("esr1ab"));
+
+        let m = matcher("esr1[!a]");
+        assert!(!m.is_match("esr1a"));
+        assert!(m.is_match("esr1b"));
+
+        let m = matcher("{management0,ethernet1}");
+        assert_eq!(m.prefix(), "");
+        assert!(m.is_match("management0"));
+        assert!(m.is_match("ethernet1"));
+        assert!(!m.is_match("ethernet2"));
+
+        let m = matcher("rk0?");
+        assert!(m.is_match("rk01"));
+        assert!(!m.is_match("rk010"));
+
+        let m = matcher("a\\*");
+        assert_eq!(m.prefix(), "a*");
+        assert!(m.is_match("a*"));
+        assert!(!m.is_match("ab"));
+
+        // a backslash on its own is just part of a literal key segment
+        assert!(matches!(
+            Segment::parse(r"C:\Users"),
+            Some(Segment::Literal(literal)) if literal == r"C:\Users"
+        ));
+    }
+
+    #[test]
+    fn test_regex_matching() {
+        let m = matcher(r"~re:^et-\d+/\d+$");
+        assert_eq!(m.prefix(), "et-");
+        assert!(m.is_match("et-0/1"));
+        assert!(!m.is_match("et-x/1"));
+        assert!(!m.is_match("xe-0/1"));
+
+        assert_eq!(matcher("~re:^ab?c").prefix(), "a");
+        assert_eq!(matcher("~re:^ab|cd").prefix(), "");
+        assert_eq!(matcher("~re:eth").prefix(), "");
+    }
+}
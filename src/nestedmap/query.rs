@@ -1,86 +1,224 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Bound;
+
 use super::config::*;
 use super::options::GetOptions;
-use super::{Item, NestedMap, NestedValue};
+use super::pattern::{AsPattern, Pattern, PatternSet, Segment};
+use super::{value, Item, NestedMap, NestedValue};
 
 impl NestedMap {
-    pub fn query(&self, keys: &str, options: Option<GetOptions>) -> Vec<Item> {
+    pub fn query<P: AsPattern + ?Sized>(
+        &self,
+        pattern: &P,
+        options: Option<GetOptions>,
+    ) -> Vec<Item> {
         let options = options.unwrap_or_default();
         let mut results = Vec::new();
-        let keys: Vec<&str> = keys.split(DELIMITER).collect();
-        Self::query_recursive(&keys, self, &mut results, options.history_count);
+
+        // A malformed pattern can never match anything
+        let Ok(pattern) = pattern.as_pattern() else {
+            return results;
+        };
+
+        self.walk(&pattern, &options, &mut |items| {
+            results.extend(
+                items
+                    .iter()
+                    .take(options.history_count)
+                    .filter(|item| options.matches(item))
+                    .map(|item| Self::output(item, &options)),
+            );
+        });
+        // the walk goes segment by segment, which isn't the keys' order when
+        // a segment sorts differently from the delimiter, e.g. `a-b` and `a.c`;
+        // the sort is stable, so each key's history stays newest first
+        results.sort_by(|a, b| a.key.cmp(&b.key));
         results
     }
 
-    fn query_recursive(
-        keys: &[&str],
-        current: &NestedMap,
-        results: &mut Vec<Item>,
-        history_max: usize,
+    /// Queries the union of a set's include patterns, dropping keys matched by
+    /// any exclude pattern. Each key appears once and results are ordered by
+    /// key, newest history first.
+    pub fn query_set(&self, patterns: &PatternSet, options: Option<GetOptions>) -> Vec<Item> {
+        let options = options.unwrap_or_default();
+        let mut matched: BTreeMap<&str, &VecDeque<Item>> = BTreeMap::new();
+
+        for pattern in patterns.includes() {
+            self.walk(pattern, &options, &mut |items| {
+                if let Some(item) = items.front() {
+                    if !patterns.is_excluded(&item.key) {
+                        matched.entry(&item.key).or_insert(items);
+                    }
+                }
+            });
+        }
+
+        matched
+            .into_values()
+            .flat_map(|items| {
+                items
+                    .iter()
+                    .take(options.history_count)
+                    .filter(|item| options.matches(item))
+                    .map(|item| Self::output(item, &options))
+            })
+            .collect()
+    }
+
+    // Clones an item for the results, keeping only the requested fields
+    fn output(item: &Item, options: &GetOptions) -> Item {
+        let mut item = item.clone();
+        if !options.fields.is_empty() {
+            item.value = value::project(&item.value, &options.fields);
+        }
+        item
+    }
+
+    // Calls `visit` with the item list of every key matching the pattern,
+    // limiting collectors to `max_depth` levels. A malformed pattern can never
+    // match anything.
+    pub(crate) fn visit_matching<'a, P, F>(
+        &'a self,
+        pattern: &P,
+        max_depth: Option<usize>,
+        mut visit: F,
+    ) where
+        P: AsPattern + ?Sized,
+        F: FnMut(&'a VecDeque<Item>),
+    {
+        if let Ok(pattern) = pattern.as_pattern() {
+            let mut options = GetOptions::new();
+            options.max_depth = max_depth;
+            self.walk(&pattern, &options, &mut visit);
+        }
+    }
+
+    // Looks up the keys planned by a value or segment index when one can help,
+    // and walks the tree otherwise. Value indexes only know each key's latest
+    // value, so they aren't used when older history is being filtered too.
+    fn walk<'a, F: FnMut(&'a VecDeque<Item>)>(
+        &'a self,
+        pattern: &Pattern,
+        options: &GetOptions,
+        visit: &mut F,
+    ) {
+        let planned = options
+            .filter
+            .as_ref()
+            .filter(|_| options.history_count <= 1 && options.max_depth.is_none())
+            .and_then(|filter| {
+                self.value_indexes
+                    .iter()
+                    .find_map(|index| index.candidates(pattern, filter))
+            })
+            .or_else(|| {
+                self.index
+                    .as_ref()
+                    .and_then(|index| index.candidates(pattern))
+            });
+
+        if let Some(keys) = planned {
+            for key in keys {
+                if let Some(items) = self.get_items(key) {
+                    visit(items);
+                }
+            }
+            return;
+        }
+
+        Self::query_recursive(pattern.segments(), self, options.max_depth, visit);
+    }
+
+    fn query_recursive<'a, F: FnMut(&'a VecDeque<Item>)>(
+        keys: &[Segment],
+        current: &'a NestedMap,
+        max_depth: Option<usize>,
+        visit: &mut F,
     ) {
         if keys.is_empty() {
             // Collect items at the current level using VALUE_KEY
             if let Some(NestedValue::Items(items)) = current.data.get(VALUE_KEY) {
-                results.extend(items.iter().take(history_max).cloned());
+                visit(items);
             }
             return;
         }
 
-        let next_key = keys[0];
         let remaining_keys = &keys[1..];
 
-        match next_key {
-            WILDCARD => {
+        match &keys[0] {
+            Segment::Wildcard | Segment::Capture(_) => {
                 // Iterate through all entries in the current map
                 for value in current.data.values() {
                     if let NestedValue::Map(nested_map) = value {
                         // Recurse into every nested map when "*" is encountered
-                        Self::query_recursive(remaining_keys, nested_map, results, history_max);
+                        Self::query_recursive(remaining_keys, nested_map, max_depth, visit);
                     }
                 }
             }
-            COLLECTOR => {
-                Self::collect_all(current, results, true, history_max);
+            Segment::Collector(depth) => {
+                let depth = match (*depth, max_depth) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+                Self::collect_all(current, visit, true, depth);
             }
-            _ => {
-                if let Some(NestedValue::Map(nested_map)) = current.data.get(next_key) {
-                    if remaining_keys.is_empty() {
-                        // Check for VALUE_KEY in the last map
-                        if let Some(NestedValue::Items(items)) = nested_map.data.get(VALUE_KEY) {
-                            results.extend(items.iter().take(history_max).cloned());
+            Segment::Match(matcher) => {
+                // Only walk the children sharing the matcher's literal prefix
+                let prefix = matcher.prefix();
+                for (key, value) in current
+                    .data
+                    .range::<_, str>((Bound::Included(prefix), Bound::Unbounded))
+                {
+                    if !key.starts_with(prefix) {
+                        break;
+                    }
+                    if let NestedValue::Map(nested_map) = value {
+                        if matcher.is_match(key) {
+                            Self::query_recursive(remaining_keys, nested_map, max_depth, visit);
                         }
-                    } else {
-                        Self::query_recursive(remaining_keys, nested_map, results, history_max);
                     }
                 }
             }
+            Segment::Literal(next_key) => {
+                if let Some(NestedValue::Map(nested_map)) = current.data.get(next_key.as_str()) {
+                    Self::query_recursive(remaining_keys, nested_map, max_depth, visit);
+                }
+            }
         }
     }
 
-    fn collect_all(
-        current: &NestedMap,
-        results: &mut Vec<Item>,
+    fn collect_all<'a, F: FnMut(&'a VecDeque<Item>)>(
+        current: &'a NestedMap,
+        visit: &mut F,
         skip_current_level: bool,
-        history_max: usize,
+        max_depth: Option<usize>,
     ) {
         // Only collect items if not skipping the current level
         if !skip_current_level {
             if let Some(NestedValue::Items(items)) = current.data.get(VALUE_KEY) {
-                results.extend(items.iter().take(history_max).cloned());
+                visit(items);
             }
         }
 
-        // Always proceed to collect from sub-maps
+        // Stop descending once the depth limit is used up
+        if max_depth == Some(0) {
+            return;
+        }
+
         for value in current.data.values() {
             if let NestedValue::Map(nested_map) = value {
                 // Skip the current level's items but not for sub-maps
-                Self::collect_all(nested_map, results, false, history_max);
+                Self::collect_all(nested_map, visit, false, max_depth.map(|d| d - 1));
             }
         }
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::nestedmap::filter::Filter;
+    use crate::nestedmap::options::SetOptions;
     use crate::nestedmap::test_helpers::*;
 
     #[test]
@@ -415,6 +553,268 @@ mod tests {
         query_tests(test_cases)
     }
 
+    #[test]
+    fn test_compiled_pattern() {
+        let mut nm = NestedMap::new(1);
+        nm.set("a.b.c", &create_item("a.b.c", b"abc"), None);
+        nm.set("a.x.c", &create_item("a.x.c", b"axc"), None);
+
+        let pattern = Pattern::parse("a.*.c").unwrap();
+        assert_eq!(nm.query(&pattern, None).len(), 2);
+        assert_eq!(nm.query(&pattern, None).len(), 2);
+
+        // invalid patterns match nothing
+        assert!(nm.query("a.>.c", None).is_empty());
+    }
+
+    #[test]
+    fn test_query_ordered_by_key() {
+        let mut nm = NestedMap::new(2);
+        for key in ["a.b", "a-c.d", "a.B", "a.b.c"] {
+            nm.set(key, &create_item(key, b"old"), None);
+        }
+        nm.set(
+            "a.b",
+            &create_item("a.b", b"new"),
+            Some(SetOptions::new().preserve_history(true)),
+        );
+
+        let results = nm.query(">", Some(GetOptions::new().history_count(2)));
+        let keys: Vec<&str> = results.iter().map(|item| item.key.as_str()).collect();
+        assert_eq!(keys, vec!["a-c.d", "a.B", "a.b", "a.b", "a.b.c"]);
+        assert_eq!(results[2].value, b"new");
+        assert_eq!(results[3].value, b"old");
+    }
+
+    #[test]
+    fn test_query_set() {
+        let mut nm = NestedMap::new(2);
+        seed_interfaces(&mut nm);
+        nm.set(
+            "interface.lab1.p01.rk01.esr1a.ethernet1.oper-status",
+            &create_item(
+                "interface.lab1.p01.rk01.esr1a.ethernet1.oper-status",
+                b"down",
+            ),
+            Some(SetOptions::new().preserve_history(true)),
+        );
+
+        let patterns = PatternSet::new()
+            .include(Pattern::parse("interface.lab1.*.*.esr1a.>").unwrap())
+            .include(Pattern::parse("interface.lab1.*.*.*.ethernet1.>").unwrap())
+            .exclude(Pattern::parse("interface.lab1.*.*.*.management0.>").unwrap())
+            .exclude(Pattern::parse("interface.lab1.*.*.*.et-*.>").unwrap());
+
+        let results = nm.query_set(&patterns, Some(GetOptions::new().history_count(2)));
+        let keys: Vec<&str> = results.iter().map(|item| item.key.as_str()).collect();
+
+        assert_eq!(
+            keys,
+            vec![
+                "interface.lab1.p01.rk01.esr1a.ethernet1.oper-status",
+                "interface.lab1.p01.rk01.esr1a.ethernet1.oper-status",
+                "interface.lab1.p01.rk01.esr1a.ethernet2.oper-status",
+                "interface.lab1.p01.rk01.esr1b.ethernet1.oper-status",
+                "interface.lab1.p01.rk01.esr1c.ethernet1.oper-status",
+            ]
+        );
+        assert_eq!(results[0].value, b"down");
+        assert_eq!(results[1].value, b"up");
+    }
+
+    #[test]
+    fn test_depth_limited_collector() {
+        let mut nm = NestedMap::new(1);
+        for key in ["a.b", "a.b.c", "a.b.c.d", "a.x", "a.x.y.z"] {
+            nm.set(key, &create_item(key, b"value"), None);
+        }
+
+        let keys = |results: Vec<Item>| -> Vec<String> {
+            let mut keys: Vec<String> = results.into_iter().map(|item| item.key).collect();
+            keys.sort();
+            keys
+        };
+
+        assert_eq!(
+            keys(nm.query("a.>1", None)),
+            crate::vec_string!["a.b", "a.x"]
+        );
+        assert_eq!(
+            keys(nm.query("a.>2", None)),
+            crate::vec_string!["a.b", "a.b.c", "a.x"]
+        );
+        assert_eq!(
+            keys(nm.query("a.>", Some(GetOptions::new().max_depth(2)))),
+            crate::vec_string!["a.b", "a.b.c", "a.x"]
+        );
+        // the tighter of the two limits wins
+        assert_eq!(
+            keys(nm.query("a.>1", Some(GetOptions::new().max_depth(3)))),
+            crate::vec_string!["a.b", "a.x"]
+        );
+        assert_eq!(keys(nm.query("a.>", None)).len(), 5);
+    }
+
+    #[test]
+    fn test_value_filter() {
+        let mut nm = NestedMap::new(1);
+        for (peer, state) in [
+            ("1_1_1_1", "established"),
+            ("1_1_1_2", "idle"),
+            ("1_1_1_3", "active"),
+        ] {
+            let key = format!("bgp.neighbor.lab1.esr1a.peer-ip.{}", peer);
+            let value = rmp_serde::to_vec_named(&serde_json::json!({ "state": state })).unwrap();
+            nm.set(&key, &create_item(&key, &value), None);
+        }
+        nm.set(
+            "bgp.neighbor.lab1.esr1a.peer-ip.opaque",
+            &create_item("bgp.neighbor.lab1.esr1a.peer-ip.opaque", &[0xc1]),
+            None,
+        );
+
+        let filter = Filter::parse("value.state != 'established'").unwrap();
+        let mut keys: Vec<String> = nm
+            .query(
+                "bgp.neighbor.>",
+                Some(GetOptions::new().filter(filter.clone())),
+            )
+            .into_iter()
+            .map(|item| item.key)
+            .collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            crate::vec_string![
+                "bgp.neighbor.lab1.esr1a.peer-ip.1_1_1_2",
+                "bgp.neighbor.lab1.esr1a.peer-ip.1_1_1_3"
+            ]
+        );
+
+        let patterns = PatternSet::new().include(Pattern::parse("bgp.>").unwrap());
+        assert_eq!(
+            nm.query_set(&patterns, Some(GetOptions::new().filter(filter)))
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_field_projection() {
+        let mut nm = NestedMap::new(1);
+        let value = rmp_serde::to_vec_named(&serde_json::json!({
+            "state": "established",
+            "uptime": 300,
+            "blob": "x".repeat(4096),
+        }))
+        .unwrap();
+        nm.set("bgp.peer.a", &create_item("bgp.peer.a", &value), None);
+
+        let options = GetOptions::new()
+            .filter(Filter::parse("value.state == 'established'").unwrap())
+            .fields(crate::vec_string!["state", "uptime"]);
+        let results = nm.query("bgp.peer.*", Some(options));
+
+        assert_eq!(results.len(), 1);
+        let projected: serde_json::Value = rmp_serde::from_read_ref(&results[0].value).unwrap();
+        assert_eq!(
+            projected,
+            serde_json::json!({"state": "established", "uptime": 300})
+        );
+
+        // the stored value is untouched
+        assert_eq!(nm.get("bgp.peer.a").unwrap().value, value);
+    }
+
+    fn seed_interfaces(nm: &mut NestedMap) {
+        for device in ["esr1a", "esr1b", "esr1c"] {
+            for ifname in ["management0", "ethernet1", "ethernet2", "et-0/1", "et-0/x"] {
+                let key = format!("interface.lab1.p01.rk01.{}.{}.oper-status", device, ifname);
+                nm.set(&key, &create_item(&key, b"up"), None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_segment_patterns() {
+        let test_cases = vec![
+            TestCase {
+                name: "Test glob suffix",
+                setup: Box::new(seed_interfaces),
+                search_keys: "interface.lab1.p01.rk01.esr1a.ethernet*.oper-status".to_string(),
+                expected: vec![
+                    create_item("interface.lab1.p01.rk01.esr1a.ethernet1.oper-status", b"up"),
+                    create_item("interface.lab1.p01.rk01.esr1a.ethernet2.oper-status", b"up"),
+                ],
+                max_history: 1,
+            },
+            TestCase {
+                name: "Test glob character class",
+                setup: Box::new(seed_interfaces),
+                search_keys: "interface.lab1.p01.rk01.esr1[ab].management0.oper-status".to_string(),
+                expected: vec![
+                    create_item(
+                        "interface.lab1.p01.rk01.esr1a.management0.oper-status",
+                        b"up",
+                    ),
+                    create_item(
+                        "interface.lab1.p01.rk01.esr1b.management0.oper-status",
+                        b"up",
+                    ),
+                ],
+                max_history: 1,
+            },
+            TestCase {
+                name: "Test glob alternation",
+                setup: Box::new(seed_interfaces),
+                search_keys: "interface.lab1.p01.rk01.esr1c.{management0,ethernet1}.oper-status"
+                    .to_string(),
+                expected: vec![
+                    create_item(
+                        "interface.lab1.p01.rk01.esr1c.management0.oper-status",
+                        b"up",
+                    ),
+                    create_item("interface.lab1.p01.rk01.esr1c.ethernet1.oper-status", b"up"),
+                ],
+                max_history: 1,
+            },
+            TestCase {
+                name: "Test regex segment",
+                setup: Box::new(seed_interfaces),
+                search_keys: r"interface.lab1.p01.rk01.esr1b.~re:^et-\d+/\d+$.oper-status"
+                    .to_string(),
+                expected: vec![create_item(
+                    "interface.lab1.p01.rk01.esr1b.et-0/1.oper-status",
+                    b"up",
+                )],
+                max_history: 1,
+            },
+            TestCase {
+                name: "Test glob with collector",
+                setup: Box::new(seed_interfaces),
+                search_keys: "interface.lab1.p01.rk01.esr1?.et-*.>".to_string(),
+                expected: vec![
+                    create_item("interface.lab1.p01.rk01.esr1a.et-0/1.oper-status", b"up"),
+                    create_item("interface.lab1.p01.rk01.esr1a.et-0/x.oper-status", b"up"),
+                    create_item("interface.lab1.p01.rk01.esr1b.et-0/1.oper-status", b"up"),
+                    create_item("interface.lab1.p01.rk01.esr1b.et-0/x.oper-status", b"up"),
+                    create_item("interface.lab1.p01.rk01.esr1c.et-0/1.oper-status", b"up"),
+                    create_item("interface.lab1.p01.rk01.esr1c.et-0/x.oper-status", b"up"),
+                ],
+                max_history: 1,
+            },
+            TestCase {
+                name: "Test malformed glob matches nothing",
+                setup: Box::new(seed_interfaces),
+                search_keys: "interface.lab1.p01.rk01.esr1[ab.>".to_string(),
+                expected: Vec::new(),
+                max_history: 1,
+            },
+        ];
+
+        query_tests(test_cases)
+    }
+
     fn query_tests(test_cases: Vec<TestCase>) {
         for test in test_cases {
             let mut nm = NestedMap::new(test.max_history);
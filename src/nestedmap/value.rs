@@ -0,0 +1,170 @@
+use std::io::Cursor;
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// How a stored value was encoded, so structured values can be re-encoded
+/// the same way after being inspected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+    Text,
+}
+
+/// Decodes a stored value into a JSON value. Values are tried as JSON, then
+/// MessagePack, then plain UTF-8 text. Returns `None` for opaque binary data.
+///
+/// JSON is tried first because any multi-byte JSON document is never a
+/// complete MessagePack value, while short ASCII text often starts with a
+/// valid MessagePack integer.
+pub fn decode(bytes: &[u8]) -> Option<(Value, Encoding)> {
+    if let Ok(value) = serde_json::from_slice::<Value>(bytes) {
+        return Some((value, Encoding::Json));
+    }
+
+    if let Some(value) = decode_msgpack(bytes) {
+        return Some((value, Encoding::MessagePack));
+    }
+
+    std::str::from_utf8(bytes)
+        .ok()
+        .map(|text| (Value::String(text.to_string()), Encoding::Text))
+}
+
+pub fn encode(value: &Value, encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Json => serde_json::to_vec(value).unwrap_or_default(),
+        Encoding::MessagePack => rmp_serde::to_vec_named(value).unwrap_or_default(),
+        Encoding::Text => match value {
+            Value::String(text) => text.clone().into_bytes(),
+            other => other.to_string().into_bytes(),
+        },
+    }
+}
+
+/// Keeps only the named fields of a structured value and re-encodes it the
+/// way it was stored. Dotted fields (`counters.in-octets`) select nested
+/// values. Values that aren't objects are returned unchanged.
+pub fn project(bytes: &[u8], fields: &[String]) -> Vec<u8> {
+    match decode(bytes) {
+        Some((value @ Value::Object(_), encoding)) => {
+            let mut projected = Value::Object(Map::new());
+            for field in fields {
+                let path: Vec<&str> = field.split(FIELD_DELIMITER).collect();
+                if let Some(selected) = select(&value, &path) {
+                    insert(&mut projected, &path, selected.clone());
+                }
+            }
+            encode(&projected, encoding)
+        }
+        _ => bytes.to_vec(),
+    }
+}
+
+const FIELD_DELIMITER: char = '.';
+
+fn select<'a>(value: &'a Value, path: &[&str]) -> Option<&'a Value> {
+    path.iter().try_fold(value, |value, field| value.get(field))
+}
+
+fn insert(target: &mut Value, path: &[&str], value: Value) {
+    let Some((last, parents)) = path.split_last() else {
+        return;
+    };
+
+    let mut current = target;
+    for field in parents {
+        current = current
+            .as_object_mut()
+            .unwrap()
+            .entry(field.to_string())
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+    current
+        .as_object_mut()
+        .unwrap()
+        .insert(last.to_string(), value);
+}
+
+// Only accepts input that is exactly one MessagePack value
+fn decode_msgpack(bytes: &[u8]) -> Option<Value> {
+    let mut deserializer = rmp_serde::Deserializer::new(Cursor::new(bytes));
+    let value = Value::deserialize(&mut deserializer).ok()?;
+
+    if deserializer.get_ref().position() as usize == bytes.len() {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_decode() {
+        let packed =
+            rmp_serde::to_vec_named(&json!({"state": "established", "mtu": 9000})).unwrap();
+        assert_eq!(
+            decode(&packed),
+            Some((
+                json!({"state": "established", "mtu": 9000}),
+                Encoding::MessagePack
+            ))
+        );
+
+        assert_eq!(
+            decode(br#"{"state":"idle"}"#),
+            Some((json!({"state": "idle"}), Encoding::Json))
+        );
+        assert_eq!(decode(b"65000"), Some((json!(65000), Encoding::Json)));
+        assert_eq!(decode(b"up"), Some((json!("up"), Encoding::Text)));
+        assert_eq!(decode(&[0xc1, 0xff]), None);
+    }
+
+    #[test]
+    fn test_project() {
+        let device = json!({
+            "state": "up",
+            "uptime": 12,
+            "blob": "x".repeat(1024),
+            "counters": {"in-octets": 10, "out-octets": 20},
+        });
+        let fields = vec![
+            "state".to_string(),
+            "uptime".to_string(),
+            "counters.in-octets".to_string(),
+            "missing".to_string(),
+        ];
+        let expected = json!({"state": "up", "uptime": 12, "counters": {"in-octets": 10}});
+
+        let packed = rmp_serde::to_vec_named(&device).unwrap();
+        assert_eq!(
+            decode(&project(&packed, &fields)),
+            Some((expected.clone(), Encoding::MessagePack))
+        );
+
+        let json = serde_json::to_vec(&device).unwrap();
+        assert_eq!(
+            decode(&project(&json, &fields)),
+            Some((expected, Encoding::Json))
+        );
+
+        assert_eq!(project(b"up", &fields), b"up");
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let value = json!({"state": "established", "uptime": 12});
+        for encoding in [Encoding::Json, Encoding::MessagePack] {
+            assert_eq!(
+                decode(&encode(&value, encoding)),
+                Some((value.clone(), encoding))
+            );
+        }
+        assert_eq!(encode(&json!("up"), Encoding::Text), b"up");
+    }
+}
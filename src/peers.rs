@@ -0,0 +1,41 @@
+// Connections to other servers, opened on first use and then shared by
+// every request to the same address
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tonic::transport::{Channel, Endpoint};
+
+use crate::datastore::datastore_client::DatastoreClient;
+
+#[derive(Debug)]
+pub struct Peers {
+    // Bounds both connecting and each request
+    timeout: Duration,
+    clients: Mutex<HashMap<String, DatastoreClient<Channel>>>,
+}
+
+impl Peers {
+    pub fn new(timeout: Duration) -> Self {
+        Peers {
+            timeout,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A client for the server at `address`, or `None` if it isn't a valid URL.
+    pub fn client(&self, address: &str) -> Option<DatastoreClient<Channel>> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get(address) {
+            return Some(client.clone());
+        }
+        let channel = Endpoint::from_shared(address.to_string())
+            .ok()?
+            .connect_timeout(self.timeout)
+            .timeout(self.timeout)
+            .connect_lazy();
+        let client = DatastoreClient::new(channel);
+        clients.insert(address.to_string(), client.clone());
+        Some(client)
+    }
+}
@@ -0,0 +1,1099 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use rand::Rng;
+
+use super::storage::Saved;
+use super::{Command, Entry, LogSnapshot, Members, Message, NodeId, ProposeError};
+
+// Upper bound on the entries sent in one AppendEntries
+const MAX_APPEND_ENTRIES: usize = 256;
+// Upper bound on the snapshot data sent in one InstallSnapshot, which keeps
+// it well under the 4 MB a gRPC transport decodes by default
+const SNAPSHOT_CHUNK_SIZE: usize = 512 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+// What the leader knows of a follower's log
+#[derive(Debug, Clone, Copy)]
+struct Progress {
+    // The next entry to send
+    next: u64,
+    // The last entry known to match the leader's
+    matched: u64,
+    // Set while the follower is sent the snapshot instead
+    sending: Option<Sending>,
+}
+
+// A snapshot on its way to a follower, one chunk at a time
+#[derive(Debug, Clone, Copy)]
+struct Sending {
+    // The snapshot's index, which changes if the leader compacts again
+    index: u64,
+    // How much of its data the follower acknowledged
+    offset: u64,
+    // Ticks since the last chunk was sent
+    waited: u32,
+}
+
+/// What changed, of the state a node keeps across restarts, since it was
+/// last taken. It has to be saved before any message from the same steps is
+/// sent, or any entry applied.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Unsaved {
+    // The term or the vote changed
+    pub hard_state: bool,
+    // The snapshot was replaced, along with the entries it covers
+    pub snapshot: bool,
+    // Entries from this index on were appended or replaced
+    pub entries_from: Option<u64>,
+}
+
+/// One node's Raft state, driven by ticks and messages and kept free of any
+/// I/O: whatever it wants sent piles up in an outbox, committed entries
+/// wait to be taken and applied, and whatever has to survive a restart
+/// waits to be saved. That keeps every step deterministic apart from the
+/// randomized election timeouts.
+///
+/// Membership changes one node at a time, and a node uses the newest
+/// membership in its log whether it is committed or not.
+#[derive(Debug)]
+pub struct Raft {
+    id: NodeId,
+    role: Role,
+    term: u64,
+    voted_for: Option<NodeId>,
+    leader: Option<NodeId>,
+    // Replaces every entry up to its index
+    snapshot: LogSnapshot,
+    // The entries after the snapshot, in order
+    entries: VecDeque<Entry>,
+    commit: u64,
+    applied: u64,
+    members: Members,
+    votes: BTreeSet<NodeId>,
+    progress: BTreeMap<NodeId, Progress>,
+    // Ticks since the last heartbeat sent or received
+    elapsed: u32,
+    timeout: u32,
+    election_ticks: u32,
+    heartbeat_ticks: u32,
+    outbox: Vec<(NodeId, Message)>,
+    // The chunks of the leader's snapshot received so far
+    receiving: Option<LogSnapshot>,
+    // A snapshot received from the leader, or saved before a restart, for
+    // the state machine to load
+    installed: Option<LogSnapshot>,
+    unsaved: Unsaved,
+}
+
+impl Raft {
+    /// A node starting out with the given members. A node joining an
+    /// existing cluster starts with none, and waits to be added.
+    ///
+    /// The initial members are the first, committed, entries of the log, so
+    /// nodes joining later learn them by replaying it. Every initial member
+    /// has to be started with the same ones.
+    pub fn new(id: NodeId, members: Members, election_ticks: u32, heartbeat_ticks: u32) -> Self {
+        let mut raft = Raft::empty(id, election_ticks, heartbeat_ticks);
+        for (id, address) in members {
+            let index = raft.last_index() + 1;
+            raft.push(Entry {
+                index,
+                term: 0,
+                command: Command::AddNode { id, address },
+            });
+        }
+        raft.commit = raft.last_index();
+        raft.unsaved.hard_state = true;
+        raft
+    }
+
+    /// A node picking up from the state it saved before it stopped. Its
+    /// members are in that state already. Only the entries up to the
+    /// snapshot are known to be committed, so the rest are applied again
+    /// once a leader commits them, after the snapshot is loaded.
+    pub fn recover(id: NodeId, saved: Saved, election_ticks: u32, heartbeat_ticks: u32) -> Self {
+        let mut raft = Raft::empty(id, election_ticks, heartbeat_ticks);
+        raft.term = saved.term;
+        raft.voted_for = saved.voted_for;
+        raft.commit = saved.snapshot.index;
+        raft.applied = saved.snapshot.index;
+        if saved.snapshot.index > 0 {
+            raft.installed = Some(saved.snapshot.clone());
+        }
+        raft.snapshot = saved.snapshot;
+        raft.entries = saved.entries.into();
+        raft.members = raft.members_at(raft.last_index());
+        raft
+    }
+
+    fn empty(id: NodeId, election_ticks: u32, heartbeat_ticks: u32) -> Self {
+        let mut raft = Raft {
+            id,
+            role: Role::Follower,
+            term: 0,
+            voted_for: None,
+            leader: None,
+            snapshot: LogSnapshot {
+                index: 0,
+                term: 0,
+                members: Members::new(),
+                data: Vec::new(),
+            },
+            entries: VecDeque::new(),
+            commit: 0,
+            applied: 0,
+            members: Members::new(),
+            votes: BTreeSet::new(),
+            progress: BTreeMap::new(),
+            elapsed: 0,
+            timeout: 0,
+            election_ticks,
+            heartbeat_ticks,
+            outbox: Vec::new(),
+            receiving: None,
+            installed: None,
+            unsaved: Unsaved::default(),
+        };
+        raft.reset_timer();
+        raft
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn voted_for(&self) -> Option<NodeId> {
+        self.voted_for
+    }
+
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    pub fn members(&self) -> &Members {
+        &self.members
+    }
+
+    pub fn commit(&self) -> u64 {
+        self.commit
+    }
+
+    pub fn applied(&self) -> u64 {
+        self.applied
+    }
+
+    pub fn snapshot(&self) -> &LogSnapshot {
+        &self.snapshot
+    }
+
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot.index
+    }
+
+    /// The entries after the snapshot, in order.
+    pub fn entries(&self) -> &VecDeque<Entry> {
+        &self.entries
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.entries
+            .back()
+            .map_or(self.snapshot.index, |entry| entry.index)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries
+            .back()
+            .map_or(self.snapshot.term, |entry| entry.term)
+    }
+
+    fn entry(&self, index: u64) -> Option<&Entry> {
+        let offset = index.checked_sub(self.snapshot.index + 1)?;
+        self.entries.get(offset as usize)
+    }
+
+    // None once the entry is compacted away, or if there is none yet
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.index {
+            return Some(self.snapshot.term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    fn quorum(&self) -> usize {
+        self.members.len() / 2 + 1
+    }
+
+    fn peers(&self) -> Vec<NodeId> {
+        self.members
+            .keys()
+            .copied()
+            .filter(|id| *id != self.id)
+            .collect()
+    }
+
+    fn reset_timer(&mut self) {
+        self.elapsed = 0;
+        self.timeout = rand::thread_rng().gen_range(self.election_ticks..self.election_ticks * 2);
+    }
+
+    /// Advances time by one tick: leaders send heartbeats, and followers
+    /// that haven't heard from one in a while start an election.
+    pub fn tick(&mut self) {
+        self.elapsed += 1;
+        if self.role == Role::Leader {
+            for progress in self.progress.values_mut() {
+                if let Some(sending) = &mut progress.sending {
+                    sending.waited += 1;
+                }
+            }
+            if self.elapsed >= self.heartbeat_ticks {
+                self.elapsed = 0;
+                self.broadcast();
+            }
+        } else if self.elapsed >= self.timeout && self.members.contains_key(&self.id) {
+            self.campaign();
+        }
+    }
+
+    /// Appends a command to the log, if this node is the leader, and returns
+    /// its index.
+    pub fn propose(&mut self, command: Command) -> Result<u64, ProposeError> {
+        if self.role != Role::Leader {
+            return Err(ProposeError::NoLeader);
+        }
+        // A new leader only knows the membership is settled once it has
+        // committed an entry of its own term
+        if command.changes_members()
+            && (self.term_at(self.commit) != Some(self.term)
+                || self
+                    .entries
+                    .iter()
+                    .any(|entry| entry.index > self.commit && entry.command.changes_members()))
+        {
+            return Err(ProposeError::MembershipChangeInProgress);
+        }
+
+        let index = self.append(command);
+        self.broadcast();
+        Ok(index)
+    }
+
+    pub fn step(&mut self, from: NodeId, message: Message) {
+        match message {
+            Message::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                // Nodes that heard from a leader recently ignore candidates,
+                // so a removed or briefly partitioned node can't disrupt the
+                // cluster
+                if self.leader.is_some() && self.elapsed < self.election_ticks {
+                    return;
+                }
+                if term > self.term {
+                    self.become_follower(term, None);
+                }
+                let up_to_date =
+                    (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let granted = term == self.term
+                    && up_to_date
+                    && !matches!(self.voted_for, Some(vote) if vote != from);
+                if granted {
+                    self.voted_for = Some(from);
+                    self.unsaved.hard_state = true;
+                    self.reset_timer();
+                }
+                self.send(
+                    from,
+                    Message::Vote {
+                        term: self.term,
+                        granted,
+                    },
+                );
+            }
+            Message::Vote { term, granted } => {
+                if term > self.term {
+                    self.become_follower(term, None);
+                    return;
+                }
+                if self.role != Role::Candidate || term != self.term || !granted {
+                    return;
+                }
+                self.votes.insert(from);
+                let votes = self
+                    .votes
+                    .iter()
+                    .filter(|id| self.members.contains_key(id))
+                    .count();
+                if votes >= self.quorum() {
+                    self.become_leader();
+                }
+            }
+            Message::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                if term < self.term {
+                    self.send(
+                        from,
+                        Message::AppendResult {
+                            term: self.term,
+                            success: false,
+                            last_index: self.last_index(),
+                        },
+                    );
+                    return;
+                }
+                self.become_follower(term, Some(from));
+
+                // Entries up to the snapshot are committed, so they match
+                if prev_log_index >= self.snapshot.index
+                    && self.term_at(prev_log_index) != Some(prev_log_term)
+                {
+                    let last_index = self.last_index().min(prev_log_index.saturating_sub(1));
+                    self.send(
+                        from,
+                        Message::AppendResult {
+                            term: self.term,
+                            success: false,
+                            last_index,
+                        },
+                    );
+                    return;
+                }
+
+                let matched = prev_log_index + entries.len() as u64;
+                for entry in entries {
+                    match self.term_at(entry.index) {
+                        _ if entry.index <= self.snapshot.index => {}
+                        Some(term) if term == entry.term => {}
+                        Some(_) => {
+                            self.truncate(entry.index);
+                            self.push(entry);
+                        }
+                        None => self.push(entry),
+                    }
+                }
+
+                let matched = matched.max(self.snapshot.index);
+                if leader_commit > self.commit {
+                    self.commit = self.commit.max(leader_commit.min(matched));
+                }
+                self.send(
+                    from,
+                    Message::AppendResult {
+                        term: self.term,
+                        success: true,
+                        last_index: matched,
+                    },
+                );
+            }
+            Message::AppendResult {
+                term,
+                success,
+                last_index,
+            } => {
+                if term > self.term {
+                    self.become_follower(term, None);
+                    return;
+                }
+                if self.role != Role::Leader || term != self.term {
+                    return;
+                }
+                let Some(progress) = self.progress.get_mut(&from) else {
+                    return;
+                };
+                if success {
+                    progress.matched = progress.matched.max(last_index);
+                    progress.next = progress.next.max(last_index + 1);
+                    let behind = progress.next <= self.last_index();
+                    self.maybe_commit();
+                    if behind {
+                        self.send_append(from);
+                    }
+                } else {
+                    progress.next = (last_index + 1)
+                        .min(progress.next.saturating_sub(1))
+                        .max(progress.matched + 1);
+                    self.send_append(from);
+                }
+            }
+            Message::InstallSnapshot {
+                term,
+                index,
+                snapshot_term,
+                members,
+                offset,
+                data,
+                done,
+            } => {
+                if term < self.term {
+                    self.send(
+                        from,
+                        Message::SnapshotInstalled {
+                            term: self.term,
+                            index: 0,
+                        },
+                    );
+                    return;
+                }
+                self.become_follower(term, Some(from));
+                if index <= self.commit {
+                    self.send(
+                        from,
+                        Message::SnapshotInstalled {
+                            term: self.term,
+                            index,
+                        },
+                    );
+                    return;
+                }
+
+                // chunks are taken in order, and anything else is answered
+                // with where to carry on from
+                let mut receiving = match self.receiving.take() {
+                    Some(receiving)
+                        if receiving.index == index && receiving.term == snapshot_term =>
+                    {
+                        receiving
+                    }
+                    _ => LogSnapshot {
+                        index,
+                        term: snapshot_term,
+                        members: Members::new(),
+                        data: Vec::new(),
+                    },
+                };
+                if offset == receiving.data.len() as u64 {
+                    receiving.data.extend(data);
+                    if done {
+                        receiving.members = members;
+                        self.install(receiving);
+                        self.send(
+                            from,
+                            Message::SnapshotInstalled {
+                                term: self.term,
+                                index,
+                            },
+                        );
+                        return;
+                    }
+                }
+                let received = receiving.data.len() as u64;
+                self.receiving = Some(receiving);
+                self.send(
+                    from,
+                    Message::SnapshotReceived {
+                        term: self.term,
+                        index,
+                        received,
+                    },
+                );
+            }
+            Message::SnapshotReceived {
+                term,
+                index,
+                received,
+            } => {
+                if term > self.term {
+                    self.become_follower(term, None);
+                    return;
+                }
+                if self.role != Role::Leader || term != self.term {
+                    return;
+                }
+                let snapshot_index = self.snapshot.index;
+                let Some(progress) = self.progress.get_mut(&from) else {
+                    return;
+                };
+                // an answer to a chunk sent again is already taken care of
+                match &mut progress.sending {
+                    Some(sending)
+                        if sending.index == index
+                            && index == snapshot_index
+                            && sending.offset != received =>
+                    {
+                        sending.offset = received;
+                    }
+                    _ => return,
+                }
+                self.send_chunk(from);
+            }
+            Message::SnapshotInstalled { term, index } => {
+                if term > self.term {
+                    self.become_follower(term, None);
+                    return;
+                }
+                if self.role != Role::Leader || term != self.term {
+                    return;
+                }
+                let Some(progress) = self.progress.get_mut(&from) else {
+                    return;
+                };
+                progress.sending = None;
+                progress.matched = progress.matched.max(index);
+                progress.next = progress.next.max(index + 1);
+                let behind = progress.next <= self.last_index();
+                self.maybe_commit();
+                if behind {
+                    self.send_append(from);
+                }
+            }
+            // handled by the node, which applies the commands
+            Message::Forward { .. } | Message::Forwarded { .. } => {}
+        }
+    }
+
+    /// The messages to send since the last call.
+    pub fn take_messages(&mut self) -> Vec<(NodeId, Message)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// A snapshot received from the leader, which the state machine has to
+    /// load before applying any further entries.
+    pub fn take_installed(&mut self) -> Option<LogSnapshot> {
+        self.installed.take()
+    }
+
+    /// What has to be saved since the last call, before the messages are
+    /// sent or the committed entries applied.
+    pub fn take_unsaved(&mut self) -> Unsaved {
+        std::mem::take(&mut self.unsaved)
+    }
+
+    /// The entries committed since the last call, to apply in order.
+    pub fn take_committed(&mut self) -> Vec<Entry> {
+        let committed = (self.applied + 1..=self.commit)
+            .filter_map(|index| self.entry(index).cloned())
+            .collect();
+        self.applied = self.commit;
+        committed
+    }
+
+    /// A snapshot to replace the applied entries up to `index` with, once
+    /// the state machine adds its data as of that index.
+    pub fn snapshot_at(&self, index: u64) -> Option<LogSnapshot> {
+        if index <= self.snapshot.index || index > self.applied {
+            return None;
+        }
+        Some(LogSnapshot {
+            index,
+            term: self.term_at(index)?,
+            members: self.members_at(index),
+            data: Vec::new(),
+        })
+    }
+
+    /// Replaces the entries the snapshot covers with it, unless a newer one
+    /// replaced them in the meantime, and returns whether it did.
+    pub fn compact(&mut self, snapshot: LogSnapshot) -> bool {
+        if snapshot.index <= self.snapshot.index
+            || self.term_at(snapshot.index) != Some(snapshot.term)
+        {
+            return false;
+        }
+        while self
+            .entries
+            .front()
+            .is_some_and(|entry| entry.index <= snapshot.index)
+        {
+            self.entries.pop_front();
+        }
+        self.snapshot = snapshot;
+        self.unsaved.snapshot = true;
+        true
+    }
+
+    fn send(&mut self, to: NodeId, message: Message) {
+        self.outbox.push((to, message));
+    }
+
+    // Loads a snapshot from the leader in place of the entries it covers,
+    // keeping those after it only if they follow on from it
+    fn install(&mut self, snapshot: LogSnapshot) {
+        let index = snapshot.index;
+        if self.term_at(index) == Some(snapshot.term) {
+            while self
+                .entries
+                .front()
+                .is_some_and(|entry| entry.index <= index)
+            {
+                self.entries.pop_front();
+            }
+        } else {
+            self.entries.clear();
+        }
+        self.commit = index;
+        self.applied = index;
+        self.snapshot = snapshot;
+        self.members = self.members_at(self.last_index());
+        self.installed = Some(self.snapshot.clone());
+        self.unsaved.snapshot = true;
+    }
+
+    fn campaign(&mut self) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.id);
+        self.unsaved.hard_state = true;
+        self.leader = None;
+        self.votes = BTreeSet::from([self.id]);
+        self.reset_timer();
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+            return;
+        }
+
+        let message = Message::RequestVote {
+            term: self.term,
+            last_log_index: self.last_index(),
+            last_log_term: self.last_term(),
+        };
+        for peer in self.peers() {
+            self.send(peer, message.clone());
+        }
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.unsaved.hard_state = true;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.votes.clear();
+        self.progress.clear();
+        self.reset_timer();
+    }
+
+    fn become_leader(&mut self) {
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.elapsed = 0;
+        self.progress.clear();
+        self.append(Command::Noop);
+        self.broadcast();
+    }
+
+    // Appends a command of the current term as the leader
+    fn append(&mut self, command: Command) -> u64 {
+        let index = self.last_index() + 1;
+        self.push(Entry {
+            index,
+            term: self.term,
+            command,
+        });
+
+        // track exactly the current members, starting new ones at this entry
+        let next = index;
+        let peers = self.peers();
+        self.progress.retain(|id, _| peers.contains(id));
+        for peer in peers {
+            self.progress.entry(peer).or_insert(Progress {
+                next,
+                matched: 0,
+                sending: None,
+            });
+        }
+        self.maybe_commit();
+        index
+    }
+
+    fn push(&mut self, entry: Entry) {
+        change_members(&mut self.members, &entry.command);
+        self.changed_from(entry.index);
+        self.entries.push_back(entry);
+    }
+
+    // Drops the entries from `index` on, and any membership changes in them
+    fn truncate(&mut self, index: u64) {
+        self.changed_from(index);
+        while self
+            .entries
+            .back()
+            .is_some_and(|entry| entry.index >= index)
+        {
+            self.entries.pop_back();
+        }
+        self.members = self.members_at(self.last_index());
+    }
+
+    fn changed_from(&mut self, index: u64) {
+        let from = self
+            .unsaved
+            .entries_from
+            .map_or(index, |from| from.min(index));
+        self.unsaved.entries_from = Some(from);
+    }
+
+    fn members_at(&self, index: u64) -> Members {
+        let mut members = self.snapshot.members.clone();
+        for entry in self.entries.iter().take_while(|entry| entry.index <= index) {
+            change_members(&mut members, &entry.command);
+        }
+        members
+    }
+
+    fn broadcast(&mut self) {
+        for peer in self.peers() {
+            self.send_append(peer);
+        }
+    }
+
+    fn send_append(&mut self, peer: NodeId) {
+        let Some(progress) = self.progress.get(&peer).copied() else {
+            return;
+        };
+        if progress.next <= self.snapshot.index {
+            // a chunk is only sent again if it goes unanswered for long
+            // enough, which is still before the follower would time out
+            let in_flight = progress.sending.is_some_and(|sending| {
+                sending.index == self.snapshot.index && sending.waited < self.election_ticks / 2
+            });
+            if !in_flight {
+                self.send_chunk(peer);
+            }
+            return;
+        }
+
+        let prev_log_index = progress.next - 1;
+        let entries = (progress.next..=self.last_index())
+            .take(MAX_APPEND_ENTRIES)
+            .filter_map(|index| self.entry(index).cloned())
+            .collect();
+        let message = Message::AppendEntries {
+            term: self.term,
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index).unwrap_or_default(),
+            entries,
+            leader_commit: self.commit,
+        };
+        self.send(peer, message);
+    }
+
+    // Sends the chunk of the snapshot after what the follower acknowledged
+    fn send_chunk(&mut self, peer: NodeId) {
+        let index = self.snapshot.index;
+        let Some(progress) = self.progress.get_mut(&peer) else {
+            return;
+        };
+        let offset = match progress.sending {
+            Some(sending) if sending.index == index => sending.offset,
+            _ => 0,
+        };
+        progress.sending = Some(Sending {
+            index,
+            offset,
+            waited: 0,
+        });
+
+        let start = (offset as usize).min(self.snapshot.data.len());
+        let end = (start + SNAPSHOT_CHUNK_SIZE).min(self.snapshot.data.len());
+        let message = Message::InstallSnapshot {
+            term: self.term,
+            index,
+            snapshot_term: self.snapshot.term,
+            members: self.snapshot.members.clone(),
+            offset: start as u64,
+            data: self.snapshot.data[start..end].to_vec(),
+            done: end == self.snapshot.data.len(),
+        };
+        self.send(peer, message);
+    }
+
+    // Commits the newest entry of this term that a majority has
+    fn maybe_commit(&mut self) {
+        if self.role != Role::Leader {
+            return;
+        }
+        let mut matched: Vec<u64> = self
+            .members
+            .keys()
+            .map(|id| match self.progress.get(id) {
+                _ if *id == self.id => self.last_index(),
+                Some(progress) => progress.matched,
+                None => 0,
+            })
+            .collect();
+        if matched.is_empty() {
+            return;
+        }
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.quorum() - 1];
+        if index > self.commit && self.term_at(index) == Some(self.term) {
+            self.commit = index;
+        }
+
+        // A leader that removed itself hands over once that is committed
+        if !self.members.contains_key(&self.id)
+            && !self
+                .entries
+                .iter()
+                .any(|entry| entry.index > self.commit && entry.command.changes_members())
+        {
+            self.broadcast();
+            self.become_follower(self.term, None);
+        }
+    }
+}
+
+fn change_members(members: &mut Members, command: &Command) {
+    match command {
+        Command::AddNode { id, address } => {
+            members.insert(*id, address.clone());
+        }
+        Command::RemoveNode { id } => {
+            members.remove(id);
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(ids: &[NodeId]) -> Members {
+        ids.iter().map(|id| (*id, String::new())).collect()
+    }
+
+    // Delivers every message until there are none left
+    fn deliver(nodes: &mut [Raft]) {
+        loop {
+            let mut messages = Vec::new();
+            for node in nodes.iter_mut() {
+                let from = node.id();
+                messages.extend(
+                    node.take_messages()
+                        .into_iter()
+                        .map(|(to, message)| (from, to, message)),
+                );
+            }
+            if messages.is_empty() {
+                return;
+            }
+            for (from, to, message) in messages {
+                if let Some(node) = nodes.iter_mut().find(|node| node.id() == to) {
+                    node.step(from, message);
+                }
+            }
+        }
+    }
+
+    fn elect(nodes: &mut [Raft], leader: usize) {
+        while nodes[leader].role() == Role::Follower {
+            nodes[leader].tick();
+        }
+        deliver(nodes);
+        assert_eq!(nodes[leader].role(), Role::Leader);
+    }
+
+    fn delete(key: &str) -> Command {
+        Command::Delete {
+            pattern: key.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_replicates_and_commits() {
+        let all = members(&[1, 2, 3]);
+        let mut nodes: Vec<Raft> = (1..=3)
+            .map(|id| Raft::new(id, all.clone(), 10, 2))
+            .collect();
+        elect(&mut nodes, 0);
+        assert!(nodes.iter().all(|node| node.leader() == Some(1)));
+
+        let index = nodes[0].propose(delete("a")).unwrap();
+        assert_eq!(nodes[1].propose(delete("b")), Err(ProposeError::NoLeader));
+        deliver(&mut nodes);
+        assert_eq!(nodes[0].commit(), index);
+
+        // followers learn the commit index with the next heartbeat, after
+        // the initial members every node starts with
+        nodes[0].tick();
+        nodes[0].tick();
+        deliver(&mut nodes);
+        let mut expected: Vec<Command> = all
+            .iter()
+            .map(|(id, address)| Command::AddNode {
+                id: *id,
+                address: address.clone(),
+            })
+            .collect();
+        expected.extend([Command::Noop, delete("a")]);
+        for node in &mut nodes {
+            let commands: Vec<Command> = node
+                .take_committed()
+                .into_iter()
+                .map(|entry| entry.command)
+                .collect();
+            assert_eq!(commands, expected);
+        }
+    }
+
+    #[test]
+    fn test_join_replays_log() {
+        let mut nodes = vec![Raft::new(1, members(&[1]), 10, 2)];
+        elect(&mut nodes, 0);
+        nodes.push(Raft::new(2, Members::new(), 10, 2));
+        nodes[0]
+            .propose(Command::AddNode {
+                id: 2,
+                address: String::new(),
+            })
+            .unwrap();
+        deliver(&mut nodes);
+
+        // the initial members come with the log, so the new node doesn't
+        // take itself for the only one
+        assert_eq!(nodes[1].members(), &members(&[1, 2]));
+        assert_eq!(nodes[1].last_index(), nodes[0].last_index());
+        assert_eq!(nodes[0].commit(), nodes[0].last_index());
+    }
+
+    #[test]
+    fn test_overwrites_uncommitted_entries() {
+        let all = members(&[1, 2, 3]);
+        let mut nodes: Vec<Raft> = (1..=3)
+            .map(|id| Raft::new(id, all.clone(), 10, 2))
+            .collect();
+        elect(&mut nodes, 0);
+
+        // appended by the leader alone before it is cut off
+        nodes[0].propose(delete("lost")).unwrap();
+        nodes[0].take_messages();
+
+        // the others stop hearing from it and elect a new leader, whose
+        // entries win
+        for node in &mut nodes[1..] {
+            node.leader = None;
+        }
+        elect(&mut nodes[1..], 0);
+        nodes[1].propose(delete("kept")).unwrap();
+        deliver(&mut nodes);
+        nodes[1].tick();
+        nodes[1].tick();
+        deliver(&mut nodes);
+
+        assert_eq!(nodes[0].role(), Role::Follower);
+        assert_eq!(nodes[0].leader(), Some(2));
+        let commands: Vec<Command> = nodes[0]
+            .take_committed()
+            .into_iter()
+            .map(|entry| entry.command)
+            .collect();
+        assert!(commands.contains(&delete("kept")));
+        assert!(!commands.contains(&delete("lost")));
+    }
+
+    #[test]
+    fn test_snapshot_install() {
+        let mut nodes = vec![Raft::new(1, members(&[1]), 10, 2)];
+        elect(&mut nodes, 0);
+        for key in ["a", "b", "c"] {
+            nodes[0].propose(delete(key)).unwrap();
+        }
+        let applied = nodes[0].take_committed().last().unwrap().index;
+        let mut snapshot = nodes[0].snapshot_at(applied).unwrap();
+        snapshot.data = b"state".to_vec();
+        assert!(nodes[0].compact(snapshot));
+        assert_eq!(nodes[0].snapshot_index(), applied);
+
+        // a new node only catches up through the snapshot
+        nodes.push(Raft::new(2, Members::new(), 10, 2));
+        nodes[0]
+            .propose(Command::AddNode {
+                id: 2,
+                address: String::new(),
+            })
+            .unwrap();
+        deliver(&mut nodes);
+
+        let installed = nodes[1].take_installed().unwrap();
+        assert_eq!(installed.data, b"state");
+        assert_eq!(nodes[1].members(), &members(&[1, 2]));
+        assert_eq!(nodes[1].last_index(), nodes[0].last_index());
+        assert_eq!(nodes[0].commit(), nodes[0].last_index());
+    }
+
+    #[test]
+    fn test_snapshot_sent_in_chunks() {
+        let mut nodes = vec![Raft::new(1, members(&[1]), 10, 2)];
+        elect(&mut nodes, 0);
+        nodes[0].propose(delete("a")).unwrap();
+        let applied = nodes[0].take_committed().last().unwrap().index;
+        let data: Vec<u8> = (0..SNAPSHOT_CHUNK_SIZE * 3 + 1).map(|i| i as u8).collect();
+        let mut snapshot = nodes[0].snapshot_at(applied).unwrap();
+        snapshot.data = data.clone();
+        assert!(nodes[0].compact(snapshot));
+
+        nodes.push(Raft::new(2, Members::new(), 10, 2));
+        nodes[0]
+            .propose(Command::AddNode {
+                id: 2,
+                address: String::new(),
+            })
+            .unwrap();
+        let chunks = |messages: &[(NodeId, Message)]| -> Vec<u64> {
+            messages
+                .iter()
+                .filter_map(|(_, message)| match message {
+                    Message::InstallSnapshot { offset, .. } => Some(*offset),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // the new node turns down the entries, so it is sent the snapshot
+        for (_, message) in nodes[0].take_messages() {
+            nodes[1].step(1, message);
+        }
+        for (_, message) in nodes[1].take_messages() {
+            nodes[0].step(2, message);
+        }
+        assert_eq!(chunks(&nodes[0].take_messages()), vec![0]);
+
+        // heartbeats don't send the chunk again while it may still arrive,
+        // but one that got lost is sent again before the follower times out
+        nodes[0].tick();
+        nodes[0].tick();
+        assert!(chunks(&nodes[0].take_messages()).is_empty());
+        let mut resent = Vec::new();
+        for _ in 0..8 {
+            nodes[0].tick();
+            resent.extend(nodes[0].take_messages());
+        }
+        assert_eq!(chunks(&resent), vec![0]);
+
+        // the follower installs it once every chunk arrived
+        for (_, message) in resent {
+            nodes[1].step(1, message);
+        }
+        deliver(&mut nodes);
+        let installed = nodes[1].take_installed().unwrap();
+        assert_eq!(installed.data, data);
+        assert_eq!(nodes[1].members(), &members(&[1, 2]));
+        assert_eq!(nodes[1].last_index(), nodes[0].last_index());
+        assert_eq!(nodes[0].commit(), nodes[0].last_index());
+    }
+}
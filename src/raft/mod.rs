@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::nestedmap::options::Precondition;
+
+pub mod core;
+pub mod network;
+pub mod node;
+pub mod storage;
+
+pub use self::core::Role;
+pub use network::{LocalNetwork, Transport};
+pub use node::{Node, RaftConfig, Status};
+pub use storage::Storage;
+
+/// Identifies a node for the lifetime of the cluster. A node that lost its
+/// saved state has to rejoin under a new id.
+pub type NodeId = u64;
+
+/// The cluster's members and the addresses their transport reaches them at.
+pub type Members = BTreeMap<NodeId, String>;
+
+/// A mutation of the datastore, or of the cluster, as stored in the log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Command {
+    // Appended by every new leader, to commit entries from earlier terms
+    Noop,
+    Set {
+        key: String,
+        value: Vec<u8>,
+        preserve_history: bool,
+        precondition: Option<Precondition>,
+        // Taken by the node that proposed it, so every node stores the same
+        timestamp: SystemTime,
+    },
+    Delete {
+        pattern: String,
+    },
+    AddNode {
+        id: NodeId,
+        address: String,
+    },
+    RemoveNode {
+        id: NodeId,
+    },
+}
+
+impl Command {
+    fn changes_members(&self) -> bool {
+        matches!(self, Command::AddNode { .. } | Command::RemoveNode { .. })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub index: u64,
+    pub term: u64,
+    pub command: Command,
+}
+
+/// The state machine as of `index`, replacing every entry up to it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogSnapshot {
+    pub index: u64,
+    pub term: u64,
+    pub members: Members,
+    // The datastore's items, encoded by the node
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Message {
+    RequestVote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    },
+    // On failure, `last_index` is the follower's last entry, so the leader
+    // can skip straight back to it
+    AppendResult {
+        term: u64,
+        success: bool,
+        last_index: u64,
+    },
+    // One chunk of the leader's snapshot, starting `offset` bytes into its
+    // data; the last one is marked `done`
+    InstallSnapshot {
+        term: u64,
+        index: u64,
+        snapshot_term: u64,
+        members: Members,
+        offset: u64,
+        data: Vec<u8>,
+        done: bool,
+    },
+    // How much of the snapshot's data the follower holds, so the leader
+    // sends the chunk after it
+    SnapshotReceived {
+        term: u64,
+        index: u64,
+        received: u64,
+    },
+    SnapshotInstalled {
+        term: u64,
+        index: u64,
+    },
+    // A follower passing a client's command on to the leader
+    Forward {
+        request: u64,
+        command: Command,
+    },
+    Forwarded {
+        request: u64,
+        result: Result<Applied, ProposeError>,
+    },
+}
+
+/// A message on its way between two nodes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub from: NodeId,
+    // Where the sender is reached, so a node that is joining can answer
+    // before it learns the members
+    pub from_address: String,
+    pub to: NodeId,
+    pub message: Message,
+}
+
+/// The outcome of applying a committed command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Applied {
+    // Whether a set's precondition held
+    Written(bool),
+    // How many keys a delete removed
+    Deleted(usize),
+    Done,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProposeError {
+    // No leader is known yet, e.g. during an election
+    NoLeader,
+    // Leadership changed before the command was committed
+    LostLeadership,
+    // Only one membership change can be in flight at a time, and a new
+    // leader has to commit an entry of its own first
+    MembershipChangeInProgress,
+    // No result within the proposal timeout; the command may still apply
+    Timeout,
+    Stopped,
+}
+
+impl fmt::Display for ProposeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProposeError::NoLeader => write!(f, "no leader is known"),
+            ProposeError::LostLeadership => {
+                write!(f, "leadership changed before the command was committed")
+            }
+            ProposeError::MembershipChangeInProgress => {
+                write!(f, "another membership change is in progress")
+            }
+            ProposeError::Timeout => write!(f, "timed out waiting for the command to apply"),
+            ProposeError::Stopped => write!(f, "the node has stopped"),
+        }
+    }
+}
+
+impl std::error::Error for ProposeError {}
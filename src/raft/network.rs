@@ -0,0 +1,54 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use super::node::Node;
+use super::{Envelope, NodeId};
+
+/// Carries messages between nodes. Sending never waits for delivery, and a
+/// message may be lost; Raft retries whatever matters.
+pub trait Transport: Send + Sync + 'static {
+    /// Sends to the node at `address`, as given when it was added.
+    fn send(&self, address: &str, envelope: Envelope);
+}
+
+/// Connects nodes running in the same process, e.g. in tests, under the
+/// addresses they were registered with. Nodes can be cut off from the rest
+/// to simulate failures.
+#[derive(Debug, Default)]
+pub struct LocalNetwork {
+    nodes: Mutex<HashMap<String, Node>>,
+    isolated: Mutex<HashSet<NodeId>>,
+}
+
+impl LocalNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, address: &str, node: Node) {
+        self.nodes.lock().unwrap().insert(address.to_string(), node);
+    }
+
+    /// Drops every message to or from the node until it is healed.
+    pub fn isolate(&self, id: NodeId) {
+        self.isolated.lock().unwrap().insert(id);
+    }
+
+    pub fn heal(&self, id: NodeId) {
+        self.isolated.lock().unwrap().remove(&id);
+    }
+}
+
+impl Transport for LocalNetwork {
+    fn send(&self, address: &str, envelope: Envelope) {
+        {
+            let isolated = self.isolated.lock().unwrap();
+            if isolated.contains(&envelope.from) || isolated.contains(&envelope.to) {
+                return;
+            }
+        }
+        if let Some(node) = self.nodes.lock().unwrap().get(address) {
+            node.receive(envelope);
+        }
+    }
+}
@@ -0,0 +1,772 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use log::error;
+use tokio::sync::{mpsc, oneshot, watch};
+
+use super::core::{Raft, Role};
+use super::network::Transport;
+use super::storage::Storage;
+use super::{
+    Applied, Command, Entry, Envelope, LogSnapshot, Members, Message, NodeId, ProposeError,
+};
+use crate::datastore::changes::{Change, ChangeKind};
+use crate::datastore::Datastore;
+use crate::nestedmap::options::{GetOptions, SetOptions};
+use crate::nestedmap::Item;
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    pub tick: Duration,
+    // A follower that hears nothing from a leader for between one and two
+    // times this many ticks starts an election
+    pub election_ticks: u32,
+    pub heartbeat_ticks: u32,
+    // Applied entries kept in the log before they are replaced by a
+    // snapshot of the datastore
+    pub snapshot_threshold: u64,
+    pub proposal_timeout: Duration,
+    // Where the node keeps its term, vote and log, so that it can restart
+    // under the same id; kept in memory only if unset
+    pub state_dir: Option<PathBuf>,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        RaftConfig {
+            tick: Duration::from_millis(50),
+            election_ticks: 10,
+            heartbeat_ticks: 2,
+            snapshot_threshold: 10_000,
+            proposal_timeout: Duration::from_secs(5),
+            state_dir: None,
+        }
+    }
+}
+
+/// Where a node stands, as of its last step.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    pub id: NodeId,
+    pub role: Role,
+    pub term: u64,
+    pub leader: Option<NodeId>,
+    pub members: Members,
+    pub commit: u64,
+    pub applied: u64,
+}
+
+#[derive(Debug)]
+enum Input {
+    Message(Envelope),
+    Propose {
+        command: Command,
+        reply: oneshot::Sender<Result<Applied, ProposeError>>,
+    },
+    Stop,
+}
+
+/// A handle on a node of a Raft cluster, which replicates every write to
+/// its datastore through the cluster's log. Writes can be made on any node;
+/// followers forward them to the leader, and they return once applied
+/// locally, after a majority stored them. Reads go straight to the node's
+/// datastore, which can lag behind the leader's.
+///
+/// Item versions are the index of the entry that wrote them, so they are
+/// the same on every node. Writes with a ttl aren't supported: each node's
+/// clock would expire items at a different point in the log.
+///
+/// A node saves its term, vote and log in its state directory before it
+/// answers any other node, so that it can restart under the same id. One
+/// that keeps them in memory only has to rejoin under a new id.
+#[derive(Debug, Clone)]
+pub struct Node {
+    id: NodeId,
+    inputs: mpsc::UnboundedSender<Input>,
+    status: watch::Receiver<Status>,
+    datastore: Arc<Datastore>,
+    proposal_timeout: Duration,
+}
+
+impl Node {
+    /// Starts a node with the cluster's initial members, or with none to
+    /// join an existing cluster once a member adds it. A node that saved
+    /// its state in the state directory before picks up from there instead,
+    /// and ignores the members.
+    pub fn start(
+        id: NodeId,
+        members: Members,
+        datastore: Arc<Datastore>,
+        transport: Arc<dyn Transport>,
+        config: RaftConfig,
+    ) -> io::Result<Node> {
+        let (storage, saved) = match &config.state_dir {
+            Some(dir) => {
+                let (storage, saved) = Storage::open(dir)?;
+                (Some(storage), saved)
+            }
+            None => (None, None),
+        };
+        let raft = match saved {
+            Some(saved) => Raft::recover(id, saved, config.election_ticks, config.heartbeat_ticks),
+            None => Raft::new(id, members, config.election_ticks, config.heartbeat_ticks),
+        };
+        let (inputs, receiver) = mpsc::unbounded_channel();
+        let (snapshots, snapshot_receiver) = mpsc::unbounded_channel();
+        let (status, status_receiver) = watch::channel(status(&raft));
+
+        let driver = Driver {
+            raft,
+            storage,
+            datastore: datastore.clone(),
+            transport,
+            config: config.clone(),
+            status,
+            pending: BTreeMap::new(),
+            forwards: HashMap::new(),
+            next_request: 0,
+            senders: HashMap::new(),
+            snapshots,
+            taking_snapshot: false,
+        };
+        tokio::spawn(driver.run(receiver, snapshot_receiver));
+
+        Ok(Node {
+            id,
+            inputs,
+            status: status_receiver,
+            datastore,
+            proposal_timeout: config.proposal_timeout,
+        })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// The node's copy of the datastore, for reads.
+    pub fn datastore(&self) -> &Arc<Datastore> {
+        &self.datastore
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.borrow().clone()
+    }
+
+    /// Notified whenever the status changes.
+    pub fn watch_status(&self) -> watch::Receiver<Status> {
+        self.status.clone()
+    }
+
+    /// Hands the node a message from another node.
+    pub fn receive(&self, envelope: Envelope) {
+        let _ = self.inputs.send(Input::Message(envelope));
+    }
+
+    /// Commits the command to the cluster's log and waits for this node to
+    /// apply it.
+    pub async fn propose(&self, command: Command) -> Result<Applied, ProposeError> {
+        let (reply, result) = oneshot::channel();
+        self.inputs
+            .send(Input::Propose { command, reply })
+            .map_err(|_| ProposeError::Stopped)?;
+
+        match tokio::time::timeout(self.proposal_timeout, result).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(ProposeError::Stopped),
+            Err(_) => Err(ProposeError::Timeout),
+        }
+    }
+
+    /// Like `Datastore::set`, but for the whole cluster. The options' ttl is
+    /// ignored.
+    pub async fn set(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        options: Option<SetOptions>,
+    ) -> Result<bool, ProposeError> {
+        let options = options.unwrap_or_default();
+        let command = Command::Set {
+            key,
+            value,
+            preserve_history: options.preserve_history,
+            precondition: options.precondition,
+            timestamp: SystemTime::now(),
+        };
+        let applied = self.propose(command).await?;
+        Ok(applied == Applied::Written(true))
+    }
+
+    /// Deletes every key matching the pattern across the cluster, and
+    /// returns how many were deleted.
+    pub async fn delete(&self, pattern: String) -> Result<usize, ProposeError> {
+        match self.propose(Command::Delete { pattern }).await? {
+            Applied::Deleted(count) => Ok(count),
+            _ => Ok(0),
+        }
+    }
+
+    /// Stops the node, as if its process exited, and waits for it to. Its
+    /// handles fail every proposal from then on.
+    pub async fn stop(&self) {
+        if self.inputs.send(Input::Stop).is_ok() {
+            self.inputs.closed().await;
+        }
+    }
+
+    /// Adds a node, reachable at `address` through the transport.
+    pub async fn add_node(&self, id: NodeId, address: String) -> Result<(), ProposeError> {
+        self.propose(Command::AddNode { id, address }).await?;
+        Ok(())
+    }
+
+    pub async fn remove_node(&self, id: NodeId) -> Result<(), ProposeError> {
+        self.propose(Command::RemoveNode { id }).await?;
+        Ok(())
+    }
+}
+
+fn status(raft: &Raft) -> Status {
+    Status {
+        id: raft.id(),
+        role: raft.role(),
+        term: raft.term(),
+        leader: raft.leader(),
+        members: raft.members().clone(),
+        commit: raft.commit(),
+        applied: raft.applied(),
+    }
+}
+
+// Who is waiting for a command's result
+#[derive(Debug)]
+enum Reply {
+    Local(oneshot::Sender<Result<Applied, ProposeError>>),
+    // A follower that forwarded the command
+    Remote { node: NodeId, request: u64 },
+}
+
+// Owns the node's Raft state, feeding it ticks, messages and proposals, and
+// applies what it commits to the datastore. It is the only writer to the
+// datastore.
+struct Driver {
+    raft: Raft,
+    storage: Option<Storage>,
+    datastore: Arc<Datastore>,
+    transport: Arc<dyn Transport>,
+    config: RaftConfig,
+    status: watch::Sender<Status>,
+    // Proposed here, by log index, with the term they were proposed in
+    pending: BTreeMap<u64, (u64, Reply)>,
+    // Forwarded to the leader, by request
+    forwards: HashMap<u64, oneshot::Sender<Result<Applied, ProposeError>>>,
+    next_request: u64,
+    // Where messages came from, to answer nodes that aren't members as far
+    // as this node knows, e.g. a leader that is removing itself
+    senders: HashMap<NodeId, String>,
+    // Where snapshots of the datastore taken away from the driver are sent
+    snapshots: mpsc::UnboundedSender<io::Result<LogSnapshot>>,
+    taking_snapshot: bool,
+}
+
+impl Driver {
+    async fn run(
+        mut self,
+        mut inputs: mpsc::UnboundedReceiver<Input>,
+        mut snapshots: mpsc::UnboundedReceiver<io::Result<LogSnapshot>>,
+    ) {
+        let mut ticker = tokio::time::interval(self.config.tick);
+        loop {
+            tokio::select! {
+                input = inputs.recv() => match input {
+                    Some(Input::Stop) | None => break,
+                    Some(input) => self.handle(input),
+                },
+                Some(snapshot) = snapshots.recv() => self.snapshot_taken(snapshot),
+                _ = ticker.tick() => {
+                    self.raft.tick();
+                    // forwards whose callers gave up
+                    self.forwards.retain(|_, reply| !reply.is_closed());
+                }
+            }
+            if let Err(e) = self.advance().await {
+                // anything sent from here on could contradict what was
+                // acknowledged before a restart
+                error!("Failed to save the Raft state, stopping: {}", e);
+                break;
+            }
+        }
+    }
+
+    fn handle(&mut self, input: Input) {
+        match input {
+            Input::Message(envelope) => {
+                if !envelope.from_address.is_empty() {
+                    self.senders
+                        .insert(envelope.from, envelope.from_address.clone());
+                }
+                self.step(envelope)
+            }
+            Input::Propose { command, reply } => self.propose(command, Reply::Local(reply)),
+            Input::Stop => {}
+        }
+    }
+
+    fn step(&mut self, envelope: Envelope) {
+        match envelope.message {
+            Message::Forward { request, command } => {
+                let reply = Reply::Remote {
+                    node: envelope.from,
+                    request,
+                };
+                self.propose(command, reply);
+            }
+            Message::Forwarded { request, result } => {
+                if let Some(reply) = self.forwards.remove(&request) {
+                    let _ = reply.send(result);
+                }
+            }
+            message => self.raft.step(envelope.from, message),
+        }
+    }
+
+    fn propose(&mut self, command: Command, reply: Reply) {
+        if self.raft.role() == Role::Leader {
+            match self.raft.propose(command) {
+                Ok(index) => {
+                    self.pending.insert(index, (self.raft.term(), reply));
+                }
+                Err(e) => self.reply(reply, Err(e)),
+            }
+            return;
+        }
+
+        // only commands from this node's callers are forwarded, so a command
+        // never goes round in circles
+        match (self.raft.leader(), reply) {
+            (Some(leader), Reply::Local(reply)) => {
+                let request = self.next_request;
+                self.next_request += 1;
+                self.forwards.insert(request, reply);
+                self.send(leader, Message::Forward { request, command });
+            }
+            (_, reply) => self.reply(reply, Err(ProposeError::NoLeader)),
+        }
+    }
+
+    fn reply(&mut self, reply: Reply, result: Result<Applied, ProposeError>) {
+        match reply {
+            Reply::Local(reply) => {
+                let _ = reply.send(result);
+            }
+            Reply::Remote { node, request } => {
+                self.send(node, Message::Forwarded { request, result });
+            }
+        }
+    }
+
+    fn send(&self, to: NodeId, message: Message) {
+        let members = self.raft.members();
+        let address = members
+            .get(&to)
+            .or_else(|| self.senders.get(&to))
+            .map(String::as_str)
+            .unwrap_or_default();
+        let envelope = Envelope {
+            from: self.raft.id(),
+            from_address: members.get(&self.raft.id()).cloned().unwrap_or_default(),
+            to,
+            message,
+        };
+        self.transport.send(address, envelope);
+    }
+
+    // Saves what has to survive a restart, applies whatever was committed,
+    // starts a snapshot once the log is long enough, and sends whatever the
+    // last step produced
+    async fn advance(&mut self) -> io::Result<()> {
+        let unsaved = self.raft.take_unsaved();
+        if let Some(storage) = &mut self.storage {
+            storage.save(&self.raft, unsaved)?;
+        }
+
+        if let Some(snapshot) = self.raft.take_installed() {
+            match rmp_serde::from_slice::<Vec<Item>>(&snapshot.data) {
+                Ok(items) => self.datastore.restore(items).await,
+                Err(e) => error!("Failed to decode snapshot {}: {}", snapshot.index, e),
+            }
+        }
+
+        for entry in self.raft.take_committed() {
+            let applied = self.apply(&entry).await;
+            if let Some((term, reply)) = self.pending.remove(&entry.index) {
+                let result = if term == entry.term {
+                    Ok(applied)
+                } else {
+                    Err(ProposeError::LostLeadership)
+                };
+                self.reply(reply, result);
+            }
+        }
+        // skipped over by a snapshot from a new leader
+        let applied = self.raft.applied();
+        while let Some(entry) = self.pending.first_entry() {
+            if *entry.key() > applied {
+                break;
+            }
+            let (_, reply) = entry.remove();
+            self.reply(reply, Err(ProposeError::LostLeadership));
+        }
+
+        if !self.taking_snapshot
+            && applied - self.raft.snapshot_index() >= self.config.snapshot_threshold
+        {
+            self.take_snapshot(applied);
+        }
+
+        for (to, message) in self.raft.take_messages() {
+            self.send(to, message);
+        }
+        let status = status(&self.raft);
+        self.status.send_if_modified(|current| {
+            let changed = *current != status;
+            *current = status;
+            changed
+        });
+        Ok(())
+    }
+
+    // Encodes the datastore as of `index`, and stages it in storage, on a
+    // blocking thread; that takes a while for a large datastore, and the
+    // driver carries on meanwhile
+    fn take_snapshot(&mut self, index: u64) {
+        let Some(mut snapshot) = self.raft.snapshot_at(index) else {
+            return;
+        };
+        let datastore = self.datastore.snapshot();
+        let dir = self
+            .storage
+            .as_ref()
+            .map(|storage| storage.dir().to_path_buf());
+        let snapshots = self.snapshots.clone();
+        self.taking_snapshot = true;
+        tokio::task::spawn_blocking(move || {
+            let items = datastore.query(">", Some(GetOptions::new().history_count(usize::MAX)));
+            let result = rmp_serde::to_vec(&items)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+                .and_then(|data| {
+                    snapshot.data = data;
+                    if let Some(dir) = dir {
+                        Storage::stage(&dir, &snapshot)?;
+                    }
+                    Ok(snapshot)
+                });
+            let _ = snapshots.send(result);
+        });
+    }
+
+    fn snapshot_taken(&mut self, result: io::Result<LogSnapshot>) {
+        self.taking_snapshot = false;
+        match result {
+            Ok(snapshot) => {
+                let index = snapshot.index;
+                // unless one from the leader replaced the log meanwhile
+                if self.raft.compact(snapshot) {
+                    if let Some(storage) = &mut self.storage {
+                        storage.staged(index);
+                    }
+                }
+            }
+            Err(e) => error!("Failed to take a snapshot: {}", e),
+        }
+    }
+
+    async fn apply(&self, entry: &Entry) -> Applied {
+        match &entry.command {
+            Command::Set {
+                key,
+                value,
+                preserve_history,
+                precondition,
+                timestamp,
+            } => {
+                // nothing else writes, so the key can't change in between
+                if let Some(precondition) = precondition {
+                    if !precondition.holds(self.datastore.get(key).await.as_ref()) {
+                        return Applied::Written(false);
+                    }
+                }
+                let item = Item {
+                    key: key.clone(),
+                    value: value.clone(),
+                    timestamp: *timestamp,
+                    id: entry.index as i64,
+                };
+                let change = Change {
+                    revision: entry.index,
+                    key: key.clone(),
+                    kind: ChangeKind::Set {
+                        item,
+                        preserve_history: *preserve_history,
+                    },
+                };
+                self.datastore.apply(vec![change]).await;
+                Applied::Written(true)
+            }
+            Command::Delete { pattern } => {
+                Applied::Deleted(self.datastore.delete_matching(pattern.as_str()).await)
+            }
+            Command::Noop | Command::AddNode { .. } | Command::RemoveNode { .. } => Applied::Done,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::LocalNetwork;
+
+    fn config() -> RaftConfig {
+        RaftConfig {
+            tick: Duration::from_millis(10),
+            proposal_timeout: Duration::from_secs(1),
+            ..Default::default()
+        }
+    }
+
+    fn address(id: NodeId) -> String {
+        format!("node{}", id)
+    }
+
+    fn start(
+        network: &Arc<LocalNetwork>,
+        id: NodeId,
+        members: Members,
+        config: RaftConfig,
+    ) -> Node {
+        let node = Node::start(
+            id,
+            members,
+            Arc::new(Datastore::new(5)),
+            network.clone(),
+            config,
+        )
+        .unwrap();
+        network.register(&address(id), node.clone());
+        node
+    }
+
+    fn cluster(size: u64, config: RaftConfig) -> (Arc<LocalNetwork>, Vec<Node>) {
+        let network = Arc::new(LocalNetwork::new());
+        let members: Members = (1..=size).map(|id| (id, address(id))).collect();
+        let nodes = (1..=size)
+            .map(|id| start(&network, id, members.clone(), config.clone()))
+            .collect();
+        (network, nodes)
+    }
+
+    // The node all of the given nodes follow, once they agree on one
+    async fn leader(nodes: &[Node]) -> Node {
+        for _ in 0..500 {
+            let leaders: Vec<Option<NodeId>> =
+                nodes.iter().map(|node| node.status().leader).collect();
+            if let Some(Some(leader)) = leaders.first() {
+                if leaders.iter().all(|id| *id == Some(*leader)) {
+                    if let Some(node) = nodes.iter().find(|node| node.id() == *leader) {
+                        return node.clone();
+                    }
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("no leader was elected");
+    }
+
+    // Retries a write through the node while an election is going on
+    async fn set(node: &Node, key: &str, value: &[u8]) {
+        for _ in 0..50 {
+            match node.set(key.to_string(), value.to_vec(), None).await {
+                Ok(written) => {
+                    assert!(written);
+                    return;
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+            }
+        }
+        panic!("failed to set {}", key);
+    }
+
+    // Waits for every node to hold the same items under the pattern
+    async fn converged(nodes: &[Node], pattern: &str) -> Vec<Item> {
+        let contents = |node: &Node| {
+            node.datastore()
+                .snapshot()
+                .query(pattern, Some(GetOptions::new().history_count(5)))
+        };
+        for _ in 0..500 {
+            let first = contents(&nodes[0]);
+            if nodes.iter().all(|node| contents(node) == first) {
+                return first;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("nodes didn't converge");
+    }
+
+    #[tokio::test]
+    async fn test_cluster() {
+        let (_network, nodes) = cluster(3, config());
+        let leader = leader(&nodes).await;
+        let follower = nodes.iter().find(|node| node.id() != leader.id()).unwrap();
+
+        // written through a follower, and applied everywhere with the same
+        // version
+        assert_eq!(
+            follower
+                .set("config.ntp.server".to_string(), b"10.0.0.1".to_vec(), None)
+                .await,
+            Ok(true)
+        );
+        let items = converged(&nodes, "config.>").await;
+        assert_eq!(items.len(), 1);
+        let version = items[0].id;
+
+        // preconditions see the same versions on every node
+        let update = |version| Some(SetOptions::new().if_version(version));
+        assert_eq!(
+            leader
+                .set(
+                    "config.ntp.server".to_string(),
+                    b"10.0.0.2".to_vec(),
+                    update(version + 1)
+                )
+                .await,
+            Ok(false)
+        );
+        assert_eq!(
+            follower
+                .set(
+                    "config.ntp.server".to_string(),
+                    b"10.0.0.2".to_vec(),
+                    update(version)
+                )
+                .await,
+            Ok(true)
+        );
+        assert_eq!(follower.delete("config.>".to_string()).await, Ok(1));
+        assert!(converged(&nodes, "config.>").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_survives_node_loss() {
+        let (network, nodes) = cluster(3, config());
+        let old = leader(&nodes).await;
+        set(&old, "config.a", b"1").await;
+
+        network.isolate(old.id());
+        let rest: Vec<Node> = nodes
+            .iter()
+            .filter(|node| node.id() != old.id())
+            .cloned()
+            .collect();
+        let new = leader(&rest).await;
+        assert_ne!(new.id(), old.id());
+        set(&new, "config.b", b"2").await;
+
+        // the old leader can't commit anything on its own
+        assert!(old
+            .set("config.c".to_string(), b"3".to_vec(), None)
+            .await
+            .is_err());
+
+        // and catches up once it is back, dropping what it couldn't commit
+        network.heal(old.id());
+        let items = converged(&nodes, "config.>").await;
+        let keys: Vec<&str> = items.iter().map(|item| item.key.as_str()).collect();
+        assert_eq!(keys, vec!["config.a", "config.b"]);
+        assert_eq!(leader(&nodes).await.id(), new.id());
+    }
+
+    #[tokio::test]
+    async fn test_restart() {
+        let network = Arc::new(LocalNetwork::new());
+        let members: Members = (1..=3).map(|id| (id, address(id))).collect();
+        let dir = |id: NodeId| {
+            std::env::temp_dir().join(format!("forst-node-{}-{}", id, std::process::id()))
+        };
+        let config = |id: NodeId| RaftConfig {
+            state_dir: Some(dir(id)),
+            ..config()
+        };
+        for id in 1..=3 {
+            let _ = std::fs::remove_dir_all(dir(id));
+        }
+        let mut nodes: Vec<Node> = (1..=3)
+            .map(|id| start(&network, id, members.clone(), config(id)))
+            .collect();
+        let leader = leader(&nodes).await;
+        set(&leader, "config.a", b"1").await;
+
+        // restarted with the same id and members, a node carries on from
+        // its saved term and log instead of starting over
+        let position = nodes
+            .iter()
+            .position(|node| node.id() != leader.id())
+            .unwrap();
+        let id = nodes[position].id();
+        let term = nodes[position].status().term;
+        nodes[position].stop().await;
+        assert_eq!(
+            nodes[position].delete("config.>".to_string()).await,
+            Err(ProposeError::Stopped)
+        );
+        nodes[position] = start(&network, id, members.clone(), config(id));
+        assert!(nodes[position].status().term >= term);
+
+        set(&leader, "config.b", b"2").await;
+        let items = converged(&nodes, "config.>").await;
+        let keys: Vec<&str> = items.iter().map(|item| item.key.as_str()).collect();
+        assert_eq!(keys, vec!["config.a", "config.b"]);
+
+        for node in &nodes {
+            node.stop().await;
+            std::fs::remove_dir_all(dir(node.id())).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_membership_and_snapshots() {
+        let config = RaftConfig {
+            snapshot_threshold: 5,
+            ..config()
+        };
+        let (network, mut nodes) = cluster(3, config.clone());
+        let leader = leader(&nodes).await;
+        for i in 0..20 {
+            set(&leader, &format!("config.key{}", i), b"value").await;
+        }
+
+        // a new node catches up from a snapshot, since the log was compacted
+        let joined = start(&network, 4, Members::new(), config);
+        nodes[0].add_node(4, address(4)).await.unwrap();
+        nodes.push(joined.clone());
+        assert_eq!(converged(&nodes, "config.>").await.len(), 20);
+        assert_eq!(joined.status().members.len(), 4);
+
+        // the cluster carries on without the node that was removed, even if
+        // it was the leader
+        joined.remove_node(leader.id()).await.unwrap();
+        nodes.retain(|node| node.id() != leader.id());
+        set(&joined, "config.key20", b"value").await;
+        assert_eq!(converged(&nodes, "config.>").await.len(), 21);
+        assert!(!self::leader(&nodes)
+            .await
+            .status()
+            .members
+            .contains_key(&leader.id()));
+    }
+}
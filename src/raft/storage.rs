@@ -0,0 +1,391 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::core::{Raft, Unsaved};
+use super::{Entry, LogSnapshot, Members, NodeId};
+
+// The term and vote, rewritten whenever they change
+const STATE_FILE: &str = "state";
+// The snapshot followed by the entries after it, appended to as entries
+// are added and rewritten when any are replaced
+const LOG_FILE: &str = "log";
+// A log file starting with a newer snapshot, written while the node carries
+// on, which replaces the log once the node compacted to that snapshot
+const STAGED_FILE: &str = "log.staged";
+
+/// What a node saved before it stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Saved {
+    pub term: u64,
+    pub voted_for: Option<NodeId>,
+    pub snapshot: LogSnapshot,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<NodeId>,
+}
+
+/// Keeps a node's term, vote and log in a directory, so that it can restart
+/// under the same id without voting twice in a term or forgetting entries
+/// it acknowledged.
+#[derive(Debug)]
+pub struct Storage {
+    dir: PathBuf,
+    log: File,
+    // The last entry in the log file, once it starts with a snapshot
+    last: Option<u64>,
+    // The index of the snapshot in the staged log file
+    staged: Option<u64>,
+}
+
+impl Storage {
+    /// Opens the directory, creating it if needed, along with whatever a
+    /// node saved there before.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<(Storage, Option<Saved>)> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let state = match fs::read(dir.join(STATE_FILE)) {
+            Ok(bytes) => Some(decode::<HardState>(&mut bytes.as_slice())?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let (snapshot, entries) = read_log(&dir.join(LOG_FILE))?;
+        // left behind by a compaction that didn't finish
+        if let Err(e) = fs::remove_file(dir.join(STAGED_FILE)) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+        let snapshot_saved = snapshot.is_some();
+
+        let saved = (state.is_some() || snapshot.is_some()).then(|| {
+            let state = state.unwrap_or(HardState {
+                term: 0,
+                voted_for: None,
+            });
+            Saved {
+                term: state.term,
+                voted_for: state.voted_for,
+                snapshot: snapshot.unwrap_or_else(empty_snapshot),
+                entries,
+            }
+        });
+        let last = saved.as_ref().filter(|_| snapshot_saved).map(|saved| {
+            saved
+                .entries
+                .last()
+                .map_or(saved.snapshot.index, |entry| entry.index)
+        });
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))?;
+        let storage = Storage {
+            dir,
+            log,
+            last,
+            staged: None,
+        };
+        Ok((storage, saved))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Writes a log file starting with the snapshot into the directory,
+    /// without using it yet. That takes a while for a large snapshot, so it
+    /// can be done away from the node, which hands it to `staged` once it
+    /// compacted its log to the snapshot.
+    pub fn stage(dir: &Path, snapshot: &LogSnapshot) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(dir.join(STAGED_FILE))?);
+        encode(&mut writer, snapshot)?;
+        writer.into_inner()?.sync_all()
+    }
+
+    /// Takes the staged log file for the snapshot at `index` the next time
+    /// the snapshot is saved, so only the entries after it are left to
+    /// write.
+    pub fn staged(&mut self, index: u64) {
+        self.staged = Some(index);
+    }
+
+    /// Saves what changed, and only returns once it is on disk.
+    pub fn save(&mut self, raft: &Raft, unsaved: Unsaved) -> io::Result<()> {
+        match (unsaved.entries_from, self.last) {
+            _ if unsaved.snapshot => self.rewrite(raft)?,
+            (Some(_), None) => self.rewrite(raft)?,
+            (Some(from), Some(last)) if from <= last => self.rewrite(raft)?,
+            (Some(_), Some(last)) => {
+                let mut writer = BufWriter::new(&self.log);
+                for entry in raft.entries().iter().filter(|entry| entry.index > last) {
+                    encode(&mut writer, entry)?;
+                }
+                writer.flush()?;
+                drop(writer);
+                self.log.sync_data()?;
+                self.last = Some(raft.last_index());
+            }
+            (None, _) => {}
+        }
+
+        if unsaved.hard_state {
+            let state = HardState {
+                term: raft.term(),
+                voted_for: raft.voted_for(),
+            };
+            let mut bytes = Vec::new();
+            encode(&mut bytes, &state)?;
+            self.replace(STATE_FILE, &bytes)?;
+        }
+        Ok(())
+    }
+
+    fn rewrite(&mut self, raft: &Raft) -> io::Result<()> {
+        if self.staged.take() == Some(raft.snapshot_index()) {
+            let staged = self.dir.join(STAGED_FILE);
+            let mut writer = BufWriter::new(OpenOptions::new().append(true).open(&staged)?);
+            for entry in raft.entries() {
+                encode(&mut writer, entry)?;
+            }
+            writer.into_inner()?.sync_all()?;
+            fs::rename(&staged, self.dir.join(LOG_FILE))?;
+            File::open(&self.dir)?.sync_all()?;
+        } else {
+            let mut bytes = Vec::new();
+            encode(&mut bytes, raft.snapshot())?;
+            for entry in raft.entries() {
+                encode(&mut bytes, entry)?;
+            }
+            self.replace(LOG_FILE, &bytes)?;
+        }
+
+        self.log = OpenOptions::new()
+            .append(true)
+            .open(self.dir.join(LOG_FILE))?;
+        self.last = Some(raft.last_index());
+        Ok(())
+    }
+
+    // Swaps in the new contents of a file all at once
+    fn replace(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        let path = self.dir.join(name);
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        File::open(&self.dir)?.sync_all()
+    }
+}
+
+fn empty_snapshot() -> LogSnapshot {
+    LogSnapshot {
+        index: 0,
+        term: 0,
+        members: Members::new(),
+        data: Vec::new(),
+    }
+}
+
+// An entry cut short by a crash while it was appended was never
+// acknowledged, so it is dropped
+fn read_log(path: &Path) -> io::Result<(Option<LogSnapshot>, Vec<Entry>)> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((None, Vec::new())),
+        Err(e) => return Err(e),
+    };
+    if bytes.is_empty() {
+        return Ok((None, Vec::new()));
+    }
+
+    let mut rest = bytes.as_slice();
+    let snapshot: LogSnapshot = decode(&mut rest)?;
+    let mut entries = Vec::new();
+    let mut valid = bytes.len() - rest.len();
+    while !rest.is_empty() {
+        match decode::<Entry>(&mut rest) {
+            Ok(entry) => {
+                entries.push(entry);
+                valid = bytes.len() - rest.len();
+            }
+            Err(_) => {
+                OpenOptions::new()
+                    .write(true)
+                    .open(path)?
+                    .set_len(valid as u64)?;
+                break;
+            }
+        }
+    }
+    Ok((Some(snapshot), entries))
+}
+
+fn encode<T: Serialize>(writer: &mut impl Write, value: &T) -> io::Result<()> {
+    rmp_serde::encode::write(writer, value)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn decode<T: DeserializeOwned>(bytes: &mut &[u8]) -> io::Result<T> {
+    rmp_serde::decode::from_read(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::{Command, Message, Role};
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("forst-raft-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn save(storage: &mut Storage, raft: &mut Raft) {
+        let unsaved = raft.take_unsaved();
+        storage.save(raft, unsaved).unwrap();
+    }
+
+    fn delete(key: &str) -> Command {
+        Command::Delete {
+            pattern: key.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_restart_keeps_vote_and_log() {
+        let dir = dir("restart");
+        let members: Members = [1, 2, 3].map(|id| (id, String::new())).into();
+        let (mut storage, saved) = Storage::open(&dir).unwrap();
+        assert!(saved.is_none());
+
+        // node 2 votes for node 3, then accepts an entry from it
+        let mut raft = Raft::new(2, members.clone(), 10, 2);
+        raft.step(
+            3,
+            Message::RequestVote {
+                term: 1,
+                last_log_index: 3,
+                last_log_term: 0,
+            },
+        );
+        raft.step(
+            3,
+            Message::AppendEntries {
+                term: 1,
+                prev_log_index: 3,
+                prev_log_term: 0,
+                entries: vec![Entry {
+                    index: 4,
+                    term: 1,
+                    command: delete("a"),
+                }],
+                leader_commit: 3,
+            },
+        );
+        assert_eq!(raft.voted_for(), Some(3));
+        save(&mut storage, &mut raft);
+        drop(storage);
+
+        // restarted under the same id, it keeps both
+        let (mut storage, saved) = Storage::open(&dir).unwrap();
+        let mut restarted = Raft::recover(2, saved.unwrap(), 10, 2);
+        assert_eq!(restarted.term(), 1);
+        assert_eq!(restarted.voted_for(), Some(3));
+        assert_eq!(restarted.entries(), raft.entries());
+        assert_eq!(restarted.members(), &members);
+
+        // so it won't vote for another candidate in the same term
+        restarted.step(
+            1,
+            Message::RequestVote {
+                term: 1,
+                last_log_index: 4,
+                last_log_term: 1,
+            },
+        );
+        assert_eq!(
+            restarted.take_messages(),
+            vec![(
+                1,
+                Message::Vote {
+                    term: 1,
+                    granted: false
+                }
+            )]
+        );
+
+        // entries replaced after a restart are replaced on disk too
+        save(&mut storage, &mut restarted);
+        restarted.step(
+            1,
+            Message::AppendEntries {
+                term: 3,
+                prev_log_index: 3,
+                prev_log_term: 0,
+                entries: vec![Entry {
+                    index: 4,
+                    term: 3,
+                    command: delete("b"),
+                }],
+                leader_commit: 3,
+            },
+        );
+        save(&mut storage, &mut restarted);
+        drop(storage);
+        let (_, saved) = Storage::open(&dir).unwrap();
+        let saved = saved.unwrap();
+        assert_eq!(saved.term, 3);
+        assert_eq!(saved.entries.last().unwrap().command, delete("b"));
+        assert_eq!(saved.entries.len(), 4);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_staged_snapshot() {
+        let dir = dir("staged");
+        let (mut storage, _) = Storage::open(&dir).unwrap();
+        let mut raft = Raft::new(1, [(1, String::new())].into(), 10, 2);
+        while raft.role() != Role::Leader {
+            raft.tick();
+        }
+        raft.propose(delete("a")).unwrap();
+        save(&mut storage, &mut raft);
+        let applied = raft.take_committed().last().unwrap().index;
+
+        // written away from the node, which appends another entry meanwhile
+        let mut snapshot = raft.snapshot_at(applied).unwrap();
+        snapshot.data = b"state".to_vec();
+        Storage::stage(storage.dir(), &snapshot).unwrap();
+        raft.propose(delete("b")).unwrap();
+        save(&mut storage, &mut raft);
+
+        assert!(raft.compact(snapshot.clone()));
+        storage.staged(applied);
+        save(&mut storage, &mut raft);
+        assert!(!dir.join(STAGED_FILE).exists());
+        drop(storage);
+
+        let (_, saved) = Storage::open(&dir).unwrap();
+        let saved = saved.unwrap();
+        assert_eq!(saved.snapshot, snapshot);
+        let commands: Vec<Command> = saved
+            .entries
+            .into_iter()
+            .map(|entry| entry.command)
+            .collect();
+        assert_eq!(commands, vec![delete("b")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
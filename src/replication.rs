@@ -0,0 +1,123 @@
+// Keeps a replica's datastore a copy of its primary's, by bootstrapping from
+// the primary's state and then applying its changes as they are made
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use log::{error, info};
+use tonic::metadata::MetadataValue;
+
+use crate::datastore::datastore_client::DatastoreClient;
+use crate::datastore::{self as proto, ChangeKind as ChangeKindResponse, WatchRequest};
+use rs_datastore::datastore::changes::{Change, ChangeKind, ChangesError};
+use rs_datastore::datastore::{Datastore, Item};
+
+// How long to wait before reconnecting to an unreachable primary
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// Marks the OUT_OF_RANGE a server fails with when its change log no longer
+// has the revision asked for, telling it apart from tonic's own, e.g. for a
+// message over the size limit
+const COMPACTED: &str = "x-forst-compacted";
+
+/// Copies the primary at `url` into the datastore until the process exits,
+/// reconnecting whenever the stream breaks. A reconnect resumes after the
+/// last change applied, or bootstraps again if the primary no longer has
+/// the changes since then, e.g. after it restarted. That relies on the
+/// primary's revisions never going back, so it should run with a revision
+/// file.
+pub async fn replicate(datastore: Arc<Datastore>, url: String) {
+    // The primary's revision the datastore is a copy of
+    let mut revision = None;
+    loop {
+        match follow(&datastore, &url, &mut revision).await {
+            Ok(()) => info!("Primary {} closed the replication stream", url),
+            Err(status) if is_compacted(&status) => {
+                info!("Fell behind primary {}, bootstrapping again", url);
+                revision = None;
+            }
+            Err(status) => error!("Replicating from {} failed: {}", url, status),
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+async fn follow(
+    datastore: &Datastore,
+    url: &str,
+    revision: &mut Option<u64>,
+) -> Result<(), tonic::Status> {
+    // the state is split into small responses, but a single key's history
+    // can still outgrow the default limit
+    let mut client = DatastoreClient::connect(url.to_string())
+        .await
+        .map_err(|e| tonic::Status::unavailable(e.to_string()))?
+        .max_decoding_message_size(usize::MAX);
+
+    let request = WatchRequest {
+        pattern: ">".to_string(),
+        initial_state: revision.is_none(),
+        since_revision: *revision,
+        history: true,
+        ..Default::default()
+    };
+    let mut responses = client.watch(request).await?.into_inner();
+    info!("Replicating from {} after revision {:?}", url, revision);
+
+    // The initial state received so far, restored once all of it arrived
+    let mut state = Vec::new();
+    while let Some(response) = responses.message().await? {
+        // only the initial state comes without changes
+        if response.changes.is_empty() {
+            state.extend(response.items.into_iter().map(item));
+            if response.more {
+                continue;
+            }
+            datastore.restore(std::mem::take(&mut state)).await;
+        } else {
+            datastore
+                .apply(response.changes.into_iter().map(change).collect())
+                .await;
+        }
+        *revision = Some(response.revision);
+    }
+    Ok(())
+}
+
+/// The status for changes the change log no longer has.
+pub fn compacted(error: ChangesError) -> tonic::Status {
+    let mut status = tonic::Status::out_of_range(error.to_string());
+    status
+        .metadata_mut()
+        .insert(COMPACTED, MetadataValue::from_static("1"));
+    status
+}
+
+fn is_compacted(status: &tonic::Status) -> bool {
+    status.metadata().contains_key(COMPACTED)
+}
+
+fn change(change: proto::Change) -> Change {
+    let kind = match change.kind() {
+        ChangeKindResponse::Set => ChangeKind::Set {
+            item: item(change.item.unwrap_or_default()),
+            preserve_history: change.preserve_history,
+        },
+        ChangeKindResponse::Delete => ChangeKind::Delete,
+        ChangeKindResponse::Expire => ChangeKind::Expire { id: change.version },
+    };
+    Change {
+        revision: change.revision,
+        key: change.key,
+        kind,
+    }
+}
+
+// Timestamps aren't sent, so copies are stamped when they arrive
+fn item(item: proto::Item) -> Item {
+    Item {
+        key: item.key,
+        value: item.value,
+        timestamp: SystemTime::now(),
+        id: item.version,
+    }
+}
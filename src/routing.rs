@@ -0,0 +1,115 @@
+// Sends requests for keys another server owns on to it, and gathers queries
+// from every server that may hold matching keys
+use std::time::Duration;
+
+use tonic::metadata::MetadataValue;
+use tonic::transport::Channel;
+use tonic::Request;
+
+use crate::datastore::datastore_client::DatastoreClient;
+use crate::peers::Peers;
+use rs_datastore::datastore::{Partitions, Pattern};
+
+// Marks a request one server sent another, which answers it from its own
+// datastore rather than routing it again
+const FORWARDED: &str = "x-forst-forwarded";
+
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct Router {
+    partitions: Partitions,
+    // This server's address, as the partitions name it
+    address: String,
+    peers: Peers,
+}
+
+impl Router {
+    pub fn new(partitions: Partitions, address: String) -> Self {
+        Router {
+            partitions,
+            address,
+            peers: Peers::new(FORWARD_TIMEOUT),
+        }
+    }
+
+    /// The address of the server that owns the key, or `None` if it is this
+    /// one.
+    #[allow(clippy::result_large_err)]
+    pub fn owner(&self, key: &str) -> Result<Option<&str>, tonic::Status> {
+        match self.partitions.owner(key) {
+            Some(owner) if owner == self.address => Ok(None),
+            Some(owner) => Ok(Some(owner)),
+            None => Err(tonic::Status::failed_precondition(format!(
+                "no partition is assigned key '{}'",
+                key
+            ))),
+        }
+    }
+
+    /// Whether this server may own keys matching any of the patterns, and
+    /// the addresses of the other servers that may.
+    pub fn owners<'a>(&self, patterns: impl IntoIterator<Item = &'a Pattern>) -> (bool, Vec<&str>) {
+        let mut local = false;
+        let mut remote: Vec<&str> = Vec::new();
+        for pattern in patterns {
+            for owner in self.partitions.owners(pattern) {
+                if owner == self.address {
+                    local = true;
+                } else if !remote.contains(&owner) {
+                    remote.push(owner);
+                }
+            }
+        }
+        (local, remote)
+    }
+
+    /// Rejects writes to keys this server doesn't own, for requests that
+    /// write several keys at once and so can't be forwarded whole.
+    #[allow(clippy::result_large_err)]
+    pub fn check_local(&self, key: &str) -> Result<(), tonic::Status> {
+        match self.owner(key)? {
+            None => Ok(()),
+            Some(owner) => Err(tonic::Status::failed_precondition(format!(
+                "key '{}' is owned by {}",
+                key, owner
+            ))),
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn check_local_pattern(&self, pattern: &Pattern) -> Result<(), tonic::Status> {
+        match self.owners([pattern]).1.first() {
+            None => Ok(()),
+            Some(owner) => Err(tonic::Status::failed_precondition(format!(
+                "keys matching '{}' are owned by {}",
+                pattern, owner
+            ))),
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn client(&self, owner: &str) -> Result<DatastoreClient<Channel>, tonic::Status> {
+        self.peers.client(owner).ok_or_else(|| {
+            tonic::Status::internal(format!("invalid partition owner address '{}'", owner))
+        })
+    }
+}
+
+pub fn is_forwarded<T>(request: &Request<T>) -> bool {
+    request.metadata().contains_key(FORWARDED)
+}
+
+/// Wraps a message to send on to its owner.
+pub fn forward<T>(message: T) -> Request<T> {
+    let mut request = Request::new(message);
+    request
+        .metadata_mut()
+        .insert(FORWARDED, MetadataValue::from_static("1"));
+    request
+}
+
+/// Says which server a failed forwarded request went to.
+pub fn from_owner(owner: &str, status: tonic::Status) -> tonic::Status {
+    tonic::Status::new(status.code(), format!("{}: {}", owner, status.message()))
+}
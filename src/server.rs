@@ -1,56 +1,337 @@
+use std::collections::BTreeMap;
 use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
 
 use clap::{Parser, value_parser};
+use futures::{SinkExt, Stream, StreamExt};
 
 use tokio::signal;
 use tokio::sync::oneshot;
 use tonic::transport::Server;
+use tonic::{Code, Streaming};
 
+use datastore::aggregate_request::GroupBy as GroupByRequest;
+use datastore::compare::Condition;
 use datastore::datastore_server::{Datastore as DatastoreTrait, DatastoreServer};
+use datastore::set_options::Precondition as PreconditionRequest;
+use datastore::txn_operation::Operation as OperationRequest;
+use datastore::{AddNodeRequest, MembersResponse, RaftAck, RaftMessage, RemoveNodeRequest};
 use datastore::{
-    DeleteAtIndexRequest, DeleteAtIndexResponse, DeleteRequest, DeleteResponse, GetRequest,
-    GetResponse, Item, QueryRequest, QueryResponse, SetRequest, SetResponse,
+    AggregateGroup, AggregateRequest, AggregateResponse, BatchGetRequest, BatchGetResponse,
+    BatchSetRequest, BatchSetResponse, Change, ChangeKind as ChangeKindResponse, ChangesRequest,
+    ChangesResponse, DeleteAtIndexRequest, DeleteAtIndexResponse, DeleteRequest, DeleteResponse,
+    GetRequest, GetResponse, IngestAck, IngestRequest, Item, QueryRequest, QueryResponse,
+    SetRequest, SetResponse, SnapshotRequest, SnapshotResponse, TxnRequest, TxnResponse,
+    WatchMode as WatchModeRequest, WatchRequest, WatchResponse,
 };
-use rs_datastore::datastore::Datastore;
-use rs_datastore::nestedmap::options::{GetOptions, SetOptions};
+use federation::Federation;
+use routing::Router;
+use rs_datastore::datastore::changes::{self, ChangeKind};
+use rs_datastore::datastore::pattern_cache::PatternCache;
+use rs_datastore::datastore::transaction::Transaction;
+use rs_datastore::datastore::{
+    Datastore, Partitions, Pattern, PatternSet, SnapshotLeases, WatchEvent, WatchMode, WatchOptions,
+};
+use rs_datastore::nestedmap::aggregate::{self, AggregateResult, Aggregation, GroupBy};
+use rs_datastore::nestedmap::filter::Filter;
+use rs_datastore::nestedmap::index::ValueIndex;
+use rs_datastore::nestedmap::options::{GetOptions, Precondition, SetOptions};
+use rs_datastore::raft::{self, Members, Node, ProposeError, RaftConfig};
 
 pub mod datastore {
     tonic::include_proto!("datastore");
 }
 
+mod cluster;
+mod federation;
+mod peers;
+mod replication;
+mod routing;
+
 #[derive(Debug)]
 pub struct MyDatastore {
-    datastore: Datastore,
+    // Shared with the tasks applying ingest streams
+    datastore: Arc<Datastore>,
+    patterns: PatternCache,
+    snapshots: SnapshotLeases,
+    // What the server is when it doesn't accept writes: a replica, which
+    // only changes by copying its primary, or an aggregator
+    read_only: Option<&'static str>,
+    // Set on members of a cluster, whose writes go through the Raft log
+    node: Option<Node>,
+    // Set when the key space is partitioned between servers
+    router: Option<Arc<Router>>,
+    // Set on aggregators, which answer reads from their upstreams
+    federation: Option<Federation>,
 }
 
+const PATTERN_CACHE_CAPACITY: usize = 1024;
+
+// Upper bound on the writes grouped under one lock by an ingest stream
+const INGEST_BATCH_SIZE: usize = 1024;
+// Acks waiting to be read by a slow client before ingesting pauses
+const INGEST_ACK_BUFFER: usize = 16;
+
+const DEFAULT_SNAPSHOT_LEASE: Duration = Duration::from_secs(60);
+// Bounds how long a forgotten snapshot can keep old values in memory
+const MAX_SNAPSHOT_LEASE: Duration = Duration::from_secs(3600);
+
+const DEFAULT_WATCH_INTERVAL: Duration = Duration::from_secs(1);
+// Splits the items of a watch state so that each response stays well under
+// the 4 MB a client decodes by default
+const WATCH_CHUNK_BYTES: usize = 1024 * 1024;
+// Keeps a sampling watcher from snapshotting in a tight loop
+const MIN_WATCH_INTERVAL: Duration = Duration::from_millis(10);
+
 impl MyDatastore {
-    pub fn new(max_history: usize) -> Self {
+    pub fn new(max_history: usize, segment_index: bool) -> Self {
+        let datastore = if segment_index {
+            Datastore::with_segment_index(max_history)
+        } else {
+            Datastore::new(max_history)
+        };
+
         MyDatastore {
-            datastore: Datastore::new(max_history),
+            datastore: Arc::new(datastore),
+            patterns: PatternCache::new(PATTERN_CACHE_CAPACITY),
+            snapshots: SnapshotLeases::new(),
+            read_only: None,
+            node: None,
+            router: None,
+            federation: None,
+        }
+    }
+
+    /// Rejects writes from clients; the datastore only changes by copying
+    /// the primary.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = Some("replica");
+        self
+    }
+
+    /// Answers Get and Query from the upstreams instead of the datastore,
+    /// and rejects everything else.
+    pub fn federated(mut self, federation: Federation) -> Self {
+        self.read_only = Some("aggregator");
+        self.federation = Some(federation);
+        self
+    }
+
+    // Aggregators only merge Get and Query across their upstreams
+    #[allow(clippy::result_large_err)]
+    fn not_federated(&self, operation: &str) -> Result<(), tonic::Status> {
+        if self.federation.is_some() {
+            return Err(tonic::Status::unimplemented(format!(
+                "{} isn't supported by an aggregator",
+                operation
+            )));
         }
+        Ok(())
+    }
+
+    /// Joins the datastore to a Raft cluster, through which every write
+    /// then goes.
+    pub fn cluster(mut self, node: Node) -> Self {
+        self.node = Some(node);
+        self
+    }
+
+    /// Only stores the keys the partitions assign this server, and routes
+    /// requests for any others to their owners.
+    pub fn partitioned(mut self, router: Router) -> Self {
+        self.router = Some(Arc::new(router));
+        self
+    }
+
+    // Requests forwarded by another server are answered from this one's
+    // datastore alone
+    fn router<T>(&self, request: &tonic::Request<T>) -> Option<&Router> {
+        self.router
+            .as_deref()
+            .filter(|_| !routing::is_forwarded(request))
+    }
+
+    // Writes that touch several keys at once aren't replicated through the
+    // log yet
+    #[allow(clippy::result_large_err)]
+    fn single_node(&self, operation: &str) -> Result<(), tonic::Status> {
+        if self.node.is_some() {
+            return Err(tonic::Status::unimplemented(format!(
+                "{} isn't supported in cluster mode",
+                operation
+            )));
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn node(&self) -> Result<&Node, tonic::Status> {
+        self.node
+            .as_ref()
+            .ok_or_else(|| tonic::Status::failed_precondition("this server isn't in a cluster"))
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn writable(&self) -> Result<(), tonic::Status> {
+        if let Some(role) = self.read_only {
+            return Err(tonic::Status::failed_precondition(format!(
+                "this server is a read-only {}",
+                role
+            )));
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn pattern(&self, pattern: &str) -> Result<Arc<Pattern>, tonic::Status> {
+        self.patterns
+            .get(pattern)
+            .map_err(|e| tonic::Status::invalid_argument(e.to_string()))
+    }
+
+    // The patterns a query asks for: its key alone, or its key and
+    // includes less its excludes
+    #[allow(clippy::result_large_err)]
+    fn query_patterns(&self, inner: &QueryRequest) -> Result<PatternSet, tonic::Status> {
+        if inner.include.is_empty() && inner.exclude.is_empty() {
+            return Ok(PatternSet::new().include(self.pattern(&inner.key)?));
+        }
+        let mut patterns = PatternSet::new();
+        for key in std::iter::once(&inner.key)
+            .filter(|key| !key.is_empty())
+            .chain(&inner.include)
+        {
+            patterns = patterns.include(self.pattern(key)?);
+        }
+        for key in &inner.exclude {
+            patterns = patterns.exclude(self.pattern(key)?);
+        }
+        Ok(patterns)
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn query_local(&self, mut inner: QueryRequest) -> Result<QueryResponse, tonic::Status> {
+        let options = inner.options.take().map(get_options).transpose()?;
+
+        let snapshot = match inner.snapshot {
+            Some(token) => self.snapshots.get(token).ok_or_else(|| {
+                tonic::Status::failed_precondition("snapshot expired or never opened")
+            })?,
+            None => self.datastore.snapshot(),
+        };
+
+        let patterns = self.query_patterns(&inner)?;
+        let items = if inner.include.is_empty() && inner.exclude.is_empty() {
+            snapshot.query(patterns.includes()[0].as_ref(), options)
+        } else {
+            snapshot.query_set(&patterns, options)
+        };
+
+        if items.is_empty() {
+            return Err(tonic::Status::not_found(
+                "No items found for the given keys",
+            ));
+        }
+
+        // Construct the response from the items
+        let reply = QueryResponse {
+            revision: snapshot.revision(),
+            items: items
+                .into_iter()
+                .map(|item| Item {
+                    labels: patterns.captures(&item.key).into_iter().collect(),
+                    key: item.key,
+                    value: item.value,
+                    version: item.id,
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        Ok(reply)
+    }
+
+    async fn batch_get_local(&self, keys: &[String]) -> BatchGetResponse {
+        let items = self.datastore.batch_get(keys).await;
+
+        BatchGetResponse {
+            responses: items
+                .into_iter()
+                .map(|item| GetResponse {
+                    item: item.map(|item| Item {
+                        key: item.key,
+                        value: item.value,
+                        labels: Default::default(),
+                        version: item.id,
+                    }),
+                    ..Default::default()
+                })
+                .collect(),
+        }
+    }
+
+    async fn delete_local(
+        &self,
+        key: String,
+        pattern: &Pattern,
+    ) -> Result<tonic::Response<DeleteResponse>, tonic::Status> {
+        let deleted = match &self.node {
+            Some(node) => node.delete(key).await.map_err(propose_status)?,
+            None => self.datastore.delete_matching(pattern).await,
+        };
+
+        Ok(tonic::Response::new(DeleteResponse {
+            success: deleted > 0,
+        }))
     }
 }
 
 #[tonic::async_trait]
 impl DatastoreTrait for MyDatastore {
+    type IngestStream = Pin<Box<dyn Stream<Item = Result<IngestAck, tonic::Status>> + Send>>;
+    type WatchStream = Pin<Box<dyn Stream<Item = Result<WatchResponse, tonic::Status>> + Send>>;
+
     async fn get(
         &self,
         request: tonic::Request<GetRequest>,
     ) -> Result<tonic::Response<GetResponse>, tonic::Status> {
+        if let Some(federation) = &self.federation {
+            return federation
+                .get(request.into_inner())
+                .await
+                .map(tonic::Response::new);
+        }
+        let router = self.router(&request);
         let key = request.into_inner().key;
 
+        if let Some(router) = router {
+            if let Some(owner) = router.owner(&key)? {
+                return router
+                    .client(owner)?
+                    .get(routing::forward(GetRequest { key }))
+                    .await
+                    .map_err(|status| routing::from_owner(owner, status));
+            }
+        }
+
         match self.datastore.get(&key).await {
             Some(item) => {
                 let reply = GetResponse {
                     item: Some(Item {
                         key: item.key.clone(),
                         value: item.value.clone(),
+                        labels: Default::default(),
+                        version: item.id,
                     }),
+                    ..Default::default()
                 };
 
                 Ok(tonic::Response::new(reply))
             }
-            None => Ok(tonic::Response::new(GetResponse { item: None })),
+            None => Ok(tonic::Response::new(GetResponse::default())),
         }
     }
 
@@ -58,16 +339,43 @@ impl DatastoreTrait for MyDatastore {
         &self,
         request: tonic::Request<SetRequest>,
     ) -> Result<tonic::Response<SetResponse>, tonic::Status> {
+        self.writable()?;
+        let router = self.router(&request);
         let req = request.into_inner();
 
-        let options = req.options.map(|opts| SetOptions {
-            preserve_history: opts.preserve_history,
-            ttl: std::time::Duration::from_secs(opts.ttl as u64),
-        });
+        if let Some(router) = router {
+            if let Some(owner) = router.owner(&req.key)? {
+                return router
+                    .client(owner)?
+                    .set(routing::forward(req))
+                    .await
+                    .map_err(|status| routing::from_owner(owner, status));
+            }
+        }
 
-        self.datastore.set(req.key, &req.value, options).await;
+        let options = req.options.map(set_options);
 
-        let reply = SetResponse { success: true };
+        let written = match &self.node {
+            Some(node) => {
+                if options
+                    .as_ref()
+                    .is_some_and(|options| !options.ttl.is_zero())
+                {
+                    return Err(tonic::Status::invalid_argument(
+                        "ttl isn't supported in cluster mode",
+                    ));
+                }
+                node.set(req.key, req.value, options)
+                    .await
+                    .map_err(propose_status)?
+            }
+            None => self.datastore.set(req.key, &req.value, options).await,
+        };
+
+        let reply = SetResponse {
+            success: written,
+            precondition_failed: !written,
+        };
         Ok(tonic::Response::new(reply))
     }
 
@@ -75,46 +383,432 @@ impl DatastoreTrait for MyDatastore {
         &self,
         request: tonic::Request<QueryRequest>,
     ) -> Result<tonic::Response<QueryResponse>, tonic::Status> {
+        if let Some(federation) = &self.federation {
+            // a malformed request is the client's to fix, not a reason to
+            // bother every upstream
+            let inner = request.into_inner();
+            self.query_patterns(&inner)?;
+            inner.options.clone().map(get_options).transpose()?;
+            return federation.query(inner).await.map(tonic::Response::new);
+        }
+        let router = self.router(&request);
         let inner = request.into_inner();
-        let key = inner.key;
-
-        let options = inner.options.map(|opts| GetOptions {
-            history_count: opts.history_count.map_or(0, |count| {
-                if count >= 0 {
-                    count as usize
-                } else {
-                    0
-                }
-            }),
-        });
 
-        let items = self.datastore.query(&key, options).await;
+        let Some(router) = router else {
+            return self.query_local(inner).map(tonic::Response::new);
+        };
+        let mut patterns = Vec::new();
+        for key in std::iter::once(&inner.key)
+            .filter(|key| !key.is_empty())
+            .chain(&inner.include)
+        {
+            patterns.push(self.pattern(key)?);
+        }
+        // snapshots are this server's own, so they only cover its keys
+        if inner.snapshot.is_some() {
+            for pattern in &patterns {
+                router.check_local_pattern(pattern)?;
+            }
+            return self.query_local(inner).map(tonic::Response::new);
+        }
+        let (local, remote) = router.owners(patterns.iter().map(AsRef::as_ref));
 
+        let queries = remote.into_iter().map(|owner| {
+            let request = inner.clone();
+            async move {
+                router
+                    .client(owner)?
+                    .query(routing::forward(request))
+                    .await
+                    .map_err(|status| routing::from_owner(owner, status))
+            }
+        });
+        let mut responses = futures::future::join_all(queries).await;
+        if local {
+            responses.push(self.query_local(inner).map(tonic::Response::new));
+        }
+        // a single owner's response stands as is, revision included
+        if responses.len() == 1 {
+            return responses.pop().unwrap();
+        }
+
+        let mut items = Vec::new();
+        for response in responses {
+            match response {
+                Ok(response) => items.extend(response.into_inner().items),
+                Err(status) if status.code() == Code::NotFound => {}
+                Err(status) => return Err(status),
+            }
+        }
         if items.is_empty() {
             return Err(tonic::Status::not_found(
                 "No items found for the given keys",
             ));
         }
+        // each key lives on one server, so this keeps its history in order
+        items.sort_by(|a, b| a.key.cmp(&b.key));
 
-        // Construct the response from the items
-        let reply = QueryResponse {
-            items: items
+        Ok(tonic::Response::new(QueryResponse {
+            items,
+            ..Default::default()
+        }))
+    }
+
+    async fn delete(
+        &self,
+        request: tonic::Request<DeleteRequest>,
+    ) -> Result<tonic::Response<DeleteResponse>, tonic::Status> {
+        self.writable()?;
+        let router = self.router(&request);
+        let key = request.into_inner().key;
+        let pattern = self.pattern(&key)?;
+
+        let Some(router) = router else {
+            return self.delete_local(key, &pattern).await;
+        };
+        let (local, remote) = router.owners([pattern.as_ref()]);
+        let deletes = remote.into_iter().map(|owner| {
+            let request = DeleteRequest { key: key.clone() };
+            async move {
+                router
+                    .client(owner)?
+                    .delete(routing::forward(request))
+                    .await
+                    .map_err(|status| routing::from_owner(owner, status))
+            }
+        });
+        let mut responses = futures::future::join_all(deletes).await;
+        if local {
+            responses.push(self.delete_local(key, &pattern).await);
+        }
+
+        let mut success = false;
+        for response in responses {
+            success |= response?.into_inner().success;
+        }
+        Ok(tonic::Response::new(DeleteResponse { success }))
+    }
+
+    async fn aggregate(
+        &self,
+        request: tonic::Request<AggregateRequest>,
+    ) -> Result<tonic::Response<AggregateResponse>, tonic::Status> {
+        self.not_federated("Aggregate")?;
+        let router = self.router(&request);
+        let inner = request.into_inner();
+        let pattern = self.pattern(&inner.key)?;
+
+        let aggregation = match inner.aggregation() {
+            datastore::Aggregation::Unspecified => {
+                return Err(tonic::Status::invalid_argument(
+                    "an aggregation must be given",
+                ))
+            }
+            datastore::Aggregation::Sum => Aggregation::Sum,
+            datastore::Aggregation::Min => Aggregation::Min,
+            datastore::Aggregation::Max => Aggregation::Max,
+            datastore::Aggregation::Avg => Aggregation::Avg,
+            datastore::Aggregation::Count => Aggregation::Count,
+            datastore::Aggregation::CountByValue => Aggregation::CountByValue,
+        };
+
+        let group_by = match &inner.group_by {
+            Some(GroupByRequest::GroupByPosition(position)) => {
+                Some(GroupBy::Position(*position as usize))
+            }
+            Some(GroupByRequest::GroupByCapture(name)) => {
+                if !pattern.has_capture(name) {
+                    return Err(tonic::Status::invalid_argument(format!(
+                        "pattern has no capture named '{}'",
+                        name
+                    )));
+                }
+                Some(GroupBy::Capture(name.clone()))
+            }
+            None => None,
+        };
+
+        let (local, remote) = match router {
+            Some(router) => router.owners([pattern.as_ref()]),
+            None => (true, Vec::new()),
+        };
+        let Some(router) = router.filter(|_| !remote.is_empty()) else {
+            let results = self
+                .datastore
+                .aggregate(pattern.as_ref(), aggregation, group_by.as_ref())
+                .await;
+            let groups = results.into_iter().map(aggregate_group).collect();
+            return Ok(tonic::Response::new(AggregateResponse { groups }));
+        };
+        // a single owner's groups stand as they are
+        if !local && remote.len() == 1 {
+            let owner = remote[0];
+            return router
+                .client(owner)?
+                .aggregate(routing::forward(inner))
+                .await
+                .map_err(|status| routing::from_owner(owner, status));
+        }
+
+        let mut request = inner;
+        if aggregation == Aggregation::Avg {
+            request.set_aggregation(datastore::Aggregation::Sum);
+        }
+        let aggregates = remote.into_iter().map(|owner| {
+            let request = request.clone();
+            async move {
+                router
+                    .client(owner)?
+                    .aggregate(routing::forward(request))
+                    .await
+                    .map_err(|status| routing::from_owner(owner, status))
+            }
+        });
+        let mut parts = Vec::new();
+        for response in futures::future::join_all(aggregates).await {
+            let groups = response?.into_inner().groups;
+            parts.extend(groups.into_iter().map(aggregate_result));
+        }
+        if local {
+            let results = self
+                .datastore
+                .aggregate(pattern.as_ref(), aggregation.partial(), group_by.as_ref())
+                .await;
+            parts.extend(results);
+        }
+
+        let groups = aggregate::merge(aggregation, parts)
+            .into_iter()
+            .map(aggregate_group)
+            .collect();
+        Ok(tonic::Response::new(AggregateResponse { groups }))
+    }
+
+    async fn txn(
+        &self,
+        request: tonic::Request<TxnRequest>,
+    ) -> Result<tonic::Response<TxnResponse>, tonic::Status> {
+        self.writable()?;
+        self.single_node("Txn")?;
+        let inner = request.into_inner();
+        let mut transaction = Transaction::new();
+
+        for compare in inner.compares {
+            let precondition = match compare.condition {
+                Some(Condition::Absent(true)) => Precondition::Absent,
+                Some(Condition::Present(true)) => Precondition::Present,
+                Some(Condition::Version(version)) => Precondition::Version(version),
+                _ => {
+                    return Err(tonic::Status::invalid_argument(format!(
+                        "compare on '{}' has no condition",
+                        compare.key
+                    )))
+                }
+            };
+            if let Some(router) = &self.router {
+                router.check_local(&compare.key)?;
+            }
+            transaction = transaction.compare(compare.key, precondition);
+        }
+
+        for operation in inner.operations {
+            transaction = match operation.operation {
+                Some(OperationRequest::Set(set)) => {
+                    if let Some(router) = &self.router {
+                        router.check_local(&set.key)?;
+                    }
+                    transaction.set(set.key, set.value, set.options.map(set_options))
+                }
+                Some(OperationRequest::Delete(delete)) => {
+                    // reject malformed patterns before applying anything
+                    let pattern = self.pattern(&delete.key)?;
+                    if let Some(router) = &self.router {
+                        router.check_local_pattern(&pattern)?;
+                    }
+                    transaction.delete(delete.key)
+                }
+                None => return Err(tonic::Status::invalid_argument("empty operation")),
+            };
+        }
+
+        let succeeded = self.datastore.transaction(transaction).await;
+
+        Ok(tonic::Response::new(TxnResponse { succeeded }))
+    }
+
+    async fn batch_set(
+        &self,
+        request: tonic::Request<BatchSetRequest>,
+    ) -> Result<tonic::Response<BatchSetResponse>, tonic::Status> {
+        self.writable()?;
+        self.single_node("BatchSet")?;
+        let requests = request.into_inner().requests;
+        if let Some(router) = &self.router {
+            for req in &requests {
+                router.check_local(&req.key)?;
+            }
+        }
+        let writes = requests
+            .into_iter()
+            .map(|req| (req.key, req.value, req.options.map(set_options)))
+            .collect();
+
+        let written = self.datastore.batch_set(writes).await;
+
+        let reply = BatchSetResponse {
+            responses: written
                 .into_iter()
-                .map(|item| Item {
-                    key: item.key.clone(),
-                    value: item.value.clone(),
+                .map(|written| SetResponse {
+                    success: written,
+                    precondition_failed: !written,
                 })
                 .collect(),
         };
+        Ok(tonic::Response::new(reply))
+    }
+
+    async fn batch_get(
+        &self,
+        request: tonic::Request<BatchGetRequest>,
+    ) -> Result<tonic::Response<BatchGetResponse>, tonic::Status> {
+        self.not_federated("BatchGet")?;
+        let router = self.router(&request);
+        let keys = request.into_inner().keys;
 
+        let Some(router) = router else {
+            return Ok(tonic::Response::new(self.batch_get_local(&keys).await));
+        };
+        // the positions of the keys each server owns, with None for this one
+        let mut owned: BTreeMap<Option<&str>, Vec<usize>> = BTreeMap::new();
+        for (position, key) in keys.iter().enumerate() {
+            owned.entry(router.owner(key)?).or_default().push(position);
+        }
+        let gets = owned.into_iter().map(|(owner, positions)| {
+            let keys: Vec<String> = positions.iter().map(|&i| keys[i].clone()).collect();
+            async move {
+                let reply = match owner {
+                    None => self.batch_get_local(&keys).await,
+                    Some(owner) => router
+                        .client(owner)?
+                        .batch_get(routing::forward(BatchGetRequest { keys }))
+                        .await
+                        .map_err(|status| routing::from_owner(owner, status))?
+                        .into_inner(),
+                };
+                Ok::<_, tonic::Status>((positions, reply.responses))
+            }
+        });
+
+        let mut responses = vec![GetResponse::default(); keys.len()];
+        for result in futures::future::join_all(gets).await {
+            let (positions, found) = result?;
+            for (position, response) in positions.into_iter().zip(found) {
+                responses[position] = response;
+            }
+        }
+        Ok(tonic::Response::new(BatchGetResponse { responses }))
+    }
+
+    // Applies whatever writes have already arrived as one group, then acks
+    async fn ingest(
+        &self,
+        request: tonic::Request<Streaming<IngestRequest>>,
+    ) -> Result<tonic::Response<Self::IngestStream>, tonic::Status> {
+        self.writable()?;
+        self.single_node("Ingest")?;
+        let (acks, receiver) = futures::channel::mpsc::channel(INGEST_ACK_BUFFER);
+        tokio::spawn(ingest(
+            self.datastore.clone(),
+            self.router.clone(),
+            request.into_inner(),
+            acks,
+        ));
+
+        // tonic drops encoded acks it hasn't sent yet when the stream fails,
+        // so an error waits a poll for the acks before it to go out
+        let replies = receiver.then(|reply| async move {
+            if reply.is_err() {
+                tokio::task::yield_now().await;
+            }
+            reply
+        });
+        Ok(tonic::Response::new(Box::pin(replies)))
+    }
+
+    async fn snapshot(
+        &self,
+        request: tonic::Request<SnapshotRequest>,
+    ) -> Result<tonic::Response<SnapshotResponse>, tonic::Status> {
+        self.not_federated("Snapshot")?;
+        let lease = match request.into_inner().lease_ms {
+            0 => DEFAULT_SNAPSHOT_LEASE,
+            ms => Duration::from_millis(ms).min(MAX_SNAPSHOT_LEASE),
+        };
+
+        let snapshot = self.snapshots.open(self.datastore.snapshot(), lease);
+        Ok(tonic::Response::new(SnapshotResponse { snapshot }))
+    }
+
+    async fn changes(
+        &self,
+        request: tonic::Request<ChangesRequest>,
+    ) -> Result<tonic::Response<ChangesResponse>, tonic::Status> {
+        self.not_federated("Changes")?;
+        let router = self.router(&request);
+        let req = request.into_inner();
+        let pattern = self.pattern(&req.pattern)?;
+        // revisions are this server's own, so they only cover its keys
+        if let Some(router) = router {
+            router.check_local_pattern(&pattern)?;
+        }
+        let limit = (req.limit > 0).then_some(req.limit as usize);
+
+        let changes = self
+            .datastore
+            .changes(req.since_revision, &pattern, limit)
+            .map_err(replication::compacted)?;
+
+        let reply = ChangesResponse {
+            changes: changes.changes.into_iter().map(change).collect(),
+            revision: changes.revision,
+        };
         Ok(tonic::Response::new(reply))
     }
 
-    async fn delete(
+    async fn watch(
         &self,
-        _request: tonic::Request<DeleteRequest>,
-    ) -> Result<tonic::Response<DeleteResponse>, tonic::Status> {
-        return Err(tonic::Status::not_found("Not implemented"));
+        request: tonic::Request<WatchRequest>,
+    ) -> Result<tonic::Response<Self::WatchStream>, tonic::Status> {
+        self.not_federated("Watch")?;
+        let router = self.router(&request);
+        let req = request.into_inner();
+        let pattern = self.pattern(&req.pattern)?;
+        if let Some(router) = router {
+            router.check_local_pattern(&pattern)?;
+        }
+        let interval = match req.interval_ms {
+            0 => DEFAULT_WATCH_INTERVAL,
+            ms => Duration::from_millis(ms).max(MIN_WATCH_INTERVAL),
+        };
+        let mode = match req.mode() {
+            WatchModeRequest::OnChange => WatchMode::OnChange,
+            WatchModeRequest::Latest => WatchMode::Latest(interval),
+            WatchModeRequest::Sample => WatchMode::Sample(interval),
+        };
+
+        let mut options = WatchOptions::new()
+            .initial_state(req.initial_state)
+            .mode(mode)
+            .history(req.history);
+        if let Some(revision) = req.since_revision {
+            options = options.since(revision);
+        }
+
+        let events = self
+            .datastore
+            .watch((*pattern).clone(), options)
+            .flat_map(|event| futures::stream::iter(watch_responses(event)));
+        Ok(tonic::Response::new(Box::pin(events)))
     }
 
     async fn delete_at_index(
@@ -123,6 +817,239 @@ impl DatastoreTrait for MyDatastore {
     ) -> Result<tonic::Response<DeleteAtIndexResponse>, tonic::Status> {
         return Err(tonic::Status::not_found("Not implemented"));
     }
+
+    async fn raft(
+        &self,
+        request: tonic::Request<RaftMessage>,
+    ) -> Result<tonic::Response<RaftAck>, tonic::Status> {
+        let node = self.node()?;
+        let envelope = rmp_serde::from_slice(&request.into_inner().envelope)
+            .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
+        node.receive(envelope);
+        Ok(tonic::Response::new(RaftAck {}))
+    }
+
+    async fn add_node(
+        &self,
+        request: tonic::Request<AddNodeRequest>,
+    ) -> Result<tonic::Response<MembersResponse>, tonic::Status> {
+        let node = self.node()?;
+        let req = request.into_inner();
+        node.add_node(req.id, req.address)
+            .await
+            .map_err(propose_status)?;
+        Ok(tonic::Response::new(members_response(node)))
+    }
+
+    async fn remove_node(
+        &self,
+        request: tonic::Request<RemoveNodeRequest>,
+    ) -> Result<tonic::Response<MembersResponse>, tonic::Status> {
+        let node = self.node()?;
+        node.remove_node(request.into_inner().id)
+            .await
+            .map_err(propose_status)?;
+        Ok(tonic::Response::new(members_response(node)))
+    }
+}
+
+// Applies an ingest stream's writes group by group, acking each group. A
+// client that stops reading acks stops the stream being read once `acks` is
+// full.
+async fn ingest<S>(
+    datastore: Arc<Datastore>,
+    router: Option<Arc<Router>>,
+    requests: S,
+    mut acks: futures::channel::mpsc::Sender<Result<IngestAck, tonic::Status>>,
+) where
+    S: Stream<Item = Result<IngestRequest, tonic::Status>> + Unpin,
+{
+    let mut batches = requests.ready_chunks(INGEST_BATCH_SIZE);
+    let mut ack = IngestAck::default();
+
+    while let Some(batch) = batches.next().await {
+        let mut writes = Vec::with_capacity(batch.len());
+        let mut failed = None;
+        for message in batch {
+            match message {
+                Ok(message) => {
+                    if let (Some(router), Some(set)) = (&router, &message.set) {
+                        if let Err(status) = router.check_local(&set.key) {
+                            failed = Some(status);
+                            break;
+                        }
+                    }
+                    ack.applied_sequence = ack.applied_sequence.max(message.sequence);
+                    if let Some(set) = message.set {
+                        writes.push((set.key, set.value, set.options.map(set_options)));
+                    }
+                }
+                Err(status) => {
+                    failed = Some(status);
+                    break;
+                }
+            }
+        }
+
+        for written in datastore.batch_set(writes).await {
+            if written {
+                ack.applied += 1;
+            } else {
+                ack.rejected += 1;
+            }
+        }
+
+        // stop if the client went away
+        if acks.send(Ok(ack.clone())).await.is_err() {
+            break;
+        }
+        if let Some(status) = failed {
+            let _ = acks.send(Err(status)).await;
+            break;
+        }
+    }
+}
+
+fn members_response(node: &Node) -> MembersResponse {
+    MembersResponse {
+        members: node.status().members.into_iter().collect(),
+    }
+}
+
+fn propose_status(error: ProposeError) -> tonic::Status {
+    match error {
+        ProposeError::NoLeader | ProposeError::Timeout | ProposeError::Stopped => {
+            tonic::Status::unavailable(error.to_string())
+        }
+        ProposeError::LostLeadership => tonic::Status::aborted(error.to_string()),
+        ProposeError::MembershipChangeInProgress => {
+            tonic::Status::failed_precondition(error.to_string())
+        }
+    }
+}
+
+fn watch_responses(
+    event: Result<WatchEvent, changes::ChangesError>,
+) -> Vec<Result<WatchResponse, tonic::Status>> {
+    match event {
+        Ok(WatchEvent::Initial { items, revision } | WatchEvent::Sample { items, revision }) => {
+            let mut responses = Vec::new();
+            let mut chunk = Vec::new();
+            let mut size = 0;
+            for item in items {
+                let item_size = item.key.len() + item.value.len();
+                if !chunk.is_empty() && size + item_size > WATCH_CHUNK_BYTES {
+                    responses.push(Ok(WatchResponse {
+                        items: std::mem::take(&mut chunk),
+                        changes: Vec::new(),
+                        revision,
+                        more: true,
+                    }));
+                    size = 0;
+                }
+                size += item_size;
+                chunk.push(Item {
+                    key: item.key,
+                    value: item.value,
+                    labels: Default::default(),
+                    version: item.id,
+                });
+            }
+            responses.push(Ok(WatchResponse {
+                items: chunk,
+                changes: Vec::new(),
+                revision,
+                more: false,
+            }));
+            responses
+        }
+        Ok(WatchEvent::Changes { changes, revision }) => vec![Ok(WatchResponse {
+            items: Vec::new(),
+            changes: changes.into_iter().map(change).collect(),
+            revision,
+            more: false,
+        })],
+        Err(e) => vec![Err(replication::compacted(e))],
+    }
+}
+
+fn aggregate_group(result: AggregateResult) -> AggregateGroup {
+    AggregateGroup {
+        group: result.group,
+        value: result.value,
+        count: result.count,
+        counts: result.counts.into_iter().collect(),
+    }
+}
+
+fn aggregate_result(group: AggregateGroup) -> AggregateResult {
+    AggregateResult {
+        group: group.group,
+        value: group.value,
+        count: group.count,
+        counts: group.counts.into_iter().collect(),
+    }
+}
+
+fn change(change: changes::Change) -> Change {
+    let mut reply = Change {
+        revision: change.revision,
+        key: change.key,
+        ..Default::default()
+    };
+    match change.kind {
+        ChangeKind::Set {
+            item,
+            preserve_history,
+        } => {
+            reply.item = Some(Item {
+                key: item.key,
+                value: item.value,
+                labels: Default::default(),
+                version: item.id,
+            });
+            reply.preserve_history = preserve_history;
+        }
+        ChangeKind::Delete => reply.set_kind(ChangeKindResponse::Delete),
+        ChangeKind::Expire { id } => {
+            reply.set_kind(ChangeKindResponse::Expire);
+            reply.version = id;
+        }
+    }
+    reply
+}
+
+#[allow(clippy::result_large_err)]
+fn get_options(opts: datastore::GetOptions) -> Result<GetOptions, tonic::Status> {
+    let mut options = GetOptions::new()
+        .history_count(opts.history_count.map_or(0, |count| count.max(0) as usize));
+    if let Some(depth) = opts.max_depth {
+        if depth < 1 {
+            return Err(tonic::Status::invalid_argument(
+                "max_depth must be at least 1",
+            ));
+        }
+        options = options.max_depth(depth as usize);
+    }
+    if let Some(filter) = opts.filter {
+        let filter =
+            Filter::parse(&filter).map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
+        options = options.filter(filter);
+    }
+    Ok(options.fields(opts.fields))
+}
+
+fn set_options(opts: datastore::SetOptions) -> SetOptions {
+    SetOptions {
+        preserve_history: opts.preserve_history,
+        ttl: std::time::Duration::from_secs(opts.ttl as u64),
+        precondition: match opts.precondition {
+            Some(PreconditionRequest::IfAbsent(true)) => Some(Precondition::Absent),
+            Some(PreconditionRequest::IfPresent(true)) => Some(Precondition::Present),
+            Some(PreconditionRequest::IfVersion(version)) => Some(Precondition::Version(version)),
+            _ => None,
+        },
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -139,6 +1066,74 @@ struct Args {
     // Max history for datastore
     #[arg(short, long, default_value_t = 5)]
     max_history: usize,
+
+    // Index key segments to speed up queries starting with wildcards
+    #[arg(long)]
+    segment_index: bool,
+
+    // Index a value field for keys matching a pattern, e.g. value.state=bgp.neighbor.>
+    #[arg(long, value_parser = parse_value_index)]
+    value_index: Vec<ValueIndex>,
+
+    // Reserve revisions in this file so they keep increasing across restarts;
+    // defaults to forst-PORT.revision in the working directory
+    #[arg(long)]
+    revision_file: Option<PathBuf>,
+
+    // Serve a read-only copy of the primary at this URL, e.g. http://10.0.0.1:7777
+    #[arg(long, conflicts_with = "node_id")]
+    replica_of: Option<String>,
+
+    // Join a Raft cluster under this id
+    #[arg(long)]
+    node_id: Option<raft::NodeId>,
+
+    // The cluster's initial members, including this node, e.g.
+    // 1=http://10.0.0.1:7777,2=http://10.0.0.2:7777; leave out to join a
+    // running cluster once a member adds this node
+    #[arg(long, requires = "node_id", value_delimiter = ',', value_parser = cluster::parse_member)]
+    peers: Vec<(raft::NodeId, String)>,
+
+    // Keep this node's Raft term, vote and log in this directory, so it can
+    // restart under the same id; defaults to forst-PORT.raft in the working
+    // directory. Once it holds a saved state, the peers are ignored
+    #[arg(long, requires = "node_id")]
+    raft_dir: Option<PathBuf>,
+
+    // Assign keys matching a pattern to the server at a URL, e.g.
+    // 'interface.lab1.>=http://10.0.0.1:7777'. The first matching assignment
+    // owns a key; every server gets the same ones, in the same order. Get,
+    // Set, BatchGet, Query and Delete are routed to the owners; other
+    // requests only see or accept this server's keys.
+    #[arg(long, requires = "advertise_url", conflicts_with_all = ["node_id", "replica_of"], value_parser = parse_partition)]
+    partition: Vec<(Pattern, String)>,
+
+    // This server's URL, as the partitions name it
+    #[arg(long)]
+    advertise_url: Option<String>,
+
+    // Serve Get and Query over several independent servers at these URLs,
+    // e.g. one per site, instead of a datastore of its own. Earlier
+    // upstreams win for keys more than one holds
+    #[arg(long, conflicts_with_all = ["replica_of", "node_id", "partition"])]
+    upstream: Vec<String>,
+}
+
+fn parse_value_index(arg: &str) -> Result<ValueIndex, String> {
+    let (field, pattern) = arg
+        .split_once('=')
+        .ok_or_else(|| "expected FIELD=PATTERN".to_string())?;
+    let pattern = Pattern::parse(pattern).map_err(|e| e.to_string())?;
+    ValueIndex::new(pattern, field).map_err(|e| e.to_string())
+}
+
+fn parse_partition(arg: &str) -> Result<(Pattern, String), String> {
+    // regex segments may contain '=', URLs don't
+    let (pattern, url) = arg
+        .rsplit_once('=')
+        .ok_or_else(|| "expected PATTERN=URL".to_string())?;
+    let pattern = Pattern::parse(pattern).map_err(|e| e.to_string())?;
+    Ok((pattern, url.to_string()))
 }
 
 #[tokio::main]
@@ -155,12 +1150,90 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     });
 
     let addr = SocketAddr::new(args.listen_ip, args.port);
-    let my_datastore = MyDatastore::new(args.max_history);
+    let mut my_datastore = MyDatastore::new(args.max_history, args.segment_index);
+    // without it revisions would start again from 0, and a client following
+    // changes from before the restart would be handed unrelated ones
+    let revision_file = args
+        .revision_file
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(format!("forst-{}.revision", args.port)));
+    my_datastore.datastore.persist_revisions(&revision_file)?;
+    let value_indexes: Vec<String> = args
+        .value_index
+        .iter()
+        .map(|index| format!("{}={}", index.field(), index.pattern()))
+        .collect();
+    for index in args.value_index {
+        my_datastore.datastore.add_value_index(index).await;
+    }
+    if let Some(url) = &args.replica_of {
+        my_datastore = my_datastore.read_only();
+        tokio::spawn(replication::replicate(
+            my_datastore.datastore.clone(),
+            url.clone(),
+        ));
+    }
+    // without it a restarted node could vote twice in a term, or forget
+    // entries it acknowledged
+    let raft_dir = args.node_id.map(|_| {
+        args.raft_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(format!("forst-{}.raft", args.port)))
+    });
+    if let Some(id) = args.node_id {
+        let members: Members = args.peers.iter().cloned().collect();
+        let node = Node::start(
+            id,
+            members,
+            my_datastore.datastore.clone(),
+            Arc::new(cluster::GrpcTransport::new()),
+            RaftConfig {
+                state_dir: raft_dir.clone(),
+                ..Default::default()
+            },
+        )?;
+        my_datastore = my_datastore.cluster(node);
+    }
+    let partitions: Vec<String> = args
+        .partition
+        .iter()
+        .map(|(pattern, url)| format!("{}={}", pattern, url))
+        .collect();
+    if let Some(url) = args
+        .advertise_url
+        .clone()
+        .filter(|_| !args.partition.is_empty())
+    {
+        let partitions = args
+            .partition
+            .into_iter()
+            .fold(Partitions::new(), |partitions, (pattern, owner)| {
+                partitions.assign(pattern, owner)
+            });
+        my_datastore = my_datastore.partitioned(Router::new(partitions, url));
+    }
+    if !args.upstream.is_empty() {
+        my_datastore = my_datastore.federated(Federation::new(args.upstream.clone()));
+    }
 
     println!("Starting gRPC server with configuration: ");
     println!("\t Listen IP: {}", args.listen_ip);
     println!("\t Port: {}", args.port);
     println!("\t Max history: {}", args.max_history);
+    println!("\t Segment index: {}", args.segment_index);
+    println!("\t Value indexes: {:?}", value_indexes);
+    println!("\t Revision file: {:?}", revision_file);
+    println!("\t Replica of: {:?}", args.replica_of);
+    println!("\t Node id: {:?}", args.node_id);
+    println!("\t Peers: {:?}", args.peers);
+    println!("\t Raft dir: {:?}", raft_dir);
+    println!("\t Partitions: {:?}", partitions);
+    println!("\t Advertise URL: {:?}", args.advertise_url);
+    println!("\t Upstreams: {:?}", args.upstream);
+    println!(
+        "\t Revision: {}",
+        my_datastore.datastore.snapshot().revision()
+    );
 
 
     let server = Server::builder()
@@ -175,3 +1248,331 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datastore::datastore_client::DatastoreClient;
+    use tokio::net::TcpListener;
+    use tonic::transport::server::TcpIncoming;
+
+    // Serves the datastore on a free localhost port until the returned
+    // sender is dropped, and returns its URL
+    async fn serve(my_datastore: MyDatastore) -> (String, oneshot::Sender<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        tokio::spawn(
+            Server::builder()
+                .add_service(DatastoreServer::new(my_datastore))
+                .serve_with_incoming_shutdown(incoming, async {
+                    stopped.await.ok();
+                }),
+        );
+        (url, stop)
+    }
+
+    async fn connect(url: &str) -> DatastoreClient<tonic::transport::Channel> {
+        DatastoreClient::connect(url.to_string()).await.unwrap()
+    }
+
+    fn ingest_request(
+        sequence: u64,
+        key: &str,
+        options: Option<datastore::SetOptions>,
+    ) -> IngestRequest {
+        IngestRequest {
+            sequence,
+            set: Some(SetRequest {
+                key: key.to_string(),
+                value: format!("value{}", sequence).into_bytes(),
+                options,
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ingest() {
+        let my_datastore = MyDatastore::new(1, false);
+        let datastore = my_datastore.datastore.clone();
+        datastore
+            .set("ingest.key007".to_string(), b"existing", None)
+            .await;
+        let (url, _stop) = serve(my_datastore).await;
+        let mut client = connect(&url).await;
+
+        // every tenth write only applies to keys that don't exist yet
+        let if_absent = datastore::SetOptions {
+            precondition: Some(PreconditionRequest::IfAbsent(true)),
+            ..Default::default()
+        };
+        let requests: Vec<IngestRequest> = (0..500u64)
+            .map(|i| {
+                let options = (i % 10 == 7).then(|| if_absent.clone());
+                ingest_request(i + 1, &format!("ingest.key{:03}", i), options)
+            })
+            .collect();
+        let acks: Vec<IngestAck> = client
+            .ingest(futures::stream::iter(requests))
+            .await
+            .unwrap()
+            .into_inner()
+            .map(|ack| ack.unwrap())
+            .collect()
+            .await;
+
+        assert!(!acks.is_empty());
+        for pair in acks.windows(2) {
+            assert!(pair[0].applied_sequence <= pair[1].applied_sequence);
+            assert!(pair[0].applied + pair[0].rejected < pair[1].applied + pair[1].rejected);
+        }
+        let last = acks.last().unwrap();
+        assert_eq!(last.applied_sequence, 500);
+        assert_eq!(last.applied, 499);
+        assert_eq!(last.rejected, 1);
+
+        assert_eq!(datastore.query("ingest.*", None).await.len(), 500);
+        let value = |key: &str| {
+            let datastore = datastore.clone();
+            let key = key.to_string();
+            async move { datastore.get(&key).await.unwrap().value }
+        };
+        assert_eq!(value("ingest.key000").await, b"value1");
+        assert_eq!(value("ingest.key007").await, b"existing");
+        assert_eq!(value("ingest.key017").await, b"value18");
+        assert_eq!(value("ingest.key499").await, b"value500");
+    }
+
+    #[tokio::test]
+    async fn test_ingest_fails_mid_stream() {
+        // keys under remote are owned by another server, so writing one fails
+        let partitions = Partitions::new()
+            .assign(Pattern::parse("local.>").unwrap(), "http://local")
+            .assign(Pattern::parse("remote.>").unwrap(), "http://remote");
+        let my_datastore = MyDatastore::new(1, false)
+            .partitioned(Router::new(partitions, "http://local".to_string()));
+        let datastore = my_datastore.datastore.clone();
+        let (url, _stop) = serve(my_datastore).await;
+        let mut client = connect(&url).await;
+
+        let requests: Vec<IngestRequest> = (0..300u64)
+            .map(|i| {
+                let key = match i {
+                    100 => "remote.key".to_string(),
+                    _ => format!("local.key{:03}", i),
+                };
+                ingest_request(i + 1, &key, None)
+            })
+            .collect();
+        let replies: Vec<Result<IngestAck, tonic::Status>> = client
+            .ingest(futures::stream::iter(requests))
+            .await
+            .unwrap()
+            .into_inner()
+            .collect()
+            .await;
+
+        // the writes before the failing one are applied and acked, then the
+        // stream ends with its error
+        let (error, acks) = replies.split_last().unwrap();
+        assert_eq!(error.as_ref().unwrap_err().code(), Code::FailedPrecondition);
+        let last = acks.last().unwrap().as_ref().unwrap();
+        assert_eq!(last.applied_sequence, 100);
+        assert_eq!(last.applied, 100);
+        assert_eq!(last.rejected, 0);
+
+        assert_eq!(datastore.query("local.*", None).await.len(), 100);
+        assert!(datastore.get("local.key099").await.is_some());
+        assert!(datastore.get("local.key101").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_federated_query() {
+        let upstream = MyDatastore::new(1, false);
+        upstream
+            .datastore
+            .set("interface.esr1a.mtu".to_string(), b"1500", None)
+            .await;
+        let (upstream, _stop) = serve(upstream).await;
+        // nothing listens on port 1
+        let federation = Federation::new(vec![upstream, "http://127.0.0.1:1".to_string()]);
+        let (url, _stop) = serve(MyDatastore::new(1, false).federated(federation)).await;
+        let mut client = connect(&url).await;
+        let query = |key: &str, filter: Option<&str>| QueryRequest {
+            key: key.to_string(),
+            options: filter.map(|filter| datastore::GetOptions {
+                filter: Some(filter.to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        // the unreachable upstream only makes the answer partial
+        let response = client
+            .query(query("interface.>", None))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.items.len(), 1);
+        assert_eq!(response.upstream_errors.len(), 1);
+
+        // while a malformed request is the client's fault, not the upstreams'
+        for request in [
+            query("interface.~re:(", None),
+            query("interface.>", Some("value.mtu >")),
+        ] {
+            let status = client.query(request).await.unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ingest_backpressure() {
+        let datastore = Arc::new(Datastore::new(1));
+        let (requests, incoming) = futures::channel::mpsc::unbounded();
+        let (acks, received) = futures::channel::mpsc::channel(INGEST_ACK_BUFFER);
+        let task = tokio::spawn(ingest(datastore.clone(), None, incoming, acks));
+
+        for i in 0..100u64 {
+            let request = ingest_request(i + 1, &format!("ingest.key{:03}", i), None);
+            requests.unbounded_send(Ok(request)).unwrap();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // nobody reads the acks, so ingesting stops once they fill the buffer
+        let stored = datastore.query("ingest.*", None).await.len();
+        assert!(stored >= INGEST_ACK_BUFFER, "stored {}", stored);
+        assert!(stored < 100, "stored {}", stored);
+
+        // reading them lets the rest through
+        drop(requests);
+        let acks: Vec<IngestAck> = received.map(|ack| ack.unwrap()).collect().await;
+        task.await.unwrap();
+        let last = acks.last().unwrap();
+        assert_eq!(last.applied_sequence, 100);
+        assert_eq!(last.applied, 100);
+        assert_eq!(datastore.query("ingest.*", None).await.len(), 100);
+    }
+
+    #[tokio::test]
+    async fn test_replica_bootstraps_large_state() {
+        // more than the 4 MB a client decodes in one message by default
+        let my_datastore = MyDatastore::new(1, false);
+        let value = vec![7u8; 100 * 1024];
+        for i in 0..64 {
+            my_datastore
+                .datastore
+                .set(format!("large.key{:02}", i), &value, None)
+                .await;
+        }
+        let (url, _stop) = serve(my_datastore).await;
+
+        // the state arrives in several responses
+        let request = WatchRequest {
+            pattern: ">".to_string(),
+            initial_state: true,
+            ..Default::default()
+        };
+        let mut client = connect(&url).await;
+        let mut responses = client.watch(request).await.unwrap().into_inner();
+        let mut chunks = 0;
+        loop {
+            let response = responses.message().await.unwrap().unwrap();
+            chunks += 1;
+            if !response.more {
+                break;
+            }
+        }
+        assert!(chunks > 1);
+
+        let replica = Arc::new(Datastore::new(1));
+        let replicating = tokio::spawn(replication::replicate(replica.clone(), url));
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while replica.query("large.*", None).await.len() < 64 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("replica never bootstrapped");
+        assert_eq!(replica.get("large.key63").await.unwrap().value, value);
+        replicating.abort();
+    }
+
+    #[tokio::test]
+    async fn test_partitioned_aggregate() {
+        // keys under b are owned by the other server
+        let other = MyDatastore::new(1, false);
+        for (key, value) in [("b.one", b"6".as_slice()), ("b.two", b"n/a")] {
+            other.datastore.set(key.to_string(), value, None).await;
+        }
+        let (other_url, _stop_other) = serve(other).await;
+        let partitions = Partitions::new()
+            .assign(Pattern::parse("a.>").unwrap(), "http://local")
+            .assign(Pattern::parse("b.>").unwrap(), other_url);
+        let my_datastore = MyDatastore::new(1, false)
+            .partitioned(Router::new(partitions, "http://local".to_string()));
+        for (key, value) in [("a.one", b"1".as_slice()), ("a.two", b"2")] {
+            my_datastore
+                .datastore
+                .set(key.to_string(), value, None)
+                .await;
+        }
+        let (url, _stop) = serve(my_datastore).await;
+        let mut client = connect(&url).await;
+
+        // every owner's keys are aggregated, as if they were on one server
+        let cases = [
+            (datastore::Aggregation::Avg, None, vec![("", 3.0, 3)]),
+            (datastore::Aggregation::Count, None, vec![("", 4.0, 4)]),
+            (
+                datastore::Aggregation::Sum,
+                Some(GroupByRequest::GroupByPosition(0)),
+                vec![("a", 3.0, 2), ("b", 6.0, 1)],
+            ),
+        ];
+        for (aggregation, group_by, expected) in cases {
+            let request = AggregateRequest {
+                key: "*.*".to_string(),
+                aggregation: aggregation.into(),
+                group_by,
+            };
+            let groups = client.aggregate(request).await.unwrap().into_inner().groups;
+            let groups: Vec<(&str, f64, u64)> = groups
+                .iter()
+                .map(|group| (group.group.as_str(), group.value, group.count))
+                .collect();
+            assert_eq!(groups, expected, "{:?}", aggregation);
+        }
+
+        // revisions and snapshots only cover this server's keys
+        let watch = WatchRequest {
+            pattern: "*.*".to_string(),
+            ..Default::default()
+        };
+        let status = client.watch(watch).await.unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        let changes = ChangesRequest {
+            pattern: "*.*".to_string(),
+            ..Default::default()
+        };
+        let status = client.changes(changes).await.unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+
+        let snapshot = client
+            .snapshot(SnapshotRequest::default())
+            .await
+            .unwrap()
+            .into_inner()
+            .snapshot;
+        let query = |key: &str| QueryRequest {
+            key: key.to_string(),
+            snapshot: Some(snapshot),
+            ..Default::default()
+        };
+        let status = client.query(query("*.*")).await.unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        let items = client.query(query("a.*")).await.unwrap().into_inner().items;
+        assert_eq!(items.len(), 2);
+    }
+}